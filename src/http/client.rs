@@ -6,18 +6,20 @@
 //! [`examples/http_request.rs`](https://github.com/esp-rs/esp-idf-svc/blob/master/examples/http_request.rs).
 
 use core::cell::UnsafeCell;
-use core::fmt::Write as _;
+use core::fmt::{self, Debug, Display, Write as _};
 
 extern crate alloc;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use ::log::*;
 
 use embedded_svc::http::client::*;
-use embedded_svc::io::{ErrorType, Read, Write};
+use embedded_svc::io::{ErrorKind, ErrorType, Read, Write};
 
 use crate::sys::*;
 
@@ -27,12 +29,29 @@ use crate::handle::RawHandle;
 use crate::io::EspIOError;
 use crate::private::common::Newtype;
 use crate::private::cstr::*;
+use crate::private::unblocker::Unblocker;
+use crate::private::zerocopy::Channel;
+#[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+use crate::tls::Psk;
 use crate::tls::X509;
 
 pub use embedded_svc::http::client::{Connection, Request, Response};
 
 pub use super::*;
 
+pub use facade::*;
+
+mod facade;
+#[cfg(feature = "std")]
+pub mod proxy;
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    esp_idf_esp_tls_using_mbedtls,
+    esp_idf_mbedtls_certificate_bundle,
+    not(esp_idf_version = "4.3")
+))]
+mod tls_hook;
+
 impl From<Method> for Newtype<(esp_http_client_method_t, ())> {
     fn from(method: Method) -> Self {
         Self((
@@ -75,6 +94,32 @@ impl Default for FollowRedirectsPolicy {
     }
 }
 
+/// When the credentials of the `Configuration` are sent to the server
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum AuthType {
+    /// Only after the server answered with `401 Unauthorized`, with the scheme requested
+    /// by the server (Basic or Digest)
+    Challenge,
+    /// Preemptively with every request, using Basic authentication
+    Basic,
+}
+
+impl Default for AuthType {
+    fn default() -> Self {
+        Self::Challenge
+    }
+}
+
+/// The configuration of the HTTP client
+///
+/// The TLS options `alpn_protos`, `psk` and `pinned_public_keys` are not supported by the ESP-IDF
+/// HTTP client itself, and need `CONFIG_MBEDTLS_CERTIFICATE_BUNDLE` (enabled by default) with
+/// mbedTLS. With other configurations, `EspHttpConnection::new` fails with `ESP_ERR_NOT_SUPPORTED`
+/// if they are set.
+///
+/// There are no proxy options, as the ESP-IDF HTTP client cannot connect through proxies,
+/// see `proxy::ProxyTunnel` for tunnelling other connections through an HTTP proxy.
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Configuration {
     pub buffer_size: Option<usize>,
@@ -83,10 +128,121 @@ pub struct Configuration {
     pub follow_redirects_policy: FollowRedirectsPolicy,
     pub client_certificate: Option<X509<'static>>,
     pub private_key: Option<X509<'static>>,
+    /// The CA certificate for verifying the server
+    pub server_certificate: Option<X509<'static>>,
     pub use_global_ca_store: bool,
     #[cfg(not(esp_idf_version = "4.3"))]
     pub crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut core::ffi::c_void) -> esp_err_t>,
+    pub skip_cert_common_name_check: bool,
+    /// The name the server certificate is checked against, instead of the host of the request URI
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub common_name: Option<&'static str>,
+    pub alpn_protos: Option<&'static [&'static str]>,
+    #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+    pub psk: Option<Psk<'static>>,
+    /// SHA-256 hashes of the DER-encoded public keys (SubjectPublicKeyInfo) the server may use
+    ///
+    /// With pinned keys, the server is only accepted if the key of its certificate is one of them.
+    /// Without any of `server_certificate`, `use_global_ca_store` and `crt_bundle_attach`,
+    /// the certificate chain of the server is not verified otherwise.
+    pub pinned_public_keys: &'static [[u8; 32]],
     pub raw_request_body: bool,
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
+    pub auth_type: AuthType,
+}
+
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+))]
+impl TryFrom<&crate::tls::Config<'static>> for Configuration {
+    type Error = EspError;
+
+    /// Takes over the TLS options of `tls`, so that HTTP and raw TLS connections can share
+    /// the same security policy.
+    ///
+    /// `client_key_password`, `keep_alive_cfg`, `use_secure_element` and `non_block` are ignored.
+    ///
+    /// Fails with `ESP_ERR_INVALID_ARG` if the PSK hint is not valid UTF-8.
+    fn try_from(tls: &crate::tls::Config<'static>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            timeout: Some(core::time::Duration::from_millis(tls.timeout_ms as _)),
+            client_certificate: tls.client_cert,
+            private_key: tls.client_key,
+            server_certificate: tls.ca_cert,
+            use_global_ca_store: tls.use_global_ca_store,
+            #[cfg(all(esp_idf_mbedtls_certificate_bundle, not(esp_idf_version = "4.3")))]
+            crt_bundle_attach: tls
+                .use_crt_bundle_attach
+                .then_some(esp_crt_bundle_attach as _),
+            skip_cert_common_name_check: tls.skip_common_name,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            common_name: tls.common_name,
+            alpn_protos: tls.alpn_protos,
+            #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+            psk: tls
+                .psk_hint_key
+                .as_ref()
+                .map(|psk| {
+                    Ok::<_, EspError>(Psk {
+                        key: psk.key,
+                        hint: psk
+                            .hint
+                            .to_str()
+                            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+                    })
+                })
+                .transpose()?,
+            ..Default::default()
+        })
+    }
+}
+
+/// The size of the buffer used by `EspHttpConnection::copy_request_from` and `EspHttpConnection::copy_response_to`
+const COPY_BUF_LEN: usize = 512;
+
+#[derive(Debug)]
+pub enum CopyError<E> {
+    /// Writing the request or reading the response failed
+    Connection(EspIOError),
+    /// Reading from the source or writing to the sink failed
+    Io(E),
+}
+
+impl<E> Display for CopyError<E>
+where
+    E: Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connection(err) => write!(f, "Connection error: {err}"),
+            Self::Io(err) => write!(f, "I/O error: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for CopyError<E> where E: Debug + Display {}
+
+impl<E> embedded_svc::io::Error for CopyError<E>
+where
+    E: embedded_svc::io::Error,
+{
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Connection(err) => err.kind(),
+            Self::Io(err) => err.kind(),
+        }
+    }
+}
+
+impl From<CopyError<EspIOError>> for EspError {
+    fn from(err: CopyError<EspIOError>) -> Self {
+        match err {
+            CopyError::Connection(err) | CopyError::Io(err) => err.0,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -106,7 +262,20 @@ pub struct EspHttpConnection {
     request_content_len: i64,
     follow_redirects: bool,
     headers: BTreeMap<Uncased<'static>, String>,
+    /// All `Set-Cookie` headers of the response, as `headers` only keeps the last one
+    set_cookies: Vec<String>,
     content_len_header: UnsafeCell<Option<Option<String>>>,
+    authenticate: bool,
+    auth_challenged: bool,
+    #[cfg(not(esp_idf_version_major = "4"))]
+    _common_name: Option<CString>,
+    #[cfg(all(
+        esp_idf_comp_esp_tls_enabled,
+        esp_idf_esp_tls_using_mbedtls,
+        esp_idf_mbedtls_certificate_bundle,
+        not(esp_idf_version = "4.3")
+    ))]
+    tls_hook: Option<Box<tls_hook::TlsHook>>,
 }
 
 impl EspHttpConnection {
@@ -149,6 +318,79 @@ impl EspHttpConnection {
             native_config.client_key_len = private_key.as_esp_idf_raw_len();
         }
 
+        if let Some(cert) = configuration.server_certificate {
+            native_config.cert_pem = cert.as_esp_idf_raw_ptr() as _;
+            native_config.cert_len = cert.as_esp_idf_raw_len();
+        }
+
+        native_config.skip_cert_common_name_check = configuration.skip_cert_common_name_check;
+
+        // Unlike the credentials, the common name is not copied by the ESP-IDF HTTP client
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let common_name = configuration.common_name.map(to_cstring_arg).transpose()?;
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        if let Some(common_name) = common_name.as_ref() {
+            native_config.common_name = common_name.as_ptr() as _;
+        }
+
+        #[cfg(all(
+            esp_idf_comp_esp_tls_enabled,
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_mbedtls_certificate_bundle,
+            not(esp_idf_version = "4.3")
+        ))]
+        let tls_hook = tls_hook::TlsHook::new(configuration)?;
+
+        #[cfg(all(
+            esp_idf_comp_esp_tls_enabled,
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_mbedtls_certificate_bundle,
+            not(esp_idf_version = "4.3")
+        ))]
+        if tls_hook.is_some() {
+            // The hook takes care of the server verification options
+            native_config.use_global_ca_store = false;
+            native_config.crt_bundle_attach = Some(tls_hook::attach);
+        }
+
+        #[cfg(not(all(
+            esp_idf_comp_esp_tls_enabled,
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_mbedtls_certificate_bundle,
+            not(esp_idf_version = "4.3")
+        )))]
+        {
+            #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+            let psk = configuration.psk.is_some();
+            #[cfg(not(all(esp_idf_esp_tls_psk_verification, feature = "alloc")))]
+            let psk = false;
+
+            if configuration.alpn_protos.is_some()
+                || psk
+                || !configuration.pinned_public_keys.is_empty()
+            {
+                return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+            }
+        }
+
+        // The ESP-IDF HTTP client keeps copies of the credentials
+        let username = configuration.username.map(to_cstring_arg).transpose()?;
+        let password = configuration.password.map(to_cstring_arg).transpose()?;
+
+        if let Some(username) = username.as_ref() {
+            native_config.username = username.as_ptr() as _;
+            native_config.password = password
+                .as_ref()
+                .map(|password| password.as_ptr() as _)
+                .unwrap_or(b"\0".as_ptr() as _);
+
+            native_config.auth_type = match configuration.auth_type {
+                AuthType::Challenge => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_NONE,
+                AuthType::Basic => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_BASIC,
+            };
+        }
+
         let raw_client = unsafe { esp_http_client_init(&native_config) };
         if raw_client.is_null() {
             Err(EspError::from_infallible::<ESP_FAIL>())
@@ -162,7 +404,19 @@ impl EspHttpConnection {
                 request_content_len: -1,
                 follow_redirects: false,
                 headers: BTreeMap::new(),
+                set_cookies: Vec::new(),
                 content_len_header: UnsafeCell::new(None),
+                authenticate: configuration.username.is_some(),
+                auth_challenged: false,
+                #[cfg(not(esp_idf_version_major = "4"))]
+                _common_name: common_name,
+                #[cfg(all(
+                    esp_idf_comp_esp_tls_enabled,
+                    esp_idf_esp_tls_using_mbedtls,
+                    esp_idf_mbedtls_certificate_bundle,
+                    not(esp_idf_version = "4.3")
+                ))]
+                tls_hook,
             })
         }
     }
@@ -202,6 +456,11 @@ impl EspHttpConnection {
         }
     }
 
+    /// Starts a request to `uri`.
+    ///
+    /// The request body is sent with chunked transfer encoding - so that its length need not be
+    /// known upfront - if `headers` contain `Transfer-Encoding: chunked`, or if a POST request has
+    /// no `Content-Length` header.
     pub fn initiate_request<'a>(
         &'a mut self,
         method: Method,
@@ -226,12 +485,18 @@ impl EspHttpConnection {
         })?;
 
         let mut content_len = None;
+        let mut chunked = false;
 
         for (name, value) in headers {
             if name.eq_ignore_ascii_case("Content-Length") {
                 if let Ok(len) = value.parse::<i64>() {
                     content_len = Some(len);
                 }
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+            {
+                // Set by the ESP-IDF HTTP client itself
+                chunked = true;
             } else {
                 let c_name = to_cstring_arg(name)?;
 
@@ -257,10 +522,13 @@ impl EspHttpConnection {
         // No Content-Length for POST requests means chunked encoding
         // This is indicated to the ESP IDF client by setting the
         // content length param of `esp_http_client_open` to -1
-        self.request_content_len =
-            content_len.unwrap_or(if method == Method::Post { -1 } else { 0 });
+        self.request_content_len = if chunked {
+            -1
+        } else {
+            content_len.unwrap_or(if method == Method::Post { -1 } else { 0 })
+        };
 
-        esp!(unsafe { esp_http_client_open(self.raw_client, self.request_content_len as i32) })?;
+        self.open()?;
 
         self.state = State::Request;
 
@@ -271,6 +539,12 @@ impl EspHttpConnection {
         self.state == State::Request
     }
 
+    /// Sets the network timeout for the following requests.
+    #[cfg(not(esp_idf_version_major = "4"))]
+    pub fn set_timeout(&mut self, timeout: core::time::Duration) -> Result<(), EspError> {
+        esp!(unsafe { esp_http_client_set_timeout_ms(self.raw_client, timeout.as_millis() as _) })
+    }
+
     pub fn initiate_response(&mut self) -> Result<(), EspError> {
         self.assert_request();
 
@@ -351,6 +625,84 @@ impl EspHttpConnection {
         Ok(())
     }
 
+    /// Sends the request body read from `source` until its end, and returns its length.
+    ///
+    /// `progress` is called with the number of bytes sent so far and the length of the body,
+    /// if known from the `Content-Length` header of the request.
+    pub fn copy_request_from<R, F>(
+        &mut self,
+        source: &mut R,
+        mut progress: F,
+    ) -> Result<usize, CopyError<R::Error>>
+    where
+        R: Read,
+        F: FnMut(usize, Option<usize>),
+    {
+        self.assert_request();
+
+        let total = (self.request_content_len >= 0).then_some(self.request_content_len as usize);
+
+        let mut buf = [0; COPY_BUF_LEN];
+        let mut sent = 0;
+
+        loop {
+            let len = source.read(&mut buf).map_err(CopyError::Io)?;
+            if len == 0 {
+                break;
+            }
+
+            EspHttpConnection::write_all(self, &buf[..len])
+                .map_err(|err| CopyError::Connection(EspIOError(err)))?;
+
+            sent += len;
+
+            progress(sent, total);
+        }
+
+        Ok(sent)
+    }
+
+    /// Writes the response body to `sink`, and returns its length.
+    ///
+    /// `progress` is called with the number of bytes received so far and the length of the body,
+    /// if known from the `Content-Length` header of the response.
+    pub fn copy_response_to<W, F>(
+        &mut self,
+        sink: &mut W,
+        mut progress: F,
+    ) -> Result<usize, CopyError<W::Error>>
+    where
+        W: Write,
+        F: FnMut(usize, Option<usize>),
+    {
+        self.assert_response();
+
+        let total = self
+            .header("Content-Length")
+            .and_then(|len| len.parse::<usize>().ok());
+
+        let mut buf = [0; COPY_BUF_LEN];
+        let mut received = 0;
+
+        loop {
+            let len = EspHttpConnection::read(self, &mut buf)
+                .map_err(|err| CopyError::Connection(EspIOError(err)))?;
+            if len == 0 {
+                break;
+            }
+
+            sink.write_all(&buf[..len]).map_err(CopyError::Io)?;
+
+            received += len;
+
+            progress(received, total);
+        }
+
+        sink.flush().map_err(CopyError::Io)?;
+
+        Ok(received)
+    }
+
     fn flush(&mut self) -> Result<(), EspError> {
         if !self.raw_request_body && self.request_content_len == -1 {
             // Finish the chunked-encoded stream
@@ -409,23 +761,64 @@ impl EspHttpConnection {
         }
     }
 
+    /// Returns `true` if the last response was a `401 Unauthorized` challenge which could not be
+    /// answered automatically, because the request had a body.
+    ///
+    /// The credentials for answering the challenge are prepared, so the request should be sent again.
+    pub fn is_auth_challenged(&self) -> bool {
+        self.auth_challenged
+    }
+
+    /// Closes the connection and discards the request or response in progress, so that a new
+    /// request can be initiated after the previous one failed midway.
+    pub(crate) fn reset(&mut self) -> Result<(), EspError> {
+        self.state = State::New;
+        self.request_content_len = 0;
+
+        self.headers.clear();
+        self.set_cookies.clear();
+        *self.content_len_header.get_mut() = None;
+        self.auth_challenged = false;
+
+        esp!(unsafe { esp_http_client_close(self.raw_client) })
+    }
+
+    /// Returns the values of all `Set-Cookie` headers of the response.
+    pub(crate) fn set_cookies(&self) -> &[String] {
+        self.assert_response();
+        &self.set_cookies
+    }
+
     fn fetch_headers(&mut self) -> Result<(), EspError> {
         self.headers.clear();
+        self.set_cookies.clear();
         *self.content_len_header.get_mut() = None;
 
+        self.auth_challenged = false;
+
+        let mut auth_retried = false;
+
         loop {
             // TODO: Implement a mechanism where the client can declare in which header it is interested
             let headers_ptr = &mut self.headers as *mut BTreeMap<Uncased, String>;
+            let set_cookies_ptr = &mut self.set_cookies as *mut Vec<String>;
 
             let handler = move |event: &esp_http_client_event_t| {
                 if event.event_id == esp_http_client_event_id_t_HTTP_EVENT_ON_HEADER {
                     unsafe {
                         // TODO: Replace with a proper conversion from ISO-8859-1 to UTF8
 
-                        headers_ptr.as_mut().unwrap().insert(
-                            Uncased::from(from_cstr_ptr(event.header_key).to_string()),
-                            from_cstr_ptr(event.header_value).to_string(),
-                        );
+                        let key = from_cstr_ptr(event.header_key);
+                        let value = from_cstr_ptr(event.header_value);
+
+                        if key.eq_ignore_ascii_case("Set-Cookie") {
+                            set_cookies_ptr.as_mut().unwrap().push(value.to_string());
+                        }
+
+                        headers_ptr
+                            .as_mut()
+                            .unwrap()
+                            .insert(Uncased::from(key.to_string()), value.to_string());
                     }
                 }
 
@@ -442,6 +835,34 @@ impl EspHttpConnection {
 
             trace!("Fetched headers: {:?}", self.headers);
 
+            if self.authenticate && !auth_retried {
+                let status = unsafe { esp_http_client_get_status_code(self.raw_client) as u16 };
+
+                if status == 401 {
+                    auth_retried = true;
+
+                    // Parses the `WWW-Authenticate` header, so that the credentials are sent with the following requests
+                    if let Err(err) = esp!(unsafe { esp_http_client_add_auth(self.raw_client) }) {
+                        warn!("Cannot answer the authentication challenge: {}", err);
+                    } else if self.request_content_len != 0 {
+                        // The request body is gone, so it is up to the caller to send the request again
+                        self.auth_challenged = true;
+                    } else {
+                        info!("Got response 401, about to retry with credentials");
+
+                        let mut len = 0_i32;
+                        esp!(unsafe { esp_http_client_flush_response(self.raw_client, &mut len) })?;
+
+                        self.open()?;
+
+                        self.headers.clear();
+                        self.set_cookies.clear();
+
+                        continue;
+                    }
+                }
+            }
+
             if self.follow_redirects {
                 let status = unsafe { esp_http_client_get_status_code(self.raw_client) as u16 };
 
@@ -457,11 +878,11 @@ impl EspHttpConnection {
                         )
                     })?;
                     esp!(unsafe { esp_http_client_set_redirection(self.raw_client) })?;
-                    esp!(unsafe {
-                        esp_http_client_open(self.raw_client, self.request_content_len as i32)
-                    })?;
+
+                    self.open()?;
 
                     self.headers.clear();
+                    self.set_cookies.clear();
 
                     continue;
                 }
@@ -473,6 +894,19 @@ impl EspHttpConnection {
         Ok(())
     }
 
+    fn open(&mut self) -> Result<(), EspError> {
+        // A new TLS connection may be set up, which needs the hook
+        #[cfg(all(
+            esp_idf_comp_esp_tls_enabled,
+            esp_idf_esp_tls_using_mbedtls,
+            esp_idf_mbedtls_certificate_bundle,
+            not(esp_idf_version = "4.3")
+        ))]
+        let _guard = self.tls_hook.as_mut().map(|hook| hook.activate());
+
+        esp!(unsafe { esp_http_client_open(self.raw_client, self.request_content_len as i32) })
+    }
+
     fn register_handler(
         &mut self,
         handler: impl Fn(&esp_http_client_event_t) -> esp_err_t + 'static,
@@ -598,3 +1032,437 @@ impl Connection for EspHttpConnection {
         Err(EspError::from_infallible::<ESP_FAIL>().into())
     }
 }
+
+/// The largest amount of data transferred to or from the worker task of an
+/// `EspAsyncHttpConnection` in one `read` or `write` call
+const ASYNC_TRANSFER_LEN: usize = 4096;
+
+#[derive(Debug, Copy, Clone)]
+enum AsyncCommand {
+    InitiateRequest(Method),
+    InitiateResponse,
+    Read(usize),
+    Write,
+}
+
+#[derive(Debug)]
+struct AsyncWork {
+    command: AsyncCommand,
+    uri: String,
+    headers: Vec<(String, String)>,
+    data: Vec<u8>,
+    result: Result<usize, EspError>,
+    status: u16,
+    response_headers: BTreeMap<Uncased<'static>, String>,
+}
+
+/// The connection is only ever used by the worker task it is moved into
+struct WorkerConnection(EspHttpConnection);
+
+unsafe impl Send for WorkerConnection {}
+
+/// The state of an `EspAsyncHttpConnection`, as seen by the async side
+struct AsyncState {
+    state: State,
+    status: u16,
+    headers: BTreeMap<Uncased<'static>, String>,
+    /// Response data read by a `read` call whose future was dropped
+    unread: Vec<u8>,
+    /// The length of the request data sent by a `write` call whose future was dropped
+    written: Option<usize>,
+}
+
+impl AsyncState {
+    fn finish(&mut self, work: &mut AsyncWork) -> Result<usize, EspError> {
+        match (work.command, work.result) {
+            (AsyncCommand::InitiateRequest(_), Ok(_)) => {
+                self.state = State::Request;
+                self.headers.clear();
+                self.unread.clear();
+                self.written = None;
+            }
+            (AsyncCommand::InitiateResponse, Ok(_)) => {
+                self.state = State::Response;
+                self.status = work.status;
+                self.headers = core::mem::take(&mut work.response_headers);
+            }
+            _ => (),
+        }
+
+        work.result
+    }
+}
+
+/// An async variant of `EspHttpConnection`
+///
+/// The connection is owned by a separate task doing the blocking calls, so that
+/// requests do not block the executor.
+///
+/// All futures are cancellation-safe: if a future is dropped before it completes, the
+/// interrupted operation is still carried out by the worker task, and the next call waits for
+/// it to finish and then applies its outcome as if the future had completed:
+/// - a request (or response) whose `initiate_request` (or `initiate_response`) future was
+///   dropped is initiated if the operation succeeded; until the next call, `is_request_initiated`
+///   (or `is_response_initiated`) does not reflect this yet
+/// - response data read by an interrupted `read` is returned by the next `read` call
+/// - request data sent by an interrupted `write` is not sent again: instead, the next `write`
+///   call returns the length of the data sent by the interrupted one, without sending anything
+///   else, so that the caller can advance past it
+pub struct EspAsyncHttpConnection {
+    unblocker: Unblocker<AsyncWork>,
+    pending: bool,
+    state: AsyncState,
+}
+
+impl EspAsyncHttpConnection {
+    pub fn new(configuration: &Configuration) -> Result<Self, EspError> {
+        Self::wrap(EspHttpConnection::new(configuration)?)
+    }
+
+    /// Moves `connection` into a new task, with a stack of 8K, which is sufficient for HTTPS.
+    pub fn wrap(connection: EspHttpConnection) -> Result<Self, EspError> {
+        let connection = WorkerConnection(connection);
+
+        let unblocker = Unblocker::new(
+            CStr::from_bytes_until_nul(b"HTTP client task\0").unwrap(),
+            8192,
+            None,
+            None,
+            move |channel| Self::work(channel, connection),
+        )?;
+
+        Ok(Self {
+            unblocker,
+            pending: false,
+            state: AsyncState {
+                state: State::New,
+                status: 0,
+                headers: BTreeMap::new(),
+                unread: Vec::new(),
+                written: None,
+            },
+        })
+    }
+
+    pub fn status(&self) -> u16 {
+        self.assert_response();
+        self.state.status
+    }
+
+    pub fn status_message(&self) -> Option<&str> {
+        self.assert_response();
+        None
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.assert_response();
+        self.state
+            .headers
+            .get(UncasedStr::new(name))
+            .map(|s| s.as_str())
+    }
+
+    pub async fn initiate_request(
+        &mut self,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), EspError> {
+        self.complete_pending().await;
+
+        self.assert_initial();
+
+        self.execute(|work| {
+            work.command = AsyncCommand::InitiateRequest(method);
+
+            work.uri.clear();
+            work.uri.push_str(uri);
+
+            work.headers.clear();
+            work.headers.extend(
+                headers
+                    .iter()
+                    .map(|(name, value)| ((*name).into(), (*value).into())),
+            );
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    pub fn is_request_initiated(&self) -> bool {
+        self.state.state == State::Request
+    }
+
+    pub async fn initiate_response(&mut self) -> Result<(), EspError> {
+        self.complete_pending().await;
+
+        self.assert_request();
+
+        self.execute(|work| work.command = AsyncCommand::InitiateResponse)
+            .await?;
+
+        Ok(())
+    }
+
+    pub fn is_response_initiated(&self) -> bool {
+        self.state.state == State::Response
+    }
+
+    pub fn split(&mut self) -> (&EspAsyncHttpConnection, &mut Self) {
+        self.assert_response();
+
+        let headers_ptr: *const EspAsyncHttpConnection = self as *const _;
+
+        let headers = unsafe { headers_ptr.as_ref().unwrap() };
+
+        (headers, self)
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        self.complete_pending().await;
+
+        self.assert_response();
+
+        if !self.state.unread.is_empty() {
+            let len = buf.len().min(self.state.unread.len());
+
+            buf[..len].copy_from_slice(&self.state.unread[..len]);
+            self.state.unread.drain(..len);
+
+            return Ok(len);
+        }
+
+        let len = buf.len().min(ASYNC_TRANSFER_LEN);
+
+        let work = self
+            .execute(|work| work.command = AsyncCommand::Read(len))
+            .await?;
+
+        let read = work.result.unwrap();
+        buf[..read].copy_from_slice(&work.data[..read]);
+
+        Ok(read)
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize, EspError> {
+        self.complete_pending().await;
+
+        self.assert_request();
+
+        if let Some(written) = self.state.written.take() {
+            return Ok(written);
+        }
+
+        let len = buf.len().min(ASYNC_TRANSFER_LEN);
+
+        self.execute(|work| {
+            work.command = AsyncCommand::Write;
+
+            work.data.clear();
+            work.data.extend_from_slice(&buf[..len]);
+        })
+        .await?;
+
+        Ok(len)
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<(), EspError> {
+        let mut offset = 0;
+
+        while offset < data.len() {
+            offset += self.write(&data[offset..]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Hands the work prepared by `prepare` over to the worker task and waits for its result.
+    async fn execute<F>(&mut self, prepare: F) -> Result<&mut AsyncWork, EspError>
+    where
+        F: FnOnce(&mut AsyncWork),
+    {
+        let work = self.unblocker.exec_in_out().await.unwrap();
+
+        prepare(work);
+
+        self.pending = true;
+        self.unblocker.do_exec().await;
+
+        let work = self.unblocker.exec_in_out().await.unwrap();
+        self.pending = false;
+
+        self.state.finish(work)?;
+
+        Ok(work)
+    }
+
+    /// Waits for the operation of a dropped future, if any, to finish.
+    async fn complete_pending(&mut self) {
+        if self.pending {
+            let work = self.unblocker.exec_in_out().await.unwrap();
+            self.pending = false;
+
+            match (work.command, work.result) {
+                (AsyncCommand::Read(_), Ok(read)) => {
+                    self.state.unread.extend_from_slice(&work.data[..read]);
+                }
+                (AsyncCommand::Write, Ok(written)) => self.state.written = Some(written),
+                _ => (),
+            }
+
+            if let Err(err) = self.state.finish(work) {
+                debug!("Interrupted {:?} failed: {}", work.command, err);
+            }
+        }
+    }
+
+    fn work(channel: Arc<Channel<AsyncWork>>, connection: WorkerConnection) {
+        let mut connection = connection.0;
+
+        let mut work = AsyncWork {
+            command: AsyncCommand::InitiateResponse,
+            uri: String::new(),
+            headers: Vec::new(),
+            data: Vec::new(),
+            result: Ok(0),
+            status: 0,
+            response_headers: BTreeMap::new(),
+        };
+
+        while channel.share(&mut work) {
+            work.result = match work.command {
+                AsyncCommand::InitiateRequest(method) => {
+                    let headers = work
+                        .headers
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str()))
+                        .collect::<Vec<_>>();
+
+                    connection
+                        .initiate_request(method, &work.uri, &headers)
+                        .map(|_| 0)
+                }
+                AsyncCommand::InitiateResponse => connection.initiate_response().map(|_| {
+                    work.status = connection.status();
+                    work.response_headers = connection.headers.clone();
+
+                    if let Some(len) = connection.header("Content-Length") {
+                        work.response_headers
+                            .insert(Uncased::from("Content-Length"), len.into());
+                    }
+
+                    0
+                }),
+                AsyncCommand::Read(len) => {
+                    work.data.resize(len, 0);
+                    connection.read(&mut work.data)
+                }
+                AsyncCommand::Write => connection.write_all(&work.data).map(|_| work.data.len()),
+            };
+        }
+    }
+
+    fn assert_initial(&self) {
+        if self.state.state != State::New && self.state.state != State::Response {
+            panic!("connection is not in initial phase");
+        }
+    }
+
+    fn assert_request(&self) {
+        if self.state.state != State::Request {
+            panic!("connection is not in request phase");
+        }
+    }
+
+    fn assert_response(&self) {
+        if self.state.state != State::Response {
+            panic!("connection is not in response phase");
+        }
+    }
+}
+
+impl Status for EspAsyncHttpConnection {
+    fn status(&self) -> u16 {
+        EspAsyncHttpConnection::status(self)
+    }
+
+    fn status_message(&self) -> Option<&str> {
+        EspAsyncHttpConnection::status_message(self)
+    }
+}
+
+impl embedded_svc::http::Headers for EspAsyncHttpConnection {
+    fn header(&self, name: &str) -> Option<&str> {
+        EspAsyncHttpConnection::header(self, name)
+    }
+}
+
+impl ErrorType for EspAsyncHttpConnection {
+    type Error = EspIOError;
+}
+
+impl embedded_svc::io::asynch::Read for EspAsyncHttpConnection {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let size = EspAsyncHttpConnection::read(self, buf).await?;
+
+        Ok(size)
+    }
+}
+
+impl embedded_svc::io::asynch::Write for EspAsyncHttpConnection {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let size = EspAsyncHttpConnection::write(self, buf).await?;
+
+        Ok(size)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.assert_request();
+
+        Ok(())
+    }
+}
+
+impl asynch::Connection for EspAsyncHttpConnection {
+    type Headers = Self;
+
+    type Read = Self;
+
+    type RawConnectionError = EspIOError;
+
+    type RawConnection = Self;
+
+    async fn initiate_request<'a>(
+        &'a mut self,
+        method: Method,
+        uri: &'a str,
+        headers: &'a [(&'a str, &'a str)],
+    ) -> Result<(), Self::Error> {
+        EspAsyncHttpConnection::initiate_request(self, method, uri, headers)
+            .await
+            .map_err(EspIOError)
+    }
+
+    fn is_request_initiated(&self) -> bool {
+        EspAsyncHttpConnection::is_request_initiated(self)
+    }
+
+    async fn initiate_response(&mut self) -> Result<(), Self::Error> {
+        EspAsyncHttpConnection::initiate_response(self)
+            .await
+            .map_err(EspIOError)
+    }
+
+    fn is_response_initiated(&self) -> bool {
+        EspAsyncHttpConnection::is_response_initiated(self)
+    }
+
+    fn split(&mut self) -> (&Self::Headers, &mut Self::Read) {
+        EspAsyncHttpConnection::split(self)
+    }
+
+    fn raw_connection(&mut self) -> Result<&mut Self::RawConnection, Self::Error> {
+        Err(EspError::from_infallible::<ESP_FAIL>().into())
+    }
+}
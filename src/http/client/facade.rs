@@ -0,0 +1,1030 @@
+//! A high-level client on top of `EspHttpConnection`
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use uncased::{Uncased, UncasedStr};
+
+use crate::hal::delay::FreeRtos;
+use crate::sys::*;
+
+use crate::systime::EspSystemTime;
+
+use super::{Configuration, EspHttpConnection, FollowRedirectsPolicy, Method};
+
+const DEFAULT_MAX_BODY_LEN: usize = 16 * 1024;
+
+/// The maximum number of redirects followed for a request
+const MAX_REDIRECTS: usize = 10;
+
+/// The default timeout of the ESP-IDF HTTP client
+#[cfg(not(esp_idf_version_major = "4"))]
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// When and how often `EspHttpClient` retries failed requests
+///
+/// Requests are retried if connecting to the server fails. Requests with an idempotent method
+/// (`GET`, `HEAD`, `PUT`, `DELETE`, `OPTIONS` and `TRACE`) are also retried if the server
+/// answers with a 5xx status, or if sending the request or receiving the response headers fails,
+/// e.g. with a timeout. The delay between the attempts starts at `initial_backoff` and is doubled
+/// after each attempt, up to `max_backoff`.
+///
+/// As the server may already have processed a request which failed that way, other requests
+/// (e.g. `POST`) are only retried in these cases if `retry_non_idempotent` is set.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub retry_non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    /// No retries
+    pub const NONE: Self = Self::new(0);
+
+    /// Creates a policy with up to `max_retries` retries and a backoff from 500ms to 10s.
+    pub const fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            retry_non_idempotent: false,
+        }
+    }
+
+    fn is_retryable(
+        &self,
+        method: Method,
+        result: &Result<EspHttpClientResponse, EspError>,
+    ) -> bool {
+        let idempotent = matches!(
+            method,
+            Method::Get
+                | Method::Head
+                | Method::Put
+                | Method::Delete
+                | Method::Options
+                | Method::Trace
+        );
+
+        // The request has not reached the server if connecting failed
+        if matches!(result, Err(err) if err.code() == ESP_ERR_HTTP_CONNECT) {
+            return true;
+        }
+
+        if !idempotent && !self.retry_non_idempotent {
+            return false;
+        }
+
+        match result {
+            Ok(response) => (500..600).contains(&response.status),
+            Err(err) => [
+                ESP_ERR_HTTP_WRITE_DATA,
+                ESP_ERR_HTTP_FETCH_HEADER,
+                ESP_ERR_HTTP_EAGAIN,
+                ESP_ERR_TIMEOUT,
+            ]
+            .contains(&err.code()),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// An HTTP client which sends requests with a buffered body and receives buffered responses,
+/// keeps the cookies set by servers and retries failed requests
+///
+/// ```
+/// use esp_idf_svc::http::client::{Configuration, EspHttpClient, RetryPolicy};
+///
+/// let mut client = EspHttpClient::new(&Configuration {
+///     crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+///     ..Default::default()
+/// })?
+/// .retry(RetryPolicy::new(3));
+///
+/// let response = client
+///     .request(Method::Get, "https://example.com/api/status")
+///     .header("Accept", "application/json")
+///     .timeout(Duration::from_secs(5))
+///     .send()?;
+///
+/// let status: Status = response.json()?;
+/// ```
+///
+/// Redirects are followed according to the `follow_redirects_policy` of the `Configuration` by
+/// `EspHttpClient` itself (rather than by its connection), so that the cookies of each hop are
+/// sent and stored for the host and scheme of that hop. Like browsers do, requests are changed to
+/// `GET` requests without a body by a `303` redirect, and `POST` requests by a `301` or `302`
+/// redirect. Authentication challenges are answered with the credentials of the `Configuration`.
+///
+/// The cookie jar keeps each cookie for the host which set it, for any path of that host (i.e.
+/// the `Domain` and `Path` attributes are ignored). Cookies with the `Secure` attribute are only
+/// accepted from and sent to `https://` URIs, and cookies are removed once they expire according
+/// to their `Expires` or `Max-Age` attribute, as measured by the system time.
+pub struct EspHttpClient {
+    connection: EspHttpConnection,
+    follow_redirects_policy: FollowRedirectsPolicy,
+    retry: RetryPolicy,
+    max_body_len: usize,
+    #[cfg(not(esp_idf_version_major = "4"))]
+    default_timeout: Duration,
+    default_headers: Vec<(String, String)>,
+    cookies: Option<BTreeMap<(String, String), Cookie>>,
+}
+
+impl EspHttpClient {
+    pub fn new(configuration: &Configuration) -> Result<Self, EspError> {
+        Ok(Self {
+            connection: EspHttpConnection::new(&Configuration {
+                follow_redirects_policy: FollowRedirectsPolicy::FollowNone,
+                ..*configuration
+            })?,
+            follow_redirects_policy: configuration.follow_redirects_policy,
+            retry: RetryPolicy::NONE,
+            max_body_len: DEFAULT_MAX_BODY_LEN,
+            #[cfg(not(esp_idf_version_major = "4"))]
+            default_timeout: configuration.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            default_headers: Vec::new(),
+            cookies: Some(BTreeMap::new()),
+        })
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the maximum size of response bodies, 16K by default.
+    pub fn max_body_len(mut self, max_body_len: usize) -> Self {
+        self.max_body_len = max_body_len;
+        self
+    }
+
+    /// Adds a header which is sent with every request.
+    pub fn default_header(mut self, name: &str, value: &str) -> Self {
+        self.default_headers
+            .push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Enables or disables the cookie jar, which is enabled by default.
+    pub fn cookies(mut self, enabled: bool) -> Self {
+        self.cookies = enabled.then(BTreeMap::new);
+        self
+    }
+
+    /// Returns the value of the cookie `name` set by `host`, unless it has expired.
+    pub fn cookie(&self, host: &str, name: &str) -> Option<&str> {
+        self.cookies
+            .as_ref()?
+            .get(&(host.to_ascii_lowercase(), name.to_owned()))
+            .filter(|cookie| !cookie.is_expired(EspSystemTime.now()))
+            .map(|cookie| cookie.value.as_str())
+    }
+
+    pub fn clear_cookies(&mut self) {
+        if let Some(cookies) = self.cookies.as_mut() {
+            cookies.clear();
+        }
+    }
+
+    pub fn get(&mut self, uri: &str) -> Result<EspHttpClientResponse, EspError> {
+        self.request(Method::Get, uri).send()
+    }
+
+    pub fn post(&mut self, uri: &str, body: &[u8]) -> Result<EspHttpClientResponse, EspError> {
+        self.request(Method::Post, uri).body(body).send()
+    }
+
+    pub fn put(&mut self, uri: &str, body: &[u8]) -> Result<EspHttpClientResponse, EspError> {
+        self.request(Method::Put, uri).body(body).send()
+    }
+
+    pub fn delete(&mut self, uri: &str) -> Result<EspHttpClientResponse, EspError> {
+        self.request(Method::Delete, uri).send()
+    }
+
+    /// Creates a request, which is sent with `EspHttpClientRequest::send`.
+    pub fn request<'a>(&'a mut self, method: Method, uri: &'a str) -> EspHttpClientRequest<'a> {
+        EspHttpClientRequest {
+            client: self,
+            request: RequestParts {
+                method,
+                uri,
+                headers: Vec::new(),
+                body: None,
+                timeout: None,
+            },
+        }
+    }
+
+    fn send(&mut self, request: &RequestParts<'_>) -> Result<EspHttpClientResponse, EspError> {
+        let policy = self.retry;
+
+        with_retries(
+            &policy,
+            request.method,
+            request.uri,
+            self,
+            |client| client.execute_with_redirects(request),
+            |client| {
+                // The failed request may have been left half-sent or half-received
+                if let Err(err) = client.connection.reset() {
+                    warn!("Closing the connection to {} failed: {}", request.uri, err);
+                }
+            },
+            |backoff| FreeRtos::delay_ms(backoff.as_millis() as _),
+        )
+    }
+
+    fn execute_with_redirects(
+        &mut self,
+        request: &RequestParts<'_>,
+    ) -> Result<EspHttpClientResponse, EspError> {
+        let mut response = self.execute_with_auth(request)?;
+
+        let mut method = request.method;
+        let mut uri = request.uri.to_owned();
+        let mut headers = request.headers.clone();
+        let mut body = request.body;
+
+        for _ in 0..MAX_REDIRECTS {
+            let follow = match self.follow_redirects_policy {
+                FollowRedirectsPolicy::FollowAll => true,
+                FollowRedirectsPolicy::FollowGetHead => {
+                    method == Method::Get || method == Method::Head
+                }
+                FollowRedirectsPolicy::FollowNone => false,
+            };
+
+            let location = response
+                .header("Location")
+                .filter(|_| follow && matches!(response.status, 301 | 302 | 303 | 307 | 308));
+
+            let Some(location) = location else {
+                return Ok(response);
+            };
+
+            uri = resolve_uri(&uri, location);
+
+            if response.status == 303
+                || (matches!(response.status, 301 | 302) && method == Method::Post)
+            {
+                if method != Method::Head {
+                    method = Method::Get;
+                }
+
+                body = None;
+                headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+            }
+
+            info!(
+                "Got response {}, following the redirect to {}",
+                response.status, uri
+            );
+
+            response = self.execute_with_auth(&RequestParts {
+                method,
+                uri: &uri,
+                headers: headers.clone(),
+                body,
+                timeout: request.timeout,
+            })?;
+        }
+
+        warn!(
+            "Request to {} exceeded the maximum of {} redirects",
+            request.uri, MAX_REDIRECTS
+        );
+
+        Err(EspError::from_infallible::<ESP_ERR_HTTP_MAX_REDIRECT>())
+    }
+
+    fn execute_with_auth(
+        &mut self,
+        request: &RequestParts<'_>,
+    ) -> Result<EspHttpClientResponse, EspError> {
+        let response = self.execute(request)?;
+
+        if self.connection.is_auth_challenged() {
+            debug!(
+                "Sending the request to {} again with credentials",
+                request.uri
+            );
+
+            return self.execute(request);
+        }
+
+        Ok(response)
+    }
+
+    fn execute(&mut self, request: &RequestParts<'_>) -> Result<EspHttpClientResponse, EspError> {
+        let host = host(request.uri).to_ascii_lowercase();
+        let secure = is_secure(request.uri);
+
+        let cookie_header = self.cookies.as_mut().map(|cookies| {
+            let now = EspSystemTime.now();
+
+            cookies.retain(|_, cookie| !cookie.is_expired(now));
+
+            cookies
+                .iter()
+                .filter(|((cookie_host, _), cookie)| {
+                    *cookie_host == host && (secure || !cookie.secure)
+                })
+                .map(|((_, name), cookie)| format!("{name}={}", cookie.value))
+                .collect::<Vec<_>>()
+                .join("; ")
+        });
+
+        let content_len = request.body.map(|body| format!("{}", body.len()));
+
+        let mut headers = self
+            .default_headers
+            .iter()
+            .chain(request.headers.iter())
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+
+        if let Some(cookie_header) = cookie_header.as_deref().filter(|c| !c.is_empty()) {
+            headers.push(("Cookie", cookie_header));
+        }
+
+        if let Some(content_len) = content_len.as_deref() {
+            headers.push(("Content-Length", content_len));
+        }
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        self.connection
+            .set_timeout(request.timeout.unwrap_or(self.default_timeout))?;
+
+        #[cfg(esp_idf_version_major = "4")]
+        if request.timeout.is_some() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_SUPPORTED>());
+        }
+
+        debug!("Sending {:?} request to {}", request.method, request.uri);
+
+        self.connection
+            .initiate_request(request.method, request.uri, &headers)?;
+
+        if let Some(body) = request.body {
+            self.connection.write_all(body)?;
+        }
+
+        self.connection.initiate_response()?;
+
+        let status = self.connection.status();
+
+        let mut response_headers = self.connection.headers.clone();
+
+        if let Some(content_len) = self.connection.header("Content-Length") {
+            response_headers.insert(Uncased::from("Content-Length"), content_len.to_owned());
+        }
+
+        if let Some(cookies) = self.cookies.as_mut() {
+            let now = EspSystemTime.now();
+
+            for set_cookie in self.connection.set_cookies() {
+                let Some((name, cookie)) = parse_set_cookie(set_cookie, now) else {
+                    continue;
+                };
+
+                match cookie {
+                    // A secure cookie must not be set (or overwritten) by an insecure response
+                    Some(cookie) if cookie.secure && !secure => (),
+                    Some(cookie) => {
+                        cookies.insert((host.clone(), name.to_owned()), cookie);
+                    }
+                    None => {
+                        cookies.remove(&(host.clone(), name.to_owned()));
+                    }
+                }
+            }
+        }
+
+        let mut body = Vec::new();
+        let mut buf = [0; 512];
+
+        loop {
+            let len = self.connection.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            if body.len() + len > self.max_body_len {
+                warn!(
+                    "Response from {} exceeds the maximum body size of {} bytes",
+                    request.uri, self.max_body_len
+                );
+
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+            }
+
+            body.extend_from_slice(&buf[..len]);
+        }
+
+        Ok(EspHttpClientResponse {
+            status,
+            headers: response_headers,
+            body,
+        })
+    }
+}
+
+/// Runs `attempt` on `target` until it succeeds or its result is not retryable according to
+/// `policy`, waiting with `delay` between the attempts.
+///
+/// `recover` is called after each attempt which failed with an error, whether it is retried or not.
+fn with_retries<T>(
+    policy: &RetryPolicy,
+    method: Method,
+    uri: &str,
+    target: &mut T,
+    mut attempt: impl FnMut(&mut T) -> Result<EspHttpClientResponse, EspError>,
+    mut recover: impl FnMut(&mut T),
+    mut delay: impl FnMut(Duration),
+) -> Result<EspHttpClientResponse, EspError> {
+    let mut backoff = policy.initial_backoff;
+    let mut retries = 0;
+
+    loop {
+        let result = attempt(target);
+
+        if result.is_err() {
+            recover(target);
+        }
+
+        if retries >= policy.max_retries || !policy.is_retryable(method, &result) {
+            return result;
+        }
+
+        retries += 1;
+
+        match &result {
+            Ok(response) => warn!(
+                "Request to {} failed with status {}, retrying in {:?} ({}/{})",
+                uri, response.status, backoff, retries, policy.max_retries
+            ),
+            Err(err) => warn!(
+                "Request to {} failed: {}, retrying in {:?} ({}/{})",
+                uri, err, backoff, retries, policy.max_retries
+            ),
+        }
+
+        delay(backoff);
+
+        backoff = (backoff * 2).min(policy.max_backoff);
+    }
+}
+
+struct RequestParts<'a> {
+    method: Method,
+    uri: &'a str,
+    headers: Vec<(String, String)>,
+    body: Option<&'a [u8]>,
+    timeout: Option<Duration>,
+}
+
+/// A request being built, see `EspHttpClient::request`
+pub struct EspHttpClientRequest<'a> {
+    client: &'a mut EspHttpClient,
+    request: RequestParts<'a>,
+}
+
+impl<'a> EspHttpClientRequest<'a> {
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.request
+            .headers
+            .push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, body: &'a [u8]) -> Self {
+        self.request.body = Some(body);
+        self
+    }
+
+    /// Sets the network timeout for this request, instead of the one of the `Configuration`.
+    ///
+    /// Not supported with ESP-IDF 4.x.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.request.timeout = Some(timeout);
+        self
+    }
+
+    pub fn send(self) -> Result<EspHttpClientResponse, EspError> {
+        self.client.send(&self.request)
+    }
+
+    /// Serializes `value` as JSON and sends it as the body of the request.
+    #[cfg(feature = "json")]
+    pub fn send_json<T>(self, value: &T) -> Result<EspHttpClientResponse, EspError>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        let body = serde_json::to_vec(value).map_err(|err| {
+            warn!("Serializing the JSON request failed: {}", err);
+            EspError::from_infallible::<ESP_FAIL>()
+        })?;
+
+        let mut request = self.request;
+
+        request
+            .headers
+            .push(("Content-Type".to_owned(), "application/json".to_owned()));
+
+        self.client.send(&RequestParts {
+            body: Some(&body),
+            ..request
+        })
+    }
+}
+
+/// A response with its body, see `EspHttpClient`
+#[derive(Clone, Debug)]
+pub struct EspHttpClientResponse {
+    status: u16,
+    headers: BTreeMap<Uncased<'static>, String>,
+    body: Vec<u8>,
+}
+
+impl EspHttpClientResponse {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns `true` for a 2xx status.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(UncasedStr::new(name)).map(String::as_str)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// Returns the body as text, failing with `ESP_ERR_INVALID_RESPONSE` if it is not valid UTF-8.
+    pub fn text(&self) -> Result<&str, EspError> {
+        core::str::from_utf8(&self.body)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())
+    }
+
+    /// Deserializes the body from JSON.
+    #[cfg(feature = "json")]
+    pub fn json<T>(&self) -> Result<T, serde_json::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+impl embedded_svc::http::Status for EspHttpClientResponse {
+    fn status(&self) -> u16 {
+        EspHttpClientResponse::status(self)
+    }
+
+    fn status_message(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl embedded_svc::http::Headers for EspHttpClientResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        EspHttpClientResponse::header(self, name)
+    }
+}
+
+/// A cookie in the cookie jar of `EspHttpClient`
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Cookie {
+    value: String,
+    secure: bool,
+    /// The expiry time, as time since the UNIX epoch
+    expires: Option<Duration>,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: Duration) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Parses a `Set-Cookie` header into the name of the cookie and the cookie, which is `None` if
+/// the header removes the cookie (with an empty value or an expiry time in the past).
+fn parse_set_cookie(set_cookie: &str, now: Duration) -> Option<(&str, Option<Cookie>)> {
+    let mut parts = set_cookie.split(';');
+
+    let (name, value) = parts.next()?.split_once('=')?;
+
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+
+    let mut secure = false;
+    let mut expires = None;
+    let mut max_age = None;
+
+    for attr in parts {
+        let (attr, attr_value) = attr.split_once('=').unwrap_or((attr, ""));
+        let (attr, attr_value) = (attr.trim(), attr_value.trim());
+
+        if attr.eq_ignore_ascii_case("Secure") {
+            secure = true;
+        } else if attr.eq_ignore_ascii_case("Expires") {
+            expires = parse_http_date(attr_value);
+        } else if attr.eq_ignore_ascii_case("Max-Age") {
+            max_age = attr_value.parse::<i64>().ok();
+        }
+    }
+
+    // `Max-Age` takes precedence over `Expires`
+    let expires = match max_age {
+        Some(max_age) if max_age <= 0 => Some(Duration::ZERO),
+        Some(max_age) => now.checked_add(Duration::from_secs(max_age as u64)),
+        None => expires,
+    };
+
+    let cookie = Cookie {
+        value: value.to_owned(),
+        secure,
+        expires,
+    };
+
+    let removed = value.is_empty() || cookie.is_expired(now);
+
+    Some((name, (!removed).then_some(cookie)))
+}
+
+/// Parses an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT` or the obsolete
+/// `Sunday, 06-Nov-94 08:49:37 GMT`, into the time since the UNIX epoch.
+fn parse_http_date(date: &str) -> Option<Duration> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (_, date) = date.split_once(',')?;
+
+    let mut parts = date.split([' ', '-']).filter(|part| !part.is_empty());
+
+    let day: u64 = parts.next()?.parse().ok()?;
+
+    let month = parts.next()?;
+    let month = MONTHS
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(month))? as u64
+        + 1;
+
+    let year: u64 = match parts.next()?.parse().ok()? {
+        year @ 0..=69 => year + 2000,
+        year @ 70..=99 => year + 1900,
+        year => year,
+    };
+
+    let mut time = parts
+        .next()?
+        .split(':')
+        .map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    if !(1..=31).contains(&day) || year < 1970 || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(Duration::from_secs(
+        days * 86400 + hours * 3600 + minutes * 60 + seconds,
+    ))
+}
+
+/// Returns `true` if `uri` is an `https://` URI.
+fn is_secure(uri: &str) -> bool {
+    uri.get(..8)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"))
+}
+
+/// Resolves the `Location` of a redirect against the `uri` of the redirected request.
+fn resolve_uri(uri: &str, location: &str) -> String {
+    let (scheme, rest) = uri.split_once("://").unwrap_or(("http", uri));
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+
+    let location_scheme = location
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        });
+
+    if location_scheme.is_some() {
+        location.to_owned()
+    } else if location.starts_with("//") {
+        format!("{scheme}:{location}")
+    } else if location.starts_with('/') {
+        format!("{scheme}://{authority}{location}")
+    } else {
+        let path = rest[authority.len()..]
+            .split(['?', '#'])
+            .next()
+            .unwrap_or_default();
+        let dir = path
+            .rsplit_once('/')
+            .map(|(dir, _)| dir)
+            .unwrap_or_default();
+
+        format!("{scheme}://{authority}{dir}/{location}")
+    }
+}
+
+/// Returns the host of `uri`, e.g. `example.com` for `https://user@example.com:8080/path`.
+fn host(uri: &str) -> &str {
+    let authority = uri.split_once("://").map(|(_, rest)| rest).unwrap_or(uri);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+    let authority = authority
+        .rsplit_once('@')
+        .map(|(_, host)| host)
+        .unwrap_or(authority);
+
+    if let Some(ipv6) = authority.strip_prefix('[') {
+        ipv6.split(']').next().unwrap_or_default()
+    } else {
+        authority.split(':').next().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts() {
+        assert_eq!(host("http://example.com"), "example.com");
+        assert_eq!(host("https://user:pw@example.com:8443/a?b"), "example.com");
+        assert_eq!(host("http://[::1]:8080/"), "::1");
+        assert_eq!(host("example.com/path"), "example.com");
+    }
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(
+            parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::from_secs(784111777))
+        );
+        assert_eq!(
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            Some(Duration::from_secs(784111777))
+        );
+        assert_eq!(
+            parse_http_date("Tue, 29 Feb 2028 23:59:59 GMT"),
+            Some(Duration::from_secs(1835481599))
+        );
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn parses_set_cookie() {
+        let now = Duration::from_secs(784111777);
+
+        assert_eq!(
+            parse_set_cookie("id=a3fWa; Path=/; Secure; HttpOnly", now),
+            Some((
+                "id",
+                Some(Cookie {
+                    value: "a3fWa".into(),
+                    secure: true,
+                    expires: None,
+                })
+            ))
+        );
+        assert_eq!(
+            parse_set_cookie("id=\"a3fWa\"; Max-Age=60", now),
+            Some((
+                "id",
+                Some(Cookie {
+                    value: "a3fWa".into(),
+                    secure: false,
+                    expires: Some(now + Duration::from_secs(60)),
+                })
+            ))
+        );
+        assert_eq!(
+            parse_set_cookie("id=a3fWa; Expires=Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(("id", None))
+        );
+        assert_eq!(
+            parse_set_cookie("id=a3fWa; Expires=Sun, 06 Nov 1994 08:49:38 GMT", now),
+            Some((
+                "id",
+                Some(Cookie {
+                    value: "a3fWa".into(),
+                    secure: false,
+                    expires: Some(now + Duration::from_secs(1)),
+                })
+            ))
+        );
+        assert_eq!(
+            parse_set_cookie(
+                "id=a3fWa; Expires=Sun, 06 Nov 1994 08:49:38 GMT; Max-Age=0",
+                now
+            ),
+            Some(("id", None))
+        );
+        assert_eq!(parse_set_cookie("id=", now), Some(("id", None)));
+        assert_eq!(parse_set_cookie("=a3fWa", now), None);
+        assert_eq!(parse_set_cookie("id", now), None);
+    }
+
+    #[test]
+    fn resolves_redirect_locations() {
+        let uri = "https://example.com/a/b?c=d";
+
+        assert_eq!(
+            resolve_uri(uri, "http://example.org/e"),
+            "http://example.org/e"
+        );
+        assert_eq!(resolve_uri(uri, "//example.org/e"), "https://example.org/e");
+        assert_eq!(resolve_uri(uri, "/e?f"), "https://example.com/e?f");
+        assert_eq!(resolve_uri(uri, "e"), "https://example.com/a/e");
+        assert_eq!(
+            resolve_uri("http://example.com", "e"),
+            "http://example.com/e"
+        );
+        assert_eq!(
+            resolve_uri("http://example.com:8080?a=/b", "e"),
+            "http://example.com:8080/e"
+        );
+    }
+
+    #[test]
+    fn secure_uris() {
+        assert!(is_secure("https://example.com"));
+        assert!(is_secure("HTTPS://example.com"));
+        assert!(!is_secure("http://example.com"));
+        assert!(!is_secure("example.com"));
+    }
+
+    /// Panics like `EspHttpConnection` if a request is initiated while another one is in progress
+    struct FakeConnection {
+        results: Vec<Result<u16, esp_err_t>>,
+        in_progress: bool,
+        attempts: usize,
+        resets: usize,
+    }
+
+    impl FakeConnection {
+        fn new(results: &[Result<u16, esp_err_t>]) -> Self {
+            Self {
+                results: results.iter().rev().copied().collect(),
+                in_progress: false,
+                attempts: 0,
+                resets: 0,
+            }
+        }
+
+        fn attempt(&mut self) -> Result<EspHttpClientResponse, EspError> {
+            assert!(!self.in_progress, "connection is not in initial phase");
+
+            self.attempts += 1;
+            self.in_progress = true;
+
+            let status = self
+                .results
+                .pop()
+                .unwrap()
+                .map_err(|code| EspError::from(code).unwrap())?;
+
+            self.in_progress = false;
+
+            Ok(EspHttpClientResponse {
+                status,
+                headers: BTreeMap::new(),
+                body: Vec::new(),
+            })
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+            self.in_progress = false;
+        }
+    }
+
+    fn run(
+        policy: RetryPolicy,
+        method: Method,
+        connection: &mut FakeConnection,
+    ) -> (Result<EspHttpClientResponse, EspError>, Vec<Duration>) {
+        let mut delays = Vec::new();
+
+        let result = with_retries(
+            &policy,
+            method,
+            "http://example.com",
+            connection,
+            FakeConnection::attempt,
+            FakeConnection::reset,
+            |delay| delays.push(delay),
+        );
+
+        (result, delays)
+    }
+
+    #[test]
+    fn retries_after_resetting_failed_requests() {
+        let mut connection = FakeConnection::new(&[
+            Err(ESP_ERR_HTTP_FETCH_HEADER),
+            Err(ESP_ERR_TIMEOUT),
+            Ok(503),
+            Ok(200),
+        ]);
+
+        let (result, delays) = run(RetryPolicy::new(3), Method::Get, &mut connection);
+
+        assert_eq!(result.unwrap().status, 200);
+        assert_eq!(connection.attempts, 4);
+        assert_eq!(connection.resets, 2);
+        assert_eq!(
+            delays,
+            [
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn resets_failed_requests_which_are_not_retried() {
+        let mut connection = FakeConnection::new(&[Err(ESP_ERR_HTTP_FETCH_HEADER), Ok(200)]);
+
+        let (result, delays) = run(RetryPolicy::new(3), Method::Post, &mut connection);
+
+        assert_eq!(result.unwrap_err().code(), ESP_ERR_HTTP_FETCH_HEADER);
+        assert_eq!(connection.resets, 1);
+        assert!(delays.is_empty());
+
+        // The next request on the same connection does not panic
+        assert_eq!(connection.attempt().unwrap().status, 200);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut connection = FakeConnection::new(&[
+            Err(ESP_ERR_HTTP_CONNECT),
+            Err(ESP_ERR_HTTP_CONNECT),
+            Err(ESP_ERR_HTTP_CONNECT),
+        ]);
+
+        let (result, delays) = run(RetryPolicy::new(2), Method::Post, &mut connection);
+
+        assert_eq!(result.unwrap_err().code(), ESP_ERR_HTTP_CONNECT);
+        assert_eq!(connection.attempts, 3);
+        assert_eq!(connection.resets, 3);
+        assert_eq!(delays.len(), 2);
+    }
+}
@@ -0,0 +1,528 @@
+//! Tunnelling through HTTP proxies
+//!
+//! The ESP-IDF HTTP client - and therefore `EspHttpConnection` and `EspHttpClient` - cannot
+//! connect through a proxy, and `Configuration` has no proxy options: proxy support is limited
+//! to connections made by the application itself over a `ProxyTunnel`.
+//!
+//! `ProxyTunnel` opens a tunnel to a host with an HTTP `CONNECT` request to a proxy, optionally
+//! authenticating with Basic or Digest authentication. The tunnel is a plain TCP connection to
+//! the host, which can be adopted by `EspTls` for HTTPS (or any other TLS-based protocol):
+//!
+//! ```
+//! use esp_idf_svc::http::client::proxy::{Proxy, ProxyTunnel};
+//! use esp_idf_svc::tls::{Config, EspTls};
+//!
+//! let proxy = Proxy {
+//!     username: Some("user"),
+//!     password: Some("secret"),
+//!     ..Proxy::new("proxy.example.com", 3128)
+//! };
+//!
+//! let tunnel = ProxyTunnel::connect(&proxy, "example.com", 443)?;
+//!
+//! let mut tls = EspTls::adopt(tunnel)?;
+//! tls.negotiate("example.com", &Config::new())?;
+//!
+//! tls.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")?;
+//! ```
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use std::io::{Read as _, Write as _};
+use std::net::TcpStream;
+
+use ::log::*;
+
+use embedded_svc::io::{ErrorType, Read, Write};
+
+use crate::io::EspIOError;
+use crate::sys::*;
+
+use crate::private::auth::{parse_params, quote, strip_scheme};
+use crate::private::base64;
+use crate::private::hash::{Md5, Sha256};
+use crate::private::hex;
+use crate::private::random::random;
+
+use super::AuthType;
+
+/// The maximum size of the response of the proxy to the `CONNECT` request
+const MAX_RESPONSE_LEN: usize = 1024;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Proxy<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// When the credentials are sent to the proxy
+    pub auth_type: AuthType,
+    /// The timeout for connecting to the proxy and for the tunnel
+    pub timeout: Option<Duration>,
+}
+
+impl<'a> Proxy<'a> {
+    pub const fn new(host: &'a str, port: u16) -> Self {
+        Self {
+            host,
+            port,
+            username: None,
+            password: None,
+            auth_type: AuthType::Challenge,
+            timeout: None,
+        }
+    }
+}
+
+/// A TCP connection to a host, tunnelled through an HTTP proxy, see the module documentation
+pub struct ProxyTunnel(Option<TcpStream>);
+
+impl ProxyTunnel {
+    /// Connects to `proxy` and asks it to open a tunnel to `host`:`port`.
+    ///
+    /// If the proxy answers with `407 Proxy Authentication Required`, the request is sent again
+    /// over a new connection, answering the Digest (with SHA-256 or MD5) or Basic challenge of
+    /// the proxy with the credentials of `proxy`.
+    ///
+    /// # Errors
+    ///
+    /// * `ESP_ERR_HTTP_CONNECT` if connecting to the proxy fails, or if the proxy refuses to
+    ///   open the tunnel (e.g. if the credentials are missing or wrong)
+    /// * `ESP_ERR_INVALID_RESPONSE` if the response of the proxy is malformed
+    pub fn connect(proxy: &Proxy<'_>, host: &str, port: u16) -> Result<Self, EspError> {
+        let authority = authority(host, port);
+
+        let authorization = match (proxy.auth_type, proxy.username) {
+            (AuthType::Basic, Some(username)) => Some(basic_authorization(
+                username,
+                proxy.password.unwrap_or_default(),
+            )),
+            _ => None,
+        };
+
+        let (mut stream, mut response) = Self::request(
+            proxy,
+            &connect_request(&authority, authorization.as_deref()),
+        )?;
+
+        if response_status(&response) == Some(407) {
+            if let Some(username) = proxy.username {
+                let authorization = authorization_for(
+                    username,
+                    proxy.password.unwrap_or_default(),
+                    &authority,
+                    response_headers(&response, "Proxy-Authenticate"),
+                    &hex::encode(&random::<16>()),
+                );
+
+                if let Some(authorization) = authorization {
+                    debug!(
+                        "Answering the authentication challenge of proxy {}:{}",
+                        proxy.host, proxy.port
+                    );
+
+                    (stream, response) =
+                        Self::request(proxy, &connect_request(&authority, Some(&authorization)))?;
+                } else {
+                    warn!(
+                        "Proxy {}:{} requested an unsupported authentication scheme",
+                        proxy.host, proxy.port
+                    );
+                }
+            }
+        }
+
+        let status = response_status(&response)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>)?;
+
+        if !(200..300).contains(&status) {
+            warn!(
+                "Proxy {}:{} refused the tunnel to {} with status {}",
+                proxy.host, proxy.port, authority, status
+            );
+
+            return Err(EspError::from_infallible::<ESP_ERR_HTTP_CONNECT>());
+        }
+
+        debug!(
+            "Opened a tunnel to {} through proxy {}:{}",
+            authority, proxy.host, proxy.port
+        );
+
+        Ok(Self(Some(stream)))
+    }
+
+    /// Connects to `proxy` and sends `request`, returning the connection and the response header.
+    ///
+    /// As the response body of a refused `CONNECT` request is not read, the connection should
+    /// not be reused for another request.
+    fn request(proxy: &Proxy<'_>, request: &str) -> Result<(TcpStream, String), EspError> {
+        let mut stream = TcpStream::connect((proxy.host, proxy.port)).map_err(|err| {
+            warn!(
+                "Connecting to proxy {}:{} failed: {}",
+                proxy.host, proxy.port, err
+            );
+
+            EspError::from_infallible::<ESP_ERR_HTTP_CONNECT>()
+        })?;
+
+        stream
+            .set_read_timeout(proxy.timeout)
+            .and_then(|_| stream.set_write_timeout(proxy.timeout))
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_HTTP_CONNECT>())?;
+
+        // Reading byte by byte, so that nothing sent by the host after the response is consumed
+        let mut response = Vec::new();
+
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() == MAX_RESPONSE_LEN {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+            }
+
+            let mut byte = [0];
+
+            match stream.read(&mut byte) {
+                Ok(0) | Err(_) => return Err(EspError::from_infallible::<ESP_ERR_HTTP_CONNECT>()),
+                Ok(_) => response.push(byte[0]),
+            }
+        }
+
+        let response = String::from_utf8(response)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>())?;
+
+        Ok((stream, response))
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        self.0.as_ref().unwrap()
+    }
+
+    pub fn into_stream(mut self) -> TcpStream {
+        self.0.take().unwrap()
+    }
+
+    fn stream_mut(&mut self) -> &mut TcpStream {
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl ErrorType for ProxyTunnel {
+    type Error = EspIOError;
+}
+
+impl Read for ProxyTunnel {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.stream_mut()
+            .read(buf)
+            .map_err(|_| EspIOError(EspError::from_infallible::<ESP_FAIL>()))
+    }
+}
+
+impl Write for ProxyTunnel {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.stream_mut()
+            .write(buf)
+            .map_err(|_| EspIOError(EspError::from_infallible::<ESP_FAIL>()))
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.stream_mut()
+            .flush()
+            .map_err(|_| EspIOError(EspError::from_infallible::<ESP_FAIL>()))
+    }
+}
+
+#[cfg(all(
+    esp_idf_comp_esp_tls_enabled,
+    any(esp_idf_esp_tls_using_mbedtls, esp_idf_esp_tls_using_wolfssl)
+))]
+impl crate::tls::Socket for ProxyTunnel {
+    fn handle(&self) -> i32 {
+        use std::os::fd::AsRawFd;
+
+        self.stream().as_raw_fd()
+    }
+
+    fn release(&mut self) -> Result<(), EspError> {
+        use std::os::fd::IntoRawFd;
+
+        // The socket is closed by `esp-tls`
+        if let Some(stream) = self.0.take() {
+            stream.into_raw_fd();
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the `host:port` authority of the tunnel, with IPv6 addresses enclosed in brackets
+/// (RFC 3986)
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+fn connect_request(authority: &str, authorization: Option<&str>) -> String {
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+
+    if let Some(authorization) = authorization {
+        request.push_str("Proxy-Authorization: ");
+        request.push_str(authorization);
+        request.push_str("\r\n");
+    }
+
+    request.push_str("\r\n");
+
+    request
+}
+
+fn basic_authorization(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        base64::encode(format!("{username}:{password}").as_bytes())
+    )
+}
+
+/// Answers the first supported of the given `Proxy-Authenticate` challenges, preferring
+/// Digest with SHA-256 over Digest with MD5 over Basic.
+///
+/// Returns `None` if none of the challenges is supported.
+fn authorization_for<'a>(
+    username: &str,
+    password: &str,
+    authority: &str,
+    challenges: impl Iterator<Item = &'a str> + Clone,
+    cnonce: &str,
+) -> Option<String> {
+    let digest = |algorithm: &str| {
+        challenges.clone().find_map(|challenge| {
+            let params = parse_params(strip_scheme(challenge, "Digest")?);
+
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find(|(param, _)| param.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.as_str())
+            };
+
+            if !param("algorithm")
+                .unwrap_or("MD5")
+                .eq_ignore_ascii_case(algorithm)
+            {
+                return None;
+            }
+
+            digest_authorization(
+                algorithm,
+                username,
+                password,
+                authority,
+                param("realm").unwrap_or_default(),
+                param("nonce")?,
+                param("opaque"),
+                param("qop").map(|qop| qop.split(',').any(|qop| qop.trim() == "auth")),
+                cnonce,
+            )
+        })
+    };
+
+    digest("SHA-256").or_else(|| digest("MD5")).or_else(|| {
+        challenges
+            .clone()
+            .any(|challenge| strip_scheme(challenge, "Basic").is_some())
+            .then(|| basic_authorization(username, password))
+    })
+}
+
+/// Computes the Digest `Proxy-Authorization` header for a `CONNECT` request (RFC 7616).
+///
+/// `qop_auth` is `None` if the challenge has no `qop` parameter (RFC 2069), and `Some(false)`
+/// if it does not offer `qop=auth`, which is not supported.
+#[allow(clippy::too_many_arguments)]
+fn digest_authorization(
+    algorithm: &str,
+    username: &str,
+    password: &str,
+    authority: &str,
+    realm: &str,
+    nonce: &str,
+    opaque: Option<&str>,
+    qop_auth: Option<bool>,
+    cnonce: &str,
+) -> Option<String> {
+    let hash = |parts: &[&str]| {
+        let data = parts.concat();
+
+        if algorithm == "SHA-256" {
+            hex::encode(&Sha256::digest(data.as_bytes()))
+        } else {
+            hex::encode(&Md5::digest(data.as_bytes()))
+        }
+    };
+
+    let user_hash = hash(&[username, ":", realm, ":", password]);
+    let request_hash = hash(&["CONNECT:", authority]);
+
+    let mut authorization = format!(
+        "Digest username={}, realm={}, nonce={}, uri={}, algorithm={algorithm}",
+        quote(username),
+        quote(realm),
+        quote(nonce),
+        quote(authority)
+    );
+
+    match qop_auth {
+        Some(true) => {
+            let nc = "00000001";
+            let response = hash(&[
+                &user_hash,
+                ":",
+                nonce,
+                ":",
+                nc,
+                ":",
+                cnonce,
+                ":auth:",
+                &request_hash,
+            ]);
+
+            authorization.push_str(&format!(
+                ", response=\"{response}\", qop=auth, nc={nc}, cnonce={}",
+                quote(cnonce)
+            ));
+        }
+        Some(false) => return None,
+        None => {
+            let response = hash(&[&user_hash, ":", nonce, ":", &request_hash]);
+
+            authorization.push_str(&format!(", response=\"{response}\""));
+        }
+    }
+
+    if let Some(opaque) = opaque {
+        authorization.push_str(&format!(", opaque={}", quote(opaque)));
+    }
+
+    Some(authorization)
+}
+
+/// Returns the values of all headers `name` of an HTTP response header.
+fn response_headers<'a>(response: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + Clone {
+    response
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(move |(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Returns the status code of an HTTP response, e.g. `200` for `HTTP/1.1 200 Connection established`
+fn response_status(response: &str) -> Option<u16> {
+    let status_line = response.split('\r').next()?;
+
+    let mut parts = status_line.split(' ');
+
+    parts
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_response_status() {
+        assert_eq!(
+            response_status("HTTP/1.1 200 Connection established\r\n\r\n"),
+            Some(200)
+        );
+        assert_eq!(
+            response_status("HTTP/1.0 407 Proxy Authentication Required\r\n\r\n"),
+            Some(407)
+        );
+        assert_eq!(response_status("SSH-2.0-OpenSSH\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn parses_response_headers() {
+        let response = "HTTP/1.1 407 Proxy Authentication Required\r\nproxy-authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\nProxy-Authenticate:Digest nonce=\"abc\"\r\n\r\n";
+
+        assert_eq!(
+            response_headers(response, "Proxy-Authenticate").collect::<Vec<_>>(),
+            ["Basic realm=\"proxy\"", "Digest nonce=\"abc\""]
+        );
+        assert_eq!(response_headers(response, "Connection").next(), None);
+    }
+
+    #[test]
+    fn formats_connect_request() {
+        assert_eq!(authority("example.com", 443), "example.com:443");
+        assert_eq!(authority("2001:db8::1", 443), "[2001:db8::1]:443");
+        assert_eq!(authority("[::1]", 8443), "[::1]:8443");
+
+        assert_eq!(
+            connect_request(
+                "example.com:443",
+                Some(&basic_authorization("user", "secret"))
+            ),
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n"
+        );
+        assert_eq!(
+            connect_request("[::1]:8443", None),
+            "CONNECT [::1]:8443 HTTP/1.1\r\nHost: [::1]:8443\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn answers_challenges() {
+        let answer = |challenges: &[&str]| {
+            authorization_for(
+                "user",
+                "secret",
+                "example.com:443",
+                challenges.iter().copied(),
+                "0a1b",
+            )
+        };
+
+        let md5 = "Digest realm=\"proxy\", nonce=\"abc\", qop=\"auth,auth-int\", opaque=\"xyz\"";
+        let sha256 = "Digest realm=\"proxy\", nonce=\"abc\", qop=auth, algorithm=SHA-256";
+
+        assert_eq!(
+            answer(&["Basic realm=\"proxy\"", md5]).as_deref(),
+            Some("Digest username=\"user\", realm=\"proxy\", nonce=\"abc\", uri=\"example.com:443\", algorithm=MD5, response=\"c786f21920f7bb76b0fceb6b8f8c5081\", qop=auth, nc=00000001, cnonce=\"0a1b\", opaque=\"xyz\"")
+        );
+        assert_eq!(
+            answer(&[md5, sha256]).as_deref(),
+            Some("Digest username=\"user\", realm=\"proxy\", nonce=\"abc\", uri=\"example.com:443\", algorithm=SHA-256, response=\"957f85f4a76ea721b6e14174a7b10babefa9403f5a1fc9162eeef7e746151afc\", qop=auth, nc=00000001, cnonce=\"0a1b\"")
+        );
+        assert_eq!(
+            answer(&["Digest realm=\"proxy\", nonce=\"abc\""]).as_deref(),
+            Some("Digest username=\"user\", realm=\"proxy\", nonce=\"abc\", uri=\"example.com:443\", algorithm=MD5, response=\"e447d6ab92b6bff800121678db3821a9\"")
+        );
+        assert_eq!(
+            answer(&[
+                "Digest realm=\"proxy\", nonce=\"abc\", qop=auth-int",
+                "Basic realm=\"proxy\""
+            ])
+            .as_deref(),
+            Some("Basic dXNlcjpzZWNyZXQ=")
+        );
+        assert_eq!(answer(&["Digest realm=\"proxy\", algorithm=MD5"]), None);
+        assert_eq!(answer(&["Negotiate"]), None);
+    }
+}
@@ -0,0 +1,284 @@
+//! The TLS options of `Configuration` which the ESP-IDF HTTP client does not support natively
+//! (ALPN, PSK and public key pinning)
+//!
+//! These are applied to the `mbedtls` configuration of each new connection by a hook
+//! installed as the `crt_bundle_attach` callback - which `esp-tls` calls during the connection
+//! setup - and which takes care of the server verification options of `Configuration` as well,
+//! as `esp-tls` skips them when the callback is set.
+//!
+//! As the callback has no user data argument, the hook of the connecting client is handed over
+//! in `CURRENT`, and connecting is serialized between clients with a hook by `CONNECTING`.
+use core::ffi::{c_int, c_void};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use crate::private::cstr::{c_char, to_cstring_arg};
+use crate::private::hash::{Sha256, SHA256_LEN};
+use crate::private::mutex::{Mutex, MutexGuard};
+#[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+use crate::tls::Psk;
+use crate::tls::X509;
+
+use super::Configuration;
+
+type VerifyCallback =
+    unsafe extern "C" fn(*mut c_void, *mut mbedtls_x509_crt, c_int, *mut u32) -> c_int;
+
+static CONNECTING: Mutex<()> = Mutex::new(());
+static CURRENT: AtomicPtr<TlsHook> = AtomicPtr::new(ptr::null_mut());
+
+pub(crate) struct TlsHook {
+    crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut c_void) -> esp_err_t>,
+    use_global_ca_store: bool,
+    ca_chain: Option<Box<mbedtls_x509_crt>>,
+    _alpn_protos: Vec<CString>,
+    /// Null-terminated, as expected by `mbedtls_ssl_conf_alpn_protocols`
+    alpn_ptrs: Vec<*const c_char>,
+    #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+    psk: Option<Psk<'static>>,
+    pinned_public_keys: &'static [[u8; SHA256_LEN]],
+    /// The verify callback installed by `crt_bundle_attach`
+    verify: Option<(VerifyCallback, *mut c_void)>,
+}
+
+impl TlsHook {
+    /// Returns the hook for `configuration`, if it has any options which need it.
+    pub(crate) fn new(configuration: &Configuration) -> Result<Option<Box<Self>>, EspError> {
+        #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+        let psk = configuration.psk;
+        #[cfg(not(all(esp_idf_esp_tls_psk_verification, feature = "alloc")))]
+        let psk: Option<()> = None;
+
+        let pinned_public_keys = configuration.pinned_public_keys;
+
+        if configuration.alpn_protos.is_none() && psk.is_none() && pinned_public_keys.is_empty() {
+            return Ok(None);
+        }
+
+        let alpn_protos = configuration
+            .alpn_protos
+            .unwrap_or_default()
+            .iter()
+            .map(|proto| to_cstring_arg(proto))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let alpn_ptrs = alpn_protos
+            .iter()
+            .map(|proto| proto.as_ptr())
+            .chain(core::iter::once(ptr::null()))
+            .collect();
+
+        let ca_chain = configuration
+            .server_certificate
+            .map(Self::parse_certificate)
+            .transpose()?;
+
+        Ok(Some(Box::new(Self {
+            crt_bundle_attach: configuration.crt_bundle_attach,
+            use_global_ca_store: configuration.use_global_ca_store,
+            ca_chain,
+            _alpn_protos: alpn_protos,
+            alpn_ptrs,
+            #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+            psk,
+            pinned_public_keys,
+            verify: None,
+        })))
+    }
+
+    /// Makes the hook the one used by `attach`, until the returned guard is dropped.
+    pub(crate) fn activate(&mut self) -> TlsHookGuard {
+        let guard = CONNECTING.lock();
+
+        CURRENT.store(self as *mut _, Ordering::SeqCst);
+
+        TlsHookGuard { _guard: guard }
+    }
+
+    fn parse_certificate(certificate: X509<'static>) -> Result<Box<mbedtls_x509_crt>, EspError> {
+        let mut crt = Box::<mbedtls_x509_crt>::default();
+
+        unsafe {
+            mbedtls_x509_crt_init(&mut *crt);
+
+            let ret = mbedtls_x509_crt_parse(
+                &mut *crt,
+                certificate.data().as_ptr(),
+                certificate.data().len(),
+            );
+
+            if ret != 0 {
+                mbedtls_x509_crt_free(&mut *crt);
+
+                warn!("Parsing the server certificate failed: -0x{:x}", -ret);
+
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+        }
+
+        Ok(crt)
+    }
+
+    /// Returns `true` if the certificate chain of the server is verified, in addition to the pinned keys.
+    fn verifies_chain(&self) -> bool {
+        self.use_global_ca_store || self.crt_bundle_attach.is_some() || self.ca_chain.is_some()
+    }
+}
+
+impl Drop for TlsHook {
+    fn drop(&mut self) {
+        if let Some(crt) = self.ca_chain.as_mut() {
+            unsafe { mbedtls_x509_crt_free(&mut **crt) };
+        }
+    }
+}
+
+pub(crate) struct TlsHookGuard {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Drop for TlsHookGuard {
+    fn drop(&mut self) {
+        CURRENT.store(ptr::null_mut(), Ordering::SeqCst);
+    }
+}
+
+/// Installed as `crt_bundle_attach` of the ESP-IDF HTTP client for clients with a `TlsHook`
+pub(crate) unsafe extern "C" fn attach(conf: *mut c_void) -> esp_err_t {
+    let Some(hook) = CURRENT.load(Ordering::SeqCst).as_mut() else {
+        error!("No TLS hook is active");
+        return ESP_FAIL;
+    };
+
+    let conf = conf as *mut mbedtls_ssl_config;
+
+    // Same precedence as in `esp-tls`
+    if hook.use_global_ca_store {
+        mbedtls_ssl_conf_ca_chain(conf, esp_tls_get_global_ca_store(), ptr::null_mut());
+    } else if let Some(crt_bundle_attach) = hook.crt_bundle_attach {
+        let err = crt_bundle_attach(conf as *mut _);
+        if err != ESP_OK {
+            return err;
+        }
+
+        #[cfg(esp_idf_version_major = "4")]
+        let verify = (*conf).f_vrfy.map(|f_vrfy| (f_vrfy, (*conf).p_vrfy));
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let verify = (*conf)
+            .private_f_vrfy
+            .map(|f_vrfy| (f_vrfy, (*conf).private_p_vrfy));
+
+        hook.verify = verify;
+    } else if let Some(crt) = hook.ca_chain.as_mut() {
+        mbedtls_ssl_conf_ca_chain(conf, &mut **crt, ptr::null_mut());
+    } else if !hook.pinned_public_keys.is_empty() {
+        // No CA chain to verify against (which `MBEDTLS_SSL_VERIFY_REQUIRED` insists on), so
+        // `verify` aborts the handshake with a fatal error for keys which are not pinned
+        mbedtls_ssl_conf_authmode(conf, MBEDTLS_SSL_VERIFY_OPTIONAL as _);
+    }
+
+    if hook.alpn_ptrs.len() > 1
+        && mbedtls_ssl_conf_alpn_protocols(conf, hook.alpn_ptrs.as_mut_ptr()) != 0
+    {
+        return ESP_ERR_INVALID_ARG;
+    }
+
+    #[cfg(all(esp_idf_esp_tls_psk_verification, feature = "alloc"))]
+    if let Some(psk) = hook.psk {
+        if mbedtls_ssl_conf_psk(
+            conf,
+            psk.key.as_ptr(),
+            psk.key.len(),
+            psk.hint.as_ptr(),
+            psk.hint.len(),
+        ) != 0
+        {
+            return ESP_ERR_INVALID_ARG;
+        }
+    }
+
+    mbedtls_ssl_conf_verify(conf, Some(verify), hook as *mut TlsHook as *mut _);
+
+    ESP_OK
+}
+
+/// Checks the public key of the server against the pinned keys, after the verification of
+/// the certificate chain (if any)
+unsafe extern "C" fn verify(
+    ctx: *mut c_void,
+    crt: *mut mbedtls_x509_crt,
+    depth: c_int,
+    flags: *mut u32,
+) -> c_int {
+    let hook = (ctx as *const TlsHook).as_ref().unwrap();
+
+    if let Some((verify, ctx)) = hook.verify {
+        let ret = verify(ctx, crt, depth, flags);
+        if ret != 0 {
+            return ret;
+        }
+    }
+
+    if !hook.verifies_chain() && !hook.pinned_public_keys.is_empty() {
+        // Only the pinned keys are trusted, the chain itself is not verified
+        *flags = 0;
+    }
+
+    if depth == 0 && !hook.pinned_public_keys.is_empty() {
+        let public_key = core::slice::from_raw_parts((*crt).pk_raw.p, (*crt).pk_raw.len);
+
+        return verify_pinned(hook.pinned_public_keys, public_key, &mut *flags);
+    }
+
+    0
+}
+
+/// Checks the public key of the server certificate against the pinned keys
+///
+/// A mismatch is reported with `MBEDTLS_ERR_X509_FATAL_ERROR`, as mbedTLS ignores
+/// `MBEDTLS_ERR_X509_CERT_VERIFY_FAILED` with `MBEDTLS_SSL_VERIFY_OPTIONAL`.
+fn verify_pinned(
+    pinned_public_keys: &[[u8; SHA256_LEN]],
+    public_key: &[u8],
+    flags: &mut u32,
+) -> c_int {
+    if pinned_public_keys.contains(&Sha256::digest(public_key)) {
+        return 0;
+    }
+
+    warn!("The public key of the server is not pinned");
+
+    *flags |= MBEDTLS_X509_BADCERT_NOT_TRUSTED;
+
+    MBEDTLS_ERR_X509_FATAL_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_keys_which_are_not_pinned() {
+        let pinned = [Sha256::digest(b"pinned key")];
+
+        let mut flags = 0;
+        assert_eq!(verify_pinned(&pinned, b"pinned key", &mut flags), 0);
+        assert_eq!(flags, 0);
+
+        let mut flags = 0;
+        assert_eq!(
+            verify_pinned(&pinned, b"other key", &mut flags),
+            MBEDTLS_ERR_X509_FATAL_ERROR
+        );
+        assert_eq!(flags, MBEDTLS_X509_BADCERT_NOT_TRUSTED);
+    }
+}
@@ -74,6 +74,18 @@ pub use embedded_svc::utils::http::server::registration::*;
 
 pub use super::*;
 
+pub mod auth;
+pub mod cors;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod multipart;
+pub mod router;
+pub mod security_headers;
+pub mod session;
+pub mod sse;
+pub mod static_files;
+pub mod urlencoded;
+
 #[derive(Copy, Clone, Debug)]
 pub struct Configuration {
     pub http_port: u16,
@@ -270,6 +282,45 @@ impl From<Method> for Newtype<ffi::c_uint> {
     }
 }
 
+/// Returns the name of `method` as it appears in requests, e.g. `M-SEARCH` for `Method::MSearch`
+pub(crate) fn method_str(method: Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Post => "POST",
+        Method::Delete => "DELETE",
+        Method::Head => "HEAD",
+        Method::Put => "PUT",
+        Method::Connect => "CONNECT",
+        Method::Options => "OPTIONS",
+        Method::Trace => "TRACE",
+        Method::Copy => "COPY",
+        Method::Lock => "LOCK",
+        Method::MkCol => "MKCOL",
+        Method::Move => "MOVE",
+        Method::Propfind => "PROPFIND",
+        Method::Proppatch => "PROPPATCH",
+        Method::Search => "SEARCH",
+        Method::Unlock => "UNLOCK",
+        Method::Bind => "BIND",
+        Method::Rebind => "REBIND",
+        Method::Unbind => "UNBIND",
+        Method::Acl => "ACL",
+        Method::Report => "REPORT",
+        Method::MkActivity => "MKACTIVITY",
+        Method::Checkout => "CHECKOUT",
+        Method::Merge => "MERGE",
+        Method::MSearch => "M-SEARCH",
+        Method::Notify => "NOTIFY",
+        Method::Subscribe => "SUBSCRIBE",
+        Method::Unsubscribe => "UNSUBSCRIBE",
+        Method::Patch => "PATCH",
+        Method::Purge => "PURGE",
+        Method::MkCalendar => "MKCALENDAR",
+        Method::Link => "LINK",
+        Method::Unlink => "UNLINK",
+    }
+}
+
 static OPEN_SESSIONS: Mutex<BTreeMap<(u32, ffi::c_int), Arc<AtomicBool>>> =
     Mutex::new(BTreeMap::new());
 static CLOSE_HANDLERS: Mutex<BTreeMap<u32, Vec<CloseHandler<'static>>>> =
@@ -658,6 +709,9 @@ pub struct EspHttpConnection<'a> {
     request: EspHttpRawConnection<'a>,
     headers: Option<UnsafeCell<EspHttpHeaders>>,
     response_headers: Option<Vec<CString>>,
+    extra_response_headers: Vec<(String, String)>,
+    detached: bool,
+    session_id: Option<String>,
 }
 
 /// Represents the two-way connection between an HTTP request and its response.
@@ -667,6 +721,9 @@ impl<'a> EspHttpConnection<'a> {
             request: EspHttpRawConnection(raw_req),
             headers: Some(UnsafeCell::new(EspHttpHeaders::new())),
             response_headers: None,
+            extra_response_headers: Vec::new(),
+            detached: false,
+            session_id: None,
         }
     }
 
@@ -747,6 +804,20 @@ impl<'a> EspHttpConnection<'a> {
         (headers, self)
     }
 
+    /// Adds a header to the response, in addition to the headers passed to
+    /// `initiate_response` - unless a header with the same name is passed there.
+    /// `Vary` headers are the exception: their values are merged with the ones passed
+    /// to `initiate_response`.
+    ///
+    /// This allows middlewares to add headers (e.g. CORS headers) to the responses
+    /// of the handlers they wrap.
+    pub fn add_response_header(&mut self, name: &str, value: &str) {
+        self.assert_request();
+
+        self.extra_response_headers
+            .push((name.to_owned(), value.to_owned()));
+    }
+
     /// Sends the HTTP status (e.g. "200 OK") and the response headers to the
     /// HTTP client.
     pub fn initiate_response(
@@ -770,6 +841,48 @@ impl<'a> EspHttpConnection<'a> {
 
         c_headers.push(c_status);
 
+        let extra_headers = core::mem::take(&mut self.extra_response_headers);
+
+        let is_vary = |name: &str| name.eq_ignore_ascii_case("Vary");
+
+        // Each `Vary` header lists request headers the response depends on, so all are kept
+        let vary = headers
+            .iter()
+            .copied()
+            .chain(
+                extra_headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            )
+            .filter(|(name, _)| is_vary(name))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .fold(Vec::new(), |mut vary: Vec<&str>, value| {
+                if !vary.iter().any(|known| known.eq_ignore_ascii_case(value)) {
+                    vary.push(value);
+                }
+
+                vary
+            })
+            .join(", ");
+
+        let headers = headers
+            .iter()
+            .copied()
+            .chain(
+                extra_headers
+                    .iter()
+                    .filter(|(name, _)| {
+                        !headers
+                            .iter()
+                            .any(|(key, _)| key.eq_ignore_ascii_case(name))
+                    })
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            )
+            .filter(|(name, _)| !is_vary(name))
+            .chain((!vary.is_empty()).then_some(("Vary", vary.as_str())));
+
         for (key, value) in headers {
             if key.eq_ignore_ascii_case("Content-Type") {
                 let c_type = to_cstring_arg(value)?;
@@ -878,6 +991,11 @@ impl<'a> EspHttpConnection<'a> {
     }
 
     fn complete(&mut self) -> Result<(), EspError> {
+        if self.detached {
+            // The response is sent outside of the handler (e.g. an SSE stream)
+            return Ok(());
+        }
+
         let buf = &[];
 
         if self.response_headers.is_some() {
@@ -1035,16 +1153,22 @@ pub mod ws {
     use core::ffi;
     use core::fmt::Debug;
     use core::sync::atomic::{AtomicBool, Ordering};
+    use core::task::{Poll, Waker};
 
     extern crate alloc;
     use alloc::boxed::Box;
+    use alloc::collections::VecDeque;
     use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
 
     use ::log::*;
 
     use embedded_svc::http::Method;
     use embedded_svc::ws::*;
 
+    use esp_idf_hal::task::asynch::Notification;
+
     use crate::sys::*;
 
     use crate::private::common::Newtype;
@@ -1056,6 +1180,8 @@ pub mod ws {
     use super::OPEN_SESSIONS;
     use super::{CloseHandler, NativeHandler};
 
+    pub mod registry;
+
     /// A Websocket connection between this server and a client.
     pub enum EspHttpWsConnection {
         New(httpd_handle_t, *mut httpd_req_t),
@@ -1444,379 +1570,442 @@ pub mod ws {
         }
     }
 
-    // TODO: Consider if it makes sense at all to put a complex async layer on top of the ESP-IDF WS API,
-    // which is very far from being async
-    // TODO: Port all of the code below to `zerocopy`, thus simplifying it and providing blocking
-    // sender/receiver/acceptor implementations as well
-
-    // enum ReceiverData {
-    //     None,
-    //     Metadata((FrameType, usize)),
-    //     Data(*mut u8),
-    //     DataCopied,
-    //     Closed,
-    // }
-
-    // unsafe impl Send for ReceiverData {}
-
-    // struct SharedReceiverState {
-    //     waker: Option<Waker>,
-    //     data: ReceiverData,
-    // }
-
-    // struct ConnectionState {
-    //     session: ffi::c_int,
-    //     receiver_state: Arc<Mutex<SharedReceiverState>>,
-    // }
-
-    // pub struct SharedAcceptorState {
-    //     waker: Option<Waker>,
-    //     data: Option<Option<(Arc<Mutex<SharedReceiverState>>, EspHttpWsDetachedSender)>>,
-    // }
-
-    // pub struct EspHttpWsAsyncSender<U> {
-    //     unblocker: U,
-    //     sender: EspHttpWsDetachedSender,
-    // }
-
-    // impl<U> EspHttpWsAsyncSender<U>
-    // where
-    //     U: Unblocker,
-    // {
-    //     pub async fn send(
-    //         &mut self,
-    //         frame_type: FrameType,
-    //         frame_data: &[u8],
-    //     ) -> Result<(), EspError> {
-    //         #[cfg(not(feature = "std"))]
-    //         use alloc::borrow::ToOwned;
-
-    //         debug!(
-    //             "Sending data (frame_type={:?}, frame_len={}) to WS connection {:?}",
-    //             frame_type,
-    //             frame_data.len(),
-    //             self.sender.session()
-    //         );
-
-    //         let mut sender = self.sender.clone();
-    //         let frame_data: alloc::vec::Vec<u8> = frame_data.to_owned();
-
-    //         self.unblocker
-    //             .unblock(move || sender.send(frame_type, &frame_data))
-    //             .await
-    //     }
-    // }
-
-    // impl<U> ErrorType for EspHttpWsAsyncSender<U> {
-    //     type Error = EspError;
-    // }
-
-    // impl<U> asynch::Sender for EspHttpWsAsyncSender<U>
-    // where
-    //     U: Unblocker,
-    // {
-    //     async fn send(
-    //         &mut self,
-    //         frame_type: FrameType,
-    //         frame_data: &[u8],
-    //     ) -> Result<(), Self::Error> {
-    //         EspHttpWsAsyncSender::send(self, frame_type, frame_data).await
-    //     }
-    // }
-
-    // pub struct EspHttpWsAsyncReceiver {
-    //     shared: Arc<Mutex<SharedReceiverState>>,
-    //     condvar: Arc<Condvar>,
-    // }
-
-    // impl EspHttpWsAsyncReceiver {
-    //     pub async fn recv(
-    //         &mut self,
-    //         frame_data_buf: &mut [u8],
-    //     ) -> Result<(FrameType, usize), EspError> {
-    //         AsyncReceiverFuture {
-    //             receiver: self,
-    //             frame_data_buf,
-    //         }
-    //         .await
-    //     }
-    // }
-
-    // struct AsyncReceiverFuture<'a> {
-    //     receiver: &'a mut EspHttpWsAsyncReceiver,
-    //     frame_data_buf: &'a mut [u8],
-    // }
-
-    // impl<'a> Future for AsyncReceiverFuture<'a> {
-    //     type Output = Result<(FrameType, usize), EspError>;
-
-    //     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-    //         let frame_data_buf_ptr = self.frame_data_buf.as_mut_ptr();
-    //         let mut shared = self.receiver.shared.lock();
-
-    //         if let ReceiverData::Metadata((frame_type, size)) = shared.data {
-    //             if self.frame_data_buf.len() >= size {
-    //                 shared.data = ReceiverData::Data(frame_data_buf_ptr);
-
-    //                 self.receiver.condvar.notify_all();
-
-    //                 while !matches!(shared.data, ReceiverData::DataCopied) {
-    //                     shared = self.receiver.condvar.wait(shared);
-    //                 }
-
-    //                 shared.data = ReceiverData::None;
-    //                 self.receiver.condvar.notify_all();
-    //             }
-
-    //             Poll::Ready(Ok((frame_type, size)))
-    //         } else if let ReceiverData::Closed = shared.data {
-    //             Poll::Ready(Ok((FrameType::Close, 0)))
-    //         } else {
-    //             shared.waker = Some(cx.waker().clone());
-    //             Poll::Pending
-    //         }
-    //     }
-    // }
-
-    // impl ErrorType for EspHttpWsAsyncReceiver {
-    //     type Error = EspError;
-    // }
-
-    // impl asynch::Receiver for EspHttpWsAsyncReceiver {
-    //     async fn recv(
-    //         &mut self,
-    //         frame_data_buf: &mut [u8],
-    //     ) -> Result<(FrameType, usize), Self::Error> {
-    //         EspHttpWsAsyncReceiver::recv(self, frame_data_buf).await
-    //     }
-    // }
-
-    // pub struct EspHttpWsAsyncAcceptor<U> {
-    //     unblocker: U,
-    //     accept: Arc<Mutex<SharedAcceptorState>>,
-    //     condvar: Arc<Condvar>,
-    // }
-
-    // impl<U> EspHttpWsAsyncAcceptor<U> {
-    //     pub fn accept(&self) -> &EspHttpWsAsyncAcceptor<U> {
-    //         self
-    //     }
-    // }
-
-    // impl<'a, U> Future for &'a EspHttpWsAsyncAcceptor<U>
-    // where
-    //     U: Clone,
-    // {
-    //     type Output = Result<(EspHttpWsAsyncSender<U>, EspHttpWsAsyncReceiver), EspError>;
-
-    //     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-    //         let mut accept = self.accept.lock();
-
-    //         match accept.data.take() {
-    //             Some(Some((shared, sender))) => {
-    //                 let sender = EspHttpWsAsyncSender {
-    //                     unblocker: self.unblocker.clone(),
-    //                     sender,
-    //                 };
-
-    //                 let receiver = EspHttpWsAsyncReceiver {
-    //                     shared,
-    //                     condvar: self.condvar.clone(),
-    //                 };
-
-    //                 self.condvar.notify_all();
-
-    //                 Poll::Ready(Ok((sender, receiver)))
-    //             }
-    //             Some(None) => {
-    //                 accept.data = Some(None);
-    //                 Poll::Pending
-    //             }
-    //             None => {
-    //                 accept.waker = Some(cx.waker().clone());
-    //                 Poll::Pending
-    //             }
-    //         }
-    //     }
-    // }
-
-    // impl<U> ErrorType for EspHttpWsAsyncAcceptor<U> {
-    //     type Error = EspError;
-    // }
-
-    // impl<U> asynch::server::Acceptor for EspHttpWsAsyncAcceptor<U>
-    // where
-    //     U: Unblocker + Clone + Send,
-    // {
-    //     type Sender<'a> = EspHttpWsAsyncSender<U> where U: 'a;
-    //     type Receiver<'a> = EspHttpWsAsyncReceiver where U: 'a;
-
-    //     async fn accept(&self) -> Result<(Self::Sender<'_>, Self::Receiver<'_>), Self::Error> {
-    //         self.await
-    //     }
-    // }
-
-    // #[allow(clippy::type_complexity)]
-    // pub struct EspHttpWsProcessor<const N: usize> {
-    //     connections: alloc::vec::Vec<ConnectionState>,
-    //     frame_data_buf: [u8; N],
-    //     accept: Arc<Mutex<SharedAcceptorState>>,
-    //     condvar: Arc<Condvar>,
-    // }
-
-    // impl<const N: usize> EspHttpWsProcessor<N> {
-    //     pub fn new<U>(unblocker: U) -> (Self, EspHttpWsAsyncAcceptor<U>) {
-    //         let this = Self {
-    //             connections: alloc::vec::Vec::new(),
-    //             frame_data_buf: [0_u8; N],
-    //             accept: Arc::new(Mutex::new(SharedAcceptorState {
-    //                 waker: None,
-    //                 data: None,
-    //             })),
-    //             condvar: Arc::new(Condvar::new()),
-    //         };
-
-    //         let acceptor = EspHttpWsAsyncAcceptor {
-    //             unblocker,
-    //             accept: this.accept.clone(),
-    //             condvar: this.condvar.clone(),
-    //         };
-
-    //         (this, acceptor)
-    //     }
-
-    //     pub fn process(&mut self, connection: &mut EspHttpWsConnection) -> Result<(), EspError> {
-    //         if connection.is_new() {
-    //             let session = connection.session();
-
-    //             info!("New WS connection {:?}", session);
-
-    //             self.process_accept(session, connection)?;
-    //         } else if connection.is_closed() {
-    //             let session = connection.session();
-
-    //             if let Some(index) = self
-    //                 .connections
-    //                 .iter()
-    //                 .enumerate()
-    //                 .find_map(|(index, conn)| (conn.session == session).then_some(index))
-    //             {
-    //                 let conn = self.connections.swap_remove(index);
-
-    //                 Self::process_receive_close(&conn.receiver_state);
-    //                 info!("Closed WS connection {:?}", session);
-    //             }
-    //         } else {
-    //             let session = connection.session();
-    //             let (frame_type, len) = connection.recv(&mut self.frame_data_buf)?;
-
-    //             debug!(
-    //                 "Incoming data (frame_type={:?}, frame_len={}) from WS connection {:?}",
-    //                 frame_type, len, session
-    //             );
-
-    //             if let Some(connection) = self
-    //                 .connections
-    //                 .iter()
-    //                 .find(|connection| connection.session == session)
-    //             {
-    //                 self.process_receive(&connection.receiver_state, frame_type, len)
-    //             }
-    //         }
-
-    //         Ok(())
-    //     }
-
-    //     fn process_accept(
-    //         &mut self,
-    //         session: ffi::c_int,
-    //         sender: &EspHttpWsConnection,
-    //     ) -> Result<(), EspError> {
-    //         let receiver_state = Arc::new(Mutex::new(SharedReceiverState {
-    //             waker: None,
-    //             data: ReceiverData::None,
-    //         }));
-
-    //         let state = ConnectionState {
-    //             session,
-    //             receiver_state: receiver_state.clone(),
-    //         };
-
-    //         self.connections.push(state);
-
-    //         let sender = sender.create_detached_sender()?;
-
-    //         let mut accept = self.accept.lock();
-
-    //         accept.data = Some(Some((receiver_state, sender)));
-
-    //         if let Some(waker) = accept.waker.take() {
-    //             waker.wake();
-    //         }
-
-    //         while accept.data.is_some() {
-    //             accept = self.condvar.wait(accept);
-    //         }
-
-    //         Ok(())
-    //     }
-
-    //     fn process_receive(
-    //         &self,
-    //         state: &Mutex<SharedReceiverState>,
-    //         frame_type: FrameType,
-    //         len: usize,
-    //     ) {
-    //         let mut shared = state.lock();
-
-    //         shared.data = ReceiverData::Metadata((frame_type, len));
-
-    //         if let Some(waker) = shared.waker.take() {
-    //             waker.wake();
-    //         }
-
-    //         loop {
-    //             if let ReceiverData::Data(buf) = &shared.data {
-    //                 unsafe { slice::from_raw_parts_mut(*buf, len) }
-    //                     .copy_from_slice(&self.frame_data_buf[..len]);
-    //                 shared.data = ReceiverData::DataCopied;
-    //                 self.condvar.notify_all();
-
-    //                 break;
-    //             }
-
-    //             shared = self.condvar.wait(shared);
-    //         }
-
-    //         while !matches!(shared.data, ReceiverData::None) {
-    //             shared = self.condvar.wait(shared);
-    //         }
-    //     }
-
-    //     fn process_accept_close(&mut self) {
-    //         let mut accept = self.accept.lock();
-
-    //         accept.data = Some(None);
-
-    //         if let Some(waker) = accept.waker.take() {
-    //             waker.wake();
-    //         }
-    //     }
-
-    //     fn process_receive_close(state: &Mutex<SharedReceiverState>) {
-    //         let mut shared = state.lock();
-
-    //         shared.data = ReceiverData::Closed;
-
-    //         if let Some(waker) = shared.waker.take() {
-    //             waker.wake();
-    //         }
-    //     }
-    // }
-
-    // impl<const N: usize> Drop for EspHttpWsProcessor<N> {
-    //     fn drop(&mut self) {
-    //         self.process_accept_close();
-    //     }
-    // }
+    struct SessionState {
+        frames: VecDeque<(FrameType, Vec<u8>)>,
+        closed: bool,
+        receiver_dropped: bool,
+    }
+
+    struct Session {
+        state: Mutex<SessionState>,
+        notification: Notification,
+    }
+
+    impl Session {
+        fn close(&self) {
+            self.state.lock().closed = true;
+            self.notification.notify_lsb();
+        }
+    }
+
+    struct AcceptorState {
+        pending: VecDeque<(Arc<Session>, EspHttpWsDetachedSender)>,
+        closed: bool,
+        /// The wakers of all pending `accept` calls, as `accept` might be called concurrently
+        wakers: Vec<Waker>,
+    }
+
+    struct SharedAcceptor {
+        state: Mutex<AcceptorState>,
+    }
+
+    impl SharedAcceptor {
+        fn update(&self, f: impl FnOnce(&mut AcceptorState)) {
+            let wakers = {
+                let mut state = self.state.lock();
+
+                f(&mut state);
+
+                core::mem::take(&mut state.wakers)
+            };
+
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Dispatches the Websockets connections of a `ws_handler` to an `EspHttpWsAsyncAcceptor`,
+    /// so that they can be served from an async executor rather than from the HTTP server task.
+    ///
+    /// ```
+    /// use esp_idf_svc::http::server::ws::EspHttpWsProcessor;
+    ///
+    /// let (processor, acceptor) = EspHttpWsProcessor::new(1024, 8);
+    ///
+    /// server.ws_handler("/ws", move |connection| processor.process(connection))?;
+    ///
+    /// executor.spawn(async move {
+    ///     loop {
+    ///         let (sender, receiver) = acceptor.accept().await?;
+    ///
+    ///         executor.spawn(echo(sender, receiver));
+    ///     }
+    /// });
+    /// ```
+    ///
+    /// Received frames are queued per connection, so the HTTP server task never waits
+    /// for the async side. Connections sending frames longer than `max_frame_len`, or
+    /// more than `max_queued_frames` frames which have not been received yet, are closed.
+    pub struct EspHttpWsProcessor {
+        sessions: Mutex<Vec<(ffi::c_int, Arc<Session>)>>,
+        acceptor: Arc<SharedAcceptor>,
+        max_frame_len: usize,
+        max_queued_frames: usize,
+    }
+
+    impl EspHttpWsProcessor {
+        pub fn new(
+            max_frame_len: usize,
+            max_queued_frames: usize,
+        ) -> (Self, EspHttpWsAsyncAcceptor) {
+            let acceptor = Arc::new(SharedAcceptor {
+                state: Mutex::new(AcceptorState {
+                    pending: VecDeque::new(),
+                    closed: false,
+                    wakers: Vec::new(),
+                }),
+            });
+
+            let this = Self {
+                sessions: Mutex::new(Vec::new()),
+                acceptor: acceptor.clone(),
+                max_frame_len,
+                max_queued_frames,
+            };
+
+            (this, EspHttpWsAsyncAcceptor { shared: acceptor })
+        }
+
+        /// Processes an event of a Websockets connection; to be called from the `ws_handler`.
+        pub fn process(&self, connection: &mut EspHttpWsConnection) -> Result<(), EspError> {
+            let session = connection.session();
+
+            if connection.is_new() {
+                let sender = connection.create_detached_sender()?;
+
+                let state = Arc::new(Session {
+                    state: Mutex::new(SessionState {
+                        frames: VecDeque::new(),
+                        closed: false,
+                        receiver_dropped: false,
+                    }),
+                    notification: Notification::new(),
+                });
+
+                self.sessions.lock().push((session, state.clone()));
+
+                self.acceptor
+                    .update(|acceptor| acceptor.pending.push_back((state, sender)));
+
+                info!("New WS connection {:?}", session);
+            } else if connection.is_closed() {
+                let mut sessions = self.sessions.lock();
+
+                if let Some(index) = sessions.iter().position(|(fd, _)| *fd == session) {
+                    let (_, state) = sessions.swap_remove(index);
+
+                    state.close();
+
+                    info!("Closed WS connection {:?}", session);
+                }
+            } else {
+                let Some(state) = self
+                    .sessions
+                    .lock()
+                    .iter()
+                    .find(|(fd, _)| *fd == session)
+                    .map(|(_, state)| state.clone())
+                else {
+                    return Ok(());
+                };
+
+                let (frame_type, len) = connection.recv(&mut [])?;
+
+                if len > self.max_frame_len {
+                    warn!(
+                        "Closing WS connection {:?}: frame of {} bytes exceeds the maximum of {} bytes",
+                        session, len, self.max_frame_len
+                    );
+
+                    Self::trigger_close(connection);
+
+                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+                }
+
+                let mut frame_data = vec![0; len];
+
+                if len > 0 {
+                    connection.recv(&mut frame_data)?;
+                }
+
+                debug!(
+                    "Incoming data (frame_type={:?}, frame_len={}) from WS connection {:?}",
+                    frame_type, len, session
+                );
+
+                let mut shared = state.state.lock();
+
+                if shared.receiver_dropped {
+                    return Ok(());
+                }
+
+                if shared.frames.len() >= self.max_queued_frames {
+                    drop(shared);
+
+                    warn!(
+                        "Closing WS connection {:?}: more than {} frames queued",
+                        session, self.max_queued_frames
+                    );
+
+                    Self::trigger_close(connection);
+
+                    return Err(EspError::from_infallible::<ESP_FAIL>());
+                }
+
+                shared.frames.push_back((frame_type, frame_data));
+                drop(shared);
+
+                state.notification.notify_lsb();
+            }
+
+            Ok(())
+        }
+
+        fn trigger_close(connection: &EspHttpWsConnection) {
+            if let EspHttpWsConnection::Receiving(sd, raw_req, _) = connection {
+                unsafe { httpd_sess_trigger_close(*sd, httpd_req_to_sockfd(*raw_req)) };
+            }
+        }
+    }
+
+    impl Drop for EspHttpWsProcessor {
+        fn drop(&mut self) {
+            self.acceptor.update(|acceptor| acceptor.closed = true);
+
+            for (_, state) in self.sessions.lock().drain(..) {
+                state.close();
+            }
+        }
+    }
+
+    /// Yields the sender and receiver of each new Websockets connection, see `EspHttpWsProcessor`
+    pub struct EspHttpWsAsyncAcceptor {
+        shared: Arc<SharedAcceptor>,
+    }
+
+    impl EspHttpWsAsyncAcceptor {
+        /// Waits for a new connection.
+        ///
+        /// Fails with `ESP_ERR_INVALID_STATE` once the `EspHttpWsProcessor` is dropped.
+        ///
+        /// Can be called concurrently, e.g. from several tasks serving connections; each new
+        /// connection is then yielded to one of the callers.
+        pub async fn accept(
+            &self,
+        ) -> Result<(EspHttpWsAsyncSender, EspHttpWsAsyncReceiver), EspError> {
+            core::future::poll_fn(|ctx| {
+                let mut state = self.shared.state.lock();
+
+                if let Some((session, sender)) = state.pending.pop_front() {
+                    return Poll::Ready(Ok((
+                        EspHttpWsAsyncSender { sender },
+                        EspHttpWsAsyncReceiver { session },
+                    )));
+                }
+
+                if state.closed {
+                    return Poll::Ready(Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()));
+                }
+
+                if !state
+                    .wakers
+                    .iter()
+                    .any(|waker| waker.will_wake(ctx.waker()))
+                {
+                    state.wakers.push(ctx.waker().clone());
+                }
+
+                Poll::Pending
+            })
+            .await
+        }
+    }
+
+    impl ErrorType for EspHttpWsAsyncAcceptor {
+        type Error = EspError;
+    }
+
+    impl asynch::server::Acceptor for EspHttpWsAsyncAcceptor {
+        type Sender<'a> = EspHttpWsAsyncSender where Self: 'a;
+        type Receiver<'a> = EspHttpWsAsyncReceiver where Self: 'a;
+
+        async fn accept(&self) -> Result<(Self::Sender<'_>, Self::Receiver<'_>), Self::Error> {
+            EspHttpWsAsyncAcceptor::accept(self).await
+        }
+    }
+
+    struct AsyncSendRequest {
+        sender: EspHttpWsDetachedSender,
+        frame_type: FrameType,
+        frame_data: Vec<u8>,
+        error_code: Mutex<Option<esp_err_t>>,
+        notification: Notification,
+    }
+
+    unsafe impl Send for AsyncSendRequest {}
+    unsafe impl Sync for AsyncSendRequest {}
+
+    /// Sends frames to a client without blocking
+    ///
+    /// The frame is copied and handed over to the HTTP server task, and the returned future
+    /// completes once it has been sent.
+    #[derive(Clone)]
+    pub struct EspHttpWsAsyncSender {
+        sender: EspHttpWsDetachedSender,
+    }
+
+    impl EspHttpWsAsyncSender {
+        pub fn session(&self) -> i32 {
+            self.sender.session()
+        }
+
+        pub fn is_closed(&self) -> bool {
+            self.sender.is_closed()
+        }
+
+        pub async fn send(
+            &mut self,
+            frame_type: FrameType,
+            frame_data: &[u8],
+        ) -> Result<(), EspError> {
+            if self.is_closed() {
+                return Err(EspError::from_infallible::<ESP_FAIL>());
+            }
+
+            debug!(
+                "Sending data (frame_type={:?}, frame_len={}) to WS connection {:?}",
+                frame_type,
+                frame_data.len(),
+                self.session()
+            );
+
+            let request = Arc::new(AsyncSendRequest {
+                sender: self.sender.clone(),
+                frame_type,
+                frame_data: frame_data.to_vec(),
+                error_code: Mutex::new(None),
+                notification: Notification::new(),
+            });
+
+            // The HTTP server task owns a reference until it has sent the frame,
+            // so that the request outlives this future even if it is dropped
+            let arg = Arc::into_raw(request.clone());
+
+            if let Err(err) = esp!(unsafe {
+                httpd_queue_work(self.sender.sd, Some(Self::enqueue), arg as *mut _)
+            }) {
+                drop(unsafe { Arc::from_raw(arg) });
+
+                return Err(err);
+            }
+
+            loop {
+                if let Some(error_code) = *request.error_code.lock() {
+                    return esp!(error_code);
+                }
+
+                request.notification.wait().await;
+            }
+        }
+
+        extern "C" fn enqueue(arg: *mut ffi::c_void) {
+            let request = unsafe { Arc::from_raw(arg as *const AsyncSendRequest) };
+
+            let ret = if !request.sender.is_closed() {
+                let raw_frame =
+                    EspHttpWsConnection::create_raw_frame(request.frame_type, &request.frame_data);
+
+                unsafe {
+                    httpd_ws_send_frame_async(
+                        request.sender.sd,
+                        request.sender.fd,
+                        &raw_frame as *const _ as *mut _,
+                    )
+                }
+            } else {
+                ESP_FAIL
+            };
+
+            *request.error_code.lock() = Some(ret);
+
+            request.notification.notify_lsb();
+        }
+    }
+
+    impl ErrorType for EspHttpWsAsyncSender {
+        type Error = EspError;
+    }
+
+    impl asynch::Sender for EspHttpWsAsyncSender {
+        async fn send(
+            &mut self,
+            frame_type: FrameType,
+            frame_data: &[u8],
+        ) -> Result<(), Self::Error> {
+            EspHttpWsAsyncSender::send(self, frame_type, frame_data).await
+        }
+    }
+
+    /// Receives the frames queued for a client by the `EspHttpWsProcessor`
+    pub struct EspHttpWsAsyncReceiver {
+        session: Arc<Session>,
+    }
+
+    impl EspHttpWsAsyncReceiver {
+        /// Receives a frame, with the same semantics as `EspHttpWsConnection::recv`:
+        /// if `frame_data_buf` is too small, the frame is not consumed, and only its type
+        /// and length are returned.
+        ///
+        /// Returns `FrameType::SocketClose` once the connection is closed.
+        pub async fn recv(
+            &mut self,
+            frame_data_buf: &mut [u8],
+        ) -> Result<(FrameType, usize), EspError> {
+            loop {
+                {
+                    let mut state = self.session.state.lock();
+
+                    if let Some((frame_type, frame_data)) = state.frames.front() {
+                        let (frame_type, len) = (*frame_type, frame_data.len());
+
+                        if frame_data_buf.len() >= len {
+                            frame_data_buf[..len].copy_from_slice(frame_data);
+                            state.frames.pop_front();
+                        }
+
+                        return Ok((frame_type, len));
+                    }
+
+                    if state.closed {
+                        return Ok((FrameType::SocketClose, 0));
+                    }
+                }
+
+                self.session.notification.wait().await;
+            }
+        }
+    }
+
+    impl Drop for EspHttpWsAsyncReceiver {
+        fn drop(&mut self) {
+            let mut state = self.session.state.lock();
+
+            state.receiver_dropped = true;
+            state.frames.clear();
+        }
+    }
+
+    impl ErrorType for EspHttpWsAsyncReceiver {
+        type Error = EspError;
+    }
+
+    impl asynch::Receiver for EspHttpWsAsyncReceiver {
+        async fn recv(
+            &mut self,
+            frame_data_buf: &mut [u8],
+        ) -> Result<(FrameType, usize), Self::Error> {
+            EspHttpWsAsyncReceiver::recv(self, frame_data_buf).await
+        }
+    }
 }
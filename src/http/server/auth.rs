@@ -0,0 +1,535 @@
+//! Authentication middlewares
+//!
+//! `BasicAuth`, `DigestAuth` (RFC 7616) and `BearerAuth` are `Middleware`s which only pass
+//! requests with valid credentials on to the wrapped handler. All other requests are answered with
+//! `401 Unauthorized` and a `WWW-Authenticate` challenge, so that browsers prompt for a login.
+//!
+//! User names and passwords are checked with a pluggable `CredentialVerifier` (Basic) or
+//! `DigestCredentials` (Digest) - both are implemented by `Users`, a fixed set of users, and
+//! `CredentialVerifier` is also implemented by closures.
+//!
+//! ```
+//! use esp_idf_svc::http::server::auth::{DigestAuth, Users};
+//! use esp_idf_svc::http::server::{fn_handler, Middleware};
+//! use esp_idf_svc::http::Method;
+//!
+//! let users = Users::new().user("admin", "secret");
+//!
+//! server.handler(
+//!     "/config",
+//!     Method::Get,
+//!     DigestAuth::new("device", users).compose(fn_handler(|request| {
+//!         request.into_ok_response()?.write_all(b"Settings")
+//!     })),
+//! )?;
+//! ```
+//!
+//! Note that Basic authentication and bearer tokens send the credentials in clear text, so they
+//! should only be used with HTTPS. Digest authentication does not reveal the password, but - as
+//! its nonces are not tracked - does not prevent replaying a request within the nonce lifetime.
+use core::fmt::Debug;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use crate::private::auth::{parse_params, quote, strip_scheme};
+use crate::private::base64;
+use crate::private::hash::{constant_time_eq, Md5, Sha256};
+use crate::private::hex;
+use crate::private::random::random;
+use crate::private::time::uptime_secs;
+
+use super::{method_str, EspHttpConnection, Handler, Middleware};
+
+const DEFAULT_NONCE_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Checks user names and passwords for `BasicAuth`
+pub trait CredentialVerifier: Send + Sync {
+    fn verify(&self, username: &str, password: &str) -> bool;
+}
+
+impl<F> CredentialVerifier for F
+where
+    F: Fn(&str, &str) -> bool + Send + Sync,
+{
+    fn verify(&self, username: &str, password: &str) -> bool {
+        self(username, password)
+    }
+}
+
+/// Provides the password hashes of users for `DigestAuth`
+pub trait DigestCredentials: Send + Sync {
+    /// Returns `H(username:realm:password)` computed with `algorithm`, if the user exists.
+    ///
+    /// Only this hash is needed for Digest authentication, so the password itself need not be stored.
+    fn password_hash(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: DigestAlgorithm,
+    ) -> Option<Vec<u8>>;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    /// Only for clients not supporting SHA-256
+    Md5,
+}
+
+impl DigestAlgorithm {
+    /// Hashes the concatenation of `parts`.
+    pub fn hash(&self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => {
+                let mut sha = Sha256::new();
+                for part in parts {
+                    sha.update(part);
+                }
+
+                sha.finalize().to_vec()
+            }
+            Self::Md5 => {
+                let mut md5 = Md5::new();
+                for part in parts {
+                    md5.update(part);
+                }
+
+                md5.finalize().to_vec()
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA-256",
+            Self::Md5 => "MD5",
+        }
+    }
+}
+
+/// A fixed set of users with their passwords
+#[derive(Clone, Default)]
+pub struct Users(Vec<(String, String)>);
+
+impl Users {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn user(mut self, username: &str, password: &str) -> Self {
+        self.0.push((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    fn password(&self, username: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(user, _)| user == username)
+            .map(|(_, password)| password.as_str())
+    }
+}
+
+impl Debug for Users {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+impl CredentialVerifier for Users {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        self.password(username)
+            .map(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes()))
+            .unwrap_or(false)
+    }
+}
+
+impl DigestCredentials for Users {
+    fn password_hash(
+        &self,
+        username: &str,
+        realm: &str,
+        algorithm: DigestAlgorithm,
+    ) -> Option<Vec<u8>> {
+        let password = self.password(username)?;
+
+        Some(algorithm.hash(&[
+            username.as_bytes(),
+            b":",
+            realm.as_bytes(),
+            b":",
+            password.as_bytes(),
+        ]))
+    }
+}
+
+/// HTTP Basic authentication (RFC 7617)
+pub struct BasicAuth<V> {
+    realm: String,
+    verifier: V,
+}
+
+impl<V> BasicAuth<V>
+where
+    V: CredentialVerifier,
+{
+    pub fn new(realm: &str, verifier: V) -> Self {
+        Self {
+            realm: realm.to_owned(),
+            verifier,
+        }
+    }
+
+    fn authorized(&self, connection: &EspHttpConnection<'_>) -> bool {
+        let Some(credentials) = connection
+            .header("Authorization")
+            .and_then(|header| strip_scheme(header, "Basic"))
+            .and_then(|credentials| base64::decode(credentials.trim()))
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+        else {
+            return false;
+        };
+
+        credentials
+            .split_once(':')
+            .map(|(username, password)| self.verifier.verify(username, password))
+            .unwrap_or(false)
+    }
+}
+
+impl<'r, V, H> Middleware<EspHttpConnection<'r>, H> for BasicAuth<V>
+where
+    V: CredentialVerifier,
+    H: Handler<EspHttpConnection<'r>>,
+    H::Error: 'static,
+{
+    type Error = Box<dyn Debug>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        if self.authorized(connection) {
+            handler
+                .handle(connection)
+                .map_err(|err| Box::new(err) as Box<dyn Debug>)
+        } else {
+            let challenge = format!("Basic realm={}, charset=\"UTF-8\"", quote(&self.realm));
+
+            unauthorized(connection, &[challenge])
+        }
+    }
+}
+
+/// HTTP Digest authentication (RFC 7616) with `qop=auth`
+pub struct DigestAuth<C> {
+    realm: String,
+    credentials: C,
+    algorithms: Vec<DigestAlgorithm>,
+    nonce_lifetime: Duration,
+    secret: [u8; 32],
+}
+
+impl<C> DigestAuth<C>
+where
+    C: DigestCredentials,
+{
+    /// Creates the middleware, offering SHA-256 and - for older clients - MD5.
+    pub fn new(realm: &str, credentials: C) -> Self {
+        Self {
+            realm: realm.to_owned(),
+            credentials,
+            algorithms: vec![DigestAlgorithm::Sha256, DigestAlgorithm::Md5],
+            nonce_lifetime: DEFAULT_NONCE_LIFETIME,
+            secret: random(),
+        }
+    }
+
+    /// Sets the offered algorithms, in the order of preference.
+    pub fn algorithms(mut self, algorithms: &[DigestAlgorithm]) -> Self {
+        self.algorithms = algorithms.to_vec();
+        self
+    }
+
+    /// Sets the time after which a nonce expires and the client has to retry with a fresh one (5 minutes by default).
+    pub fn nonce_lifetime(mut self, nonce_lifetime: Duration) -> Self {
+        self.nonce_lifetime = nonce_lifetime;
+        self
+    }
+
+    /// Checks the credentials, returning `Err(stale)` if they are invalid
+    fn authorize(&self, connection: &EspHttpConnection<'_>) -> Result<(), bool> {
+        let params = connection
+            .header("Authorization")
+            .and_then(|header| strip_scheme(header, "Digest"))
+            .map(parse_params)
+            .ok_or(false)?;
+
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(param, _)| param.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+
+        let algorithm = match param("algorithm").unwrap_or("MD5") {
+            algorithm if algorithm.eq_ignore_ascii_case("SHA-256") => DigestAlgorithm::Sha256,
+            algorithm if algorithm.eq_ignore_ascii_case("MD5") => DigestAlgorithm::Md5,
+            _ => return Err(false),
+        };
+
+        if !self.algorithms.contains(&algorithm)
+            || param("realm") != Some(self.realm.as_str())
+            || param("uri") != Some(connection.uri())
+            || param("qop") != Some("auth")
+            || param("userhash").map(|userhash| userhash.eq_ignore_ascii_case("true")) == Some(true)
+        {
+            return Err(false);
+        }
+
+        let (Some(username), Some(nonce), Some(nc), Some(cnonce), Some(response)) = (
+            param("username"),
+            param("nonce"),
+            param("nc"),
+            param("cnonce"),
+            param("response"),
+        ) else {
+            return Err(false);
+        };
+
+        let fresh = self.check_nonce(nonce).ok_or(false)?;
+
+        let password_hash = self
+            .credentials
+            .password_hash(username, &self.realm, algorithm)
+            .ok_or(false)?;
+
+        let expected = digest_response(
+            algorithm,
+            &password_hash,
+            method_str(connection.method()),
+            connection.uri(),
+            nonce,
+            nc,
+            cnonce,
+        );
+
+        if !constant_time_eq(
+            expected.as_bytes(),
+            response.to_ascii_lowercase().as_bytes(),
+        ) {
+            return Err(false);
+        }
+
+        // Only report a stale nonce if the credentials were correct, so that
+        // clients do not retry with a fresh nonce and wrong credentials
+        if fresh {
+            Ok(())
+        } else {
+            Err(true)
+        }
+    }
+
+    /// Nonces are the creation time (in seconds since boot) followed by a MAC of it, so
+    /// that they do not need to be stored
+    fn nonce(&self, timestamp: u32) -> String {
+        let mac = Sha256::digest(&[&timestamp.to_be_bytes()[..], &self.secret[..]].concat());
+
+        format!("{timestamp:08x}{}", hex::encode(&mac[..16]))
+    }
+
+    /// Returns whether the nonce is fresh, or `None` if it was not issued by this middleware
+    fn check_nonce(&self, nonce: &str) -> Option<bool> {
+        let timestamp = u32::from_str_radix(nonce.get(..8)?, 16).ok()?;
+
+        constant_time_eq(self.nonce(timestamp).as_bytes(), nonce.as_bytes()).then(|| {
+            uptime_secs().saturating_sub(timestamp as u64) <= self.nonce_lifetime.as_secs()
+        })
+    }
+}
+
+impl<'r, C, H> Middleware<EspHttpConnection<'r>, H> for DigestAuth<C>
+where
+    C: DigestCredentials,
+    H: Handler<EspHttpConnection<'r>>,
+    H::Error: 'static,
+{
+    type Error = Box<dyn Debug>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        match self.authorize(connection) {
+            Ok(()) => handler
+                .handle(connection)
+                .map_err(|err| Box::new(err) as Box<dyn Debug>),
+            Err(stale) => {
+                let nonce = self.nonce(uptime_secs() as u32);
+
+                let challenges = self
+                    .algorithms
+                    .iter()
+                    .map(|algorithm| {
+                        format!(
+                            "Digest realm={}, qop=\"auth\", algorithm={}, nonce=\"{}\", charset=UTF-8{}",
+                            quote(&self.realm),
+                            algorithm.name(),
+                            nonce,
+                            if stale { ", stale=true" } else { "" },
+                        )
+                    })
+                    .collect::<Vec<_>>();
+
+                unauthorized(connection, &challenges)
+            }
+        }
+    }
+}
+
+/// Bearer token authentication (RFC 6750)
+pub struct BearerAuth<V> {
+    realm: String,
+    verifier: V,
+}
+
+impl<V> BearerAuth<V>
+where
+    V: Fn(&str) -> bool + Send + Sync,
+{
+    /// Creates the middleware, accepting requests with tokens for which `verifier` returns `true`.
+    pub fn new(realm: &str, verifier: V) -> Self {
+        Self {
+            realm: realm.to_owned(),
+            verifier,
+        }
+    }
+}
+
+impl<'r, V, H> Middleware<EspHttpConnection<'r>, H> for BearerAuth<V>
+where
+    V: Fn(&str) -> bool + Send + Sync,
+    H: Handler<EspHttpConnection<'r>>,
+    H::Error: 'static,
+{
+    type Error = Box<dyn Debug>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        let token = connection
+            .header("Authorization")
+            .and_then(|header| strip_scheme(header, "Bearer"))
+            .map(str::trim);
+
+        match token {
+            Some(token) if (self.verifier)(token) => handler
+                .handle(connection)
+                .map_err(|err| Box::new(err) as Box<dyn Debug>),
+            Some(_) => {
+                let challenge = format!(
+                    "Bearer realm={}, error=\"invalid_token\"",
+                    quote(&self.realm)
+                );
+
+                unauthorized(connection, &[challenge])
+            }
+            None => unauthorized(
+                connection,
+                &[format!("Bearer realm={}", quote(&self.realm))],
+            ),
+        }
+    }
+}
+
+/// Computes the expected `response` parameter of a Digest `Authorization` header with `qop=auth`
+fn digest_response(
+    algorithm: DigestAlgorithm,
+    password_hash: &[u8],
+    method: &str,
+    uri: &str,
+    nonce: &str,
+    nc: &str,
+    cnonce: &str,
+) -> String {
+    let request_hash = algorithm.hash(&[method.as_bytes(), b":", uri.as_bytes()]);
+
+    hex::encode(&algorithm.hash(&[
+        hex::encode(password_hash).as_bytes(),
+        b":",
+        nonce.as_bytes(),
+        b":",
+        nc.as_bytes(),
+        b":",
+        cnonce.as_bytes(),
+        b":auth:",
+        hex::encode(&request_hash).as_bytes(),
+    ]))
+}
+
+fn unauthorized(
+    connection: &mut EspHttpConnection<'_>,
+    challenges: &[String],
+) -> Result<(), Box<dyn Debug>> {
+    debug!("Unauthorized request to {}", connection.uri());
+
+    let headers = challenges
+        .iter()
+        .map(|challenge| ("WWW-Authenticate", challenge.as_str()))
+        .collect::<Vec<_>>();
+
+    connection
+        .initiate_response(401, Some("Unauthorized"), &headers)
+        .map_err(|err| Box::new(err) as Box<dyn Debug>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_digest_response() {
+        // RFC 7616, section 3.9.1
+        let users = Users::new().user("Mufasa", "Circle of Life");
+
+        let response = |algorithm| {
+            let password_hash = users
+                .password_hash("Mufasa", "http-auth@example.org", algorithm)
+                .unwrap();
+
+            digest_response(
+                algorithm,
+                &password_hash,
+                "GET",
+                "/dir/index.html",
+                "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+                "00000001",
+                "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+            )
+        };
+
+        assert_eq!(
+            response(DigestAlgorithm::Md5),
+            "8ca523f5e9506fed4657c9700eebdbec"
+        );
+        assert_eq!(
+            response(DigestAlgorithm::Sha256),
+            "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"
+        );
+    }
+}
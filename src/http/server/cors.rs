@@ -0,0 +1,320 @@
+//! Cross-Origin Resource Sharing (CORS)
+//!
+//! `Cors` is a `Middleware` which adds the `Access-Control-*` headers to the responses of the
+//! handlers it wraps, for requests from allowed origins, and answers CORS preflight (`OPTIONS`)
+//! requests itself.
+//!
+//! `Cors` is also a `Handler` answering preflight requests, so - rather than registering an
+//! `OPTIONS` handler for every URI - preflight requests for a whole URI prefix can be handled
+//! with a single wildcard registration (which requires `uri_match_wildcard` to be enabled):
+//!
+//! ```
+//! use core::time::Duration;
+//!
+//! use esp_idf_svc::http::server::cors::Cors;
+//! use esp_idf_svc::http::server::{fn_handler, ChainRoot, Middleware};
+//! use esp_idf_svc::http::Method;
+//!
+//! let cors = Cors::new()
+//!     .allow_origin("https://dashboard.example.com")
+//!     .allow_methods(&[Method::Get, Method::Post])
+//!     .allow_headers(&["Content-Type"])
+//!     .max_age(Duration::from_secs(3600));
+//!
+//! server.handler_chain(
+//!     ChainRoot
+//!         .get("/api/status", cors.clone().compose(fn_handler(status)))
+//!         .post("/api/settings", cors.clone().compose(fn_handler(settings))),
+//! )?;
+//!
+//! server.handler("/api/*", Method::Options, cors)?;
+//! ```
+//!
+//! Note that the `Access-Control-*` headers count against the `max_resp_headers` limit of the
+//! server `Configuration` (8 by default): up to six of them are added to preflight responses,
+//! so the limit may need to be raised, especially if other middlewares add headers as well.
+use core::fmt::Debug;
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use super::{method_str, EspHttpConnection, Handler, Method, Middleware};
+
+/// The CORS policy, see the module documentation
+#[derive(Clone, Debug)]
+pub struct Cors {
+    /// `None` allows all origins
+    origins: Option<Vec<String>>,
+    methods: Vec<Method>,
+    /// `None` allows all headers requested by the client
+    headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Creates a policy allowing all origins, the methods GET, HEAD, POST, PUT, PATCH and DELETE,
+    /// and all request headers, without credentials.
+    pub fn new() -> Self {
+        Self {
+            origins: None,
+            methods: vec![
+                Method::Get,
+                Method::Head,
+                Method::Post,
+                Method::Put,
+                Method::Patch,
+                Method::Delete,
+            ],
+            headers: None,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allows the given origin (e.g. `https://dashboard.example.com`).
+    ///
+    /// Once an origin is added, only the added origins are allowed.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.origins
+            .get_or_insert_with(Vec::new)
+            .push(origin.trim_end_matches('/').to_owned());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: &[Method]) -> Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// Only allows the given request headers, instead of all headers requested by the client.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = Some(headers.iter().map(|header| (*header).to_owned()).collect());
+        self
+    }
+
+    /// Allows the client to read the given response headers.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers = headers.iter().map(|header| (*header).to_owned()).collect();
+        self
+    }
+
+    /// Allows requests with credentials (cookies or `Authorization` headers).
+    ///
+    /// Credentials are only allowed for explicitly added origins (see `allow_origin`): as long as
+    /// all origins are allowed, `Access-Control-Allow-Credentials` is never sent, as otherwise any
+    /// website could make credentialed requests to the device.
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Sets for how long the client may cache the result of a preflight request.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Returns the value for `Access-Control-Allow-Origin`, if `origin` is allowed.
+    fn allowed_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        match &self.origins {
+            None => Some("*"),
+            Some(origins) => origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+                .then_some(origin),
+        }
+    }
+
+    /// Returns `true` if `Access-Control-Allow-Credentials` is sent, i.e. never for a wildcard origin.
+    fn credentials_allowed(&self) -> bool {
+        self.credentials && self.origins.is_some()
+    }
+
+    fn is_preflight(connection: &EspHttpConnection<'_>) -> bool {
+        connection.method() == Method::Options
+            && connection.header("Origin").is_some()
+            && connection.header("Access-Control-Request-Method").is_some()
+    }
+
+    fn preflight(&self, connection: &mut EspHttpConnection<'_>) -> Result<(), EspError> {
+        let origin = connection.header("Origin").unwrap_or_default();
+        let method = connection
+            .header("Access-Control-Request-Method")
+            .unwrap_or_default();
+
+        let method_allowed = self
+            .methods
+            .iter()
+            .any(|allowed| method_str(*allowed).eq_ignore_ascii_case(method.trim()));
+
+        let Some(allow_origin) = self
+            .allowed_origin(origin)
+            .filter(|_| method_allowed)
+            .map(ToOwned::to_owned)
+        else {
+            debug!(
+                "Rejecting CORS preflight request to {} from {}",
+                connection.uri(),
+                origin
+            );
+
+            return connection.initiate_response(403, Some("Forbidden"), &[]);
+        };
+
+        let allow_headers = match &self.headers {
+            Some(headers) => headers.join(", "),
+            None => connection
+                .header("Access-Control-Request-Headers")
+                .unwrap_or_default()
+                .to_owned(),
+        };
+
+        let allow_methods = self
+            .methods
+            .iter()
+            .map(|method| method_str(*method))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let max_age = self.max_age.map(|max_age| max_age.as_secs().to_string());
+
+        let mut headers = vec![
+            ("Access-Control-Allow-Origin", allow_origin.as_str()),
+            ("Access-Control-Allow-Methods", allow_methods.as_str()),
+            ("Vary", "Origin, Access-Control-Request-Headers"),
+        ];
+
+        if !allow_headers.is_empty() {
+            headers.push(("Access-Control-Allow-Headers", allow_headers.as_str()));
+        }
+
+        if let Some(max_age) = &max_age {
+            headers.push(("Access-Control-Max-Age", max_age.as_str()));
+        }
+
+        if self.credentials_allowed() {
+            headers.push(("Access-Control-Allow-Credentials", "true"));
+        }
+
+        connection.initiate_response(204, Some("No Content"), &headers)
+    }
+
+    /// Adds the CORS headers for a non-preflight request, if it is a cross-origin request from an allowed origin
+    fn add_headers(&self, connection: &mut EspHttpConnection<'_>) {
+        let Some(allow_origin) = connection
+            .header("Origin")
+            .and_then(|origin| self.allowed_origin(origin))
+            .map(ToOwned::to_owned)
+        else {
+            return;
+        };
+
+        if allow_origin != "*" {
+            connection.add_response_header("Vary", "Origin");
+        }
+
+        connection.add_response_header("Access-Control-Allow-Origin", &allow_origin);
+
+        if self.credentials_allowed() {
+            connection.add_response_header("Access-Control-Allow-Credentials", "true");
+        }
+
+        if !self.expose_headers.is_empty() {
+            connection.add_response_header(
+                "Access-Control-Expose-Headers",
+                &self.expose_headers.join(", "),
+            );
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'r, H> Middleware<EspHttpConnection<'r>, H> for Cors
+where
+    H: Handler<EspHttpConnection<'r>>,
+    H::Error: 'static,
+{
+    type Error = Box<dyn Debug>;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        if Self::is_preflight(connection) {
+            self.preflight(connection)
+                .map_err(|err| Box::new(err) as Box<dyn Debug>)
+        } else {
+            self.add_headers(connection);
+
+            handler
+                .handle(connection)
+                .map_err(|err| Box::new(err) as Box<dyn Debug>)
+        }
+    }
+}
+
+/// Answers preflight requests; other requests are answered with `404 Not Found`
+impl<'r> Handler<EspHttpConnection<'r>> for Cors {
+    type Error = EspError;
+
+    fn handle(&self, connection: &mut EspHttpConnection<'r>) -> Result<(), Self::Error> {
+        if Self::is_preflight(connection) {
+            self.preflight(connection)
+        } else {
+            connection.initiate_response(404, Some("Not Found"), &[])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_all_origins_without_credentials() {
+        let cors = Cors::new().allow_credentials(true);
+
+        assert_eq!(cors.allowed_origin("https://any.example.com"), Some("*"));
+        assert!(!cors.credentials_allowed());
+    }
+
+    #[test]
+    fn allows_added_origins_only() {
+        let cors = Cors::new()
+            .allow_origin("https://dashboard.example.com/")
+            .allow_credentials(true);
+
+        assert_eq!(
+            cors.allowed_origin("https://Dashboard.example.com"),
+            Some("https://Dashboard.example.com")
+        );
+        assert_eq!(cors.allowed_origin("https://evil.example.com"), None);
+        assert_eq!(
+            cors.allowed_origin("https://dashboard.example.com.evil"),
+            None
+        );
+        assert!(cors.credentials_allowed());
+
+        assert!(!Cors::new()
+            .allow_origin("https://dashboard.example.com")
+            .credentials_allowed());
+    }
+}
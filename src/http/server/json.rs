@@ -0,0 +1,311 @@
+//! JSON request and response helpers
+//!
+//! Adds methods to `EspHttpConnection` for reading a request body of bounded size into a
+//! `serde::Deserialize` type and for writing a `serde::Serialize` value as an
+//! `application/json` response.
+//!
+//! `JsonHandler` (created with `json_handler`) adapts a function returning `Result<T, HttpError>`
+//! into a `Handler`: `Ok(value)` is sent as a `200 OK` JSON response, while `Err(error)` is sent
+//! with the status of the error and a JSON body of the form `{"status": 404, "error": "..."}`.
+//!
+//! ```
+//! use esp_idf_svc::http::server::json::{json_handler, HttpError};
+//! use esp_idf_svc::http::Method;
+//!
+//! server.handler(
+//!     "/api/settings",
+//!     Method::Post,
+//!     json_handler(|mut request| {
+//!         let settings: Settings = request.connection().read_json(1024)?;
+//!
+//!         if settings.interval == 0 {
+//!             return Err(HttpError::new(422, "Interval must not be 0"));
+//!         }
+//!
+//!         save(&settings)?;
+//!
+//!         Ok(settings)
+//!     }),
+//! )?;
+//! ```
+use core::fmt::{self, Display};
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::sys::*;
+
+use crate::io::EspIOError;
+
+use super::router::ParamError;
+use super::{EspHttpConnection, Handler, Request};
+
+const CONTENT_TYPE_JSON: &str = "application/json";
+
+/// An error which is sent to the client as a response with the given status
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl HttpError {
+    pub fn new(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_owned(),
+        }
+    }
+
+    pub fn bad_request(message: &str) -> Self {
+        Self::new(400, message)
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        Self::new(404, message)
+    }
+
+    pub fn internal(message: &str) -> Self {
+        Self::new(500, message)
+    }
+
+    /// Sends the error as a JSON response.
+    pub fn write(&self, connection: &mut EspHttpConnection<'_>) -> Result<(), EspError> {
+        connection.write_json(self.status, &self.to_json())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "status": self.status,
+            "error": self.message,
+        })
+    }
+}
+
+impl Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for HttpError {}
+
+impl From<EspError> for HttpError {
+    fn from(err: EspError) -> Self {
+        Self::new(500, &format!("{err}"))
+    }
+}
+
+impl From<EspIOError> for HttpError {
+    fn from(err: EspIOError) -> Self {
+        err.0.into()
+    }
+}
+
+impl From<serde_json::Error> for HttpError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::new(400, &format!("Invalid JSON: {err}"))
+    }
+}
+
+impl From<ParamError> for HttpError {
+    fn from(err: ParamError) -> Self {
+        Self::new(400, &format!("{err}"))
+    }
+}
+
+impl<'a> EspHttpConnection<'a> {
+    /// Reads the request body - which must not be larger than `max_len` bytes - and deserializes
+    /// it from JSON.
+    ///
+    /// Fails with status 413 if the body is too large, 415 if the request has a content type
+    /// other than `application/json`, and 400 if the body is not valid JSON for `T`.
+    pub fn read_json<T>(&mut self, max_len: usize) -> Result<T, HttpError>
+    where
+        T: DeserializeOwned,
+    {
+        if let Some(content_type) = self.header("Content-Type") {
+            let mime = content_type.split(';').next().unwrap_or("").trim();
+
+            if !mime.eq_ignore_ascii_case(CONTENT_TYPE_JSON) {
+                return Err(HttpError::new(415, "Expected application/json"));
+            }
+        }
+
+        let content_len = self
+            .header("Content-Length")
+            .and_then(|len| len.trim().parse::<usize>().ok());
+
+        if content_len.map(|len| len > max_len).unwrap_or(false) {
+            return Err(HttpError::new(413, "Request body too large"));
+        }
+
+        let mut body = Vec::with_capacity(content_len.unwrap_or(0));
+        let mut buf = [0; 256];
+
+        loop {
+            let len = self.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            if body.len() + len > max_len {
+                return Err(HttpError::new(413, "Request body too large"));
+            }
+
+            body.extend_from_slice(&buf[..len]);
+        }
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Sends `value` serialized as JSON, as a response with the given status.
+    ///
+    /// For statuses which have no body (`1xx`, `204 No Content` and `304 Not Modified`), `value`
+    /// is ignored and the response is sent without a body and without a `Content-Type`.
+    pub fn write_json<T>(&mut self, status: u16, value: &T) -> Result<(), EspError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let message = status_message(status);
+
+        if !has_body(status) {
+            return self.initiate_response(status, (!message.is_empty()).then_some(message), &[]);
+        }
+
+        let body = serde_json::to_vec(value).map_err(|err| {
+            warn!("Serializing the JSON response failed: {}", err);
+            EspError::from_infallible::<ESP_FAIL>()
+        })?;
+
+        self.initiate_response(
+            status,
+            (!message.is_empty()).then_some(message),
+            &[("Content-Type", CONTENT_TYPE_JSON)],
+        )?;
+
+        self.write_all(&body)
+    }
+}
+
+/// A handler adapting a function returning `Result<T, HttpError>`, see the module documentation.
+pub struct JsonHandler<F>(F);
+
+impl<F> JsonHandler<F> {
+    pub const fn new(f: F) -> Self {
+        Self(f)
+    }
+}
+
+impl<'a, F, T> Handler<EspHttpConnection<'a>> for JsonHandler<F>
+where
+    F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<T, HttpError>,
+    T: Serialize,
+{
+    type Error = EspError;
+
+    fn handle(&self, connection: &mut EspHttpConnection<'a>) -> Result<(), Self::Error> {
+        let result = (self.0)(Request::wrap(&mut *connection));
+
+        if connection.is_response_initiated() {
+            if let Err(err) = result {
+                warn!(
+                    "Handler for {} failed after sending a response: {}",
+                    connection.uri(),
+                    err
+                );
+            }
+
+            return Ok(());
+        }
+
+        match result {
+            Ok(value) => connection.write_json(200, &value),
+            Err(err) => {
+                debug!("Handler for {} failed: {}", connection.uri(), err);
+
+                err.write(connection)
+            }
+        }
+    }
+}
+
+/// Wraps the given function into a `JsonHandler`.
+pub fn json_handler<F, T>(f: F) -> JsonHandler<F>
+where
+    F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>) -> Result<T, HttpError> + Send,
+    T: Serialize,
+{
+    JsonHandler::new(f)
+}
+
+/// Returns `false` for the statuses whose responses must not have a body.
+fn has_body(status: u16) -> bool {
+    !matches!(status, 100..=199 | 204 | 304)
+}
+
+fn status_message(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_errors() {
+        assert_eq!(
+            serde_json::to_string(&HttpError::not_found("No such \"item\"").to_json()).unwrap(),
+            r#"{"error":"No such \"item\"","status":404}"#
+        );
+        assert_eq!(
+            HttpError::from(serde_json::from_str::<u32>("x").unwrap_err()).status,
+            400
+        );
+    }
+
+    #[test]
+    fn status_messages() {
+        assert_eq!(status_message(200), "OK");
+        assert_eq!(status_message(304), "Not Modified");
+        assert_eq!(status_message(422), "Unprocessable Content");
+        assert_eq!(status_message(599), "");
+    }
+
+    #[test]
+    fn statuses_without_body() {
+        assert!(!has_body(101));
+        assert!(!has_body(204));
+        assert!(!has_body(304));
+        assert!(has_body(200));
+        assert!(has_body(404));
+    }
+}
@@ -0,0 +1,551 @@
+//! Streaming parser for `multipart/form-data` request bodies
+//!
+//! HTML forms with file inputs are posted as `multipart/form-data`. `Multipart` parses such a
+//! body from any `io::Read` - typically the request itself, see `EspHttpConnection::multipart` -
+//! and yields its parts one by one. Each `Part` provides the headers of the part and is itself an
+//! `io::Read` for the content of the part, so files of any size can be processed with a small,
+//! fixed amount of memory.
+//!
+//! E.g. a firmware image uploaded from the browser can be written directly into an OTA update:
+//!
+//! ```
+//! use esp_idf_svc::http::Method;
+//! use esp_idf_svc::io::Read;
+//! use esp_idf_svc::ota::EspOta;
+//!
+//! server.fn_handler("/update", Method::Post, |mut request| {
+//!     let mut multipart = request.connection().multipart()?;
+//!
+//!     while let Some(mut part) = multipart.next_part()? {
+//!         if part.name() != Some("firmware") {
+//!             continue;
+//!         }
+//!
+//!         let mut ota = EspOta::new()?;
+//!         let mut update = ota.initiate_update()?;
+//!
+//!         let mut buf = [0; 1024];
+//!
+//!         loop {
+//!             let len = part.read(&mut buf)?;
+//!             if len == 0 {
+//!                 break;
+//!             }
+//!
+//!             update.write(&buf[..len])?;
+//!         }
+//!
+//!         update.complete()?;
+//!     }
+//!
+//!     request.into_ok_response()?;
+//!
+//!     Ok::<_, anyhow::Error>(())
+//! })?;
+//! ```
+use core::fmt::{self, Debug, Display};
+use core::ops::Range;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use embedded_svc::io::{self, ErrorKind, ErrorType, Read};
+
+use crate::sys::*;
+
+use crate::io::EspIOError;
+
+use super::EspHttpConnection;
+
+/// The maximum length of a boundary, as per RFC 2046
+pub const MAX_BOUNDARY_LEN: usize = 70;
+
+const BUF_LEN: usize = 1024;
+const MAX_HEADERS: usize = 16;
+
+#[derive(Debug)]
+pub enum MultipartError<E> {
+    /// Reading the body failed
+    Io(E),
+    /// The body is not valid `multipart/form-data`, or a part header line is too long
+    Malformed,
+}
+
+impl<E> Display for MultipartError<E>
+where
+    E: Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "I/O error: {err}"),
+            Self::Malformed => write!(f, "Malformed multipart body"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> std::error::Error for MultipartError<E> where E: Debug + Display {}
+
+impl<E> io::Error for MultipartError<E>
+where
+    E: io::Error,
+{
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Io(err) => err.kind(),
+            Self::Malformed => ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl From<MultipartError<EspIOError>> for EspError {
+    fn from(err: MultipartError<EspIOError>) -> Self {
+        match err {
+            MultipartError::Io(err) => err.0,
+            MultipartError::Malformed => EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+        }
+    }
+}
+
+/// Returns the boundary of a `multipart/form-data` content type, if `content_type` is one.
+pub fn boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+
+    params
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+
+            name.trim()
+                .eq_ignore_ascii_case("boundary")
+                .then(|| value.trim().trim_matches('"'))
+        })
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= MAX_BOUNDARY_LEN)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    /// Within the preamble or the content of a part
+    Body,
+    /// Right after a delimiter
+    Delimiter,
+    /// After the closing delimiter
+    Done,
+}
+
+/// A streaming `multipart/form-data` parser, see the module documentation
+pub struct Multipart<R> {
+    reader: R,
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+    state: State,
+}
+
+impl<R> Multipart<R>
+where
+    R: Read,
+{
+    /// Creates a parser for a body read from `reader`, using the given boundary
+    /// (see `boundary` for extracting it from the `Content-Type` header).
+    pub fn new(reader: R, boundary: &str) -> Result<Self, MultipartError<R::Error>> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LEN {
+            return Err(MultipartError::Malformed);
+        }
+
+        let mut delimiter = Vec::with_capacity(boundary.len() + 4);
+        delimiter.extend_from_slice(b"\r\n--");
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        // The first delimiter is not preceded by a line break when there is no preamble,
+        // so one is prepended to the body
+        let mut buf = vec![0; BUF_LEN];
+        buf[..2].copy_from_slice(b"\r\n");
+
+        Ok(Self {
+            reader,
+            delimiter,
+            buf,
+            start: 0,
+            end: 2,
+            eof: false,
+            state: State::Body,
+        })
+    }
+
+    /// Returns the next part, skipping the content of the previous one which was not read.
+    ///
+    /// Returns `None` once the closing delimiter is reached.
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, R>>, MultipartError<R::Error>> {
+        while self.state == State::Body {
+            self.start += self.body_len()?;
+        }
+
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        self.fill(2)?;
+
+        if self.buf[self.start..self.end].starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+
+        // Only transport padding may follow the delimiter on its line
+        let padding = self.read_line()?;
+        if !self.buf[padding]
+            .iter()
+            .all(|byte| *byte == b' ' || *byte == b'\t')
+        {
+            return Err(MultipartError::Malformed);
+        }
+
+        let mut headers = Vec::new();
+
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+
+            let line =
+                core::str::from_utf8(&self.buf[line]).map_err(|_| MultipartError::Malformed)?;
+            let (name, value) = line.split_once(':').ok_or(MultipartError::Malformed)?;
+
+            if headers.len() == MAX_HEADERS {
+                return Err(MultipartError::Malformed);
+            }
+
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+
+        self.state = State::Body;
+
+        Ok(Some(Part {
+            multipart: self,
+            headers,
+        }))
+    }
+
+    /// Consumes the parser, returning the reader.
+    pub fn release(self) -> R {
+        self.reader
+    }
+
+    /// Returns the number of buffered bytes which belong to the current body.
+    ///
+    /// Returns 0 - after consuming the delimiter - once the body is complete.
+    fn body_len(&mut self) -> Result<usize, MultipartError<R::Error>> {
+        self.fill(self.delimiter.len())?;
+
+        let data = &self.buf[self.start..self.end];
+
+        if let Some(pos) = find(data, &self.delimiter) {
+            if pos == 0 {
+                self.start += self.delimiter.len();
+                self.state = State::Delimiter;
+            }
+
+            Ok(pos)
+        } else if self.eof {
+            Err(MultipartError::Malformed)
+        } else {
+            // Keep the bytes which might be the beginning of the delimiter
+            Ok(data.len() + 1 - self.delimiter.len())
+        }
+    }
+
+    /// Reads a line, returning its range in the buffer without the line break.
+    fn read_line(&mut self) -> Result<Range<usize>, MultipartError<R::Error>> {
+        loop {
+            if let Some(pos) = find(&self.buf[self.start..self.end], b"\r\n") {
+                let line = self.start..self.start + pos;
+                self.start += pos + 2;
+
+                return Ok(line);
+            }
+
+            if self.eof || self.end - self.start == self.buf.len() {
+                return Err(MultipartError::Malformed);
+            }
+
+            self.fill(self.end - self.start + 1)?;
+        }
+    }
+
+    /// Reads until at least `min` bytes are buffered or the end of the body is reached.
+    fn fill(&mut self, min: usize) -> Result<(), MultipartError<R::Error>> {
+        if self.end - self.start >= min || self.eof {
+            return Ok(());
+        }
+
+        self.buf.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+
+        while self.end < min && !self.eof {
+            let len = self
+                .reader
+                .read(&mut self.buf[self.end..])
+                .map_err(MultipartError::Io)?;
+
+            if len == 0 {
+                self.eof = true;
+            } else {
+                self.end += len;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A part of a `multipart/form-data` body, which is read via `io::Read`
+pub struct Part<'a, R> {
+    multipart: &'a mut Multipart<R>,
+    headers: Vec<(String, String)>,
+}
+
+impl<'a, R> Part<'a, R> {
+    /// Returns the value of the header `name` of the part.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// The name of the form field, from the `Content-Disposition` header
+    pub fn name(&self) -> Option<&str> {
+        self.disposition_param("name")
+    }
+
+    /// The name of the uploaded file, from the `Content-Disposition` header
+    pub fn filename(&self) -> Option<&str> {
+        self.disposition_param("filename")
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    fn disposition_param(&self, name: &str) -> Option<&str> {
+        let (_, mut params) = self.header("Content-Disposition")?.split_once(';')?;
+
+        loop {
+            let (param, rest) = params.split_once('=')?;
+            let rest = rest.trim_start();
+
+            let (value, next) = if let Some(quoted) = rest.strip_prefix('"') {
+                let (value, rest) = quoted.split_once('"')?;
+
+                (
+                    value,
+                    rest.split_once(';').map(|(_, next)| next).unwrap_or(""),
+                )
+            } else {
+                let (value, next) = rest.split_once(';').unwrap_or((rest, ""));
+
+                (value.trim_end(), next)
+            };
+
+            if param.trim().eq_ignore_ascii_case(name) {
+                return Some(value);
+            }
+
+            params = next;
+        }
+    }
+}
+
+impl<'a, R> ErrorType for Part<'a, R>
+where
+    R: Read,
+{
+    type Error = MultipartError<R::Error>;
+}
+
+impl<'a, R> Read for Part<'a, R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() || self.multipart.state != State::Body {
+            return Ok(0);
+        }
+
+        let multipart = &mut *self.multipart;
+
+        let len = multipart.body_len()?.min(buf.len());
+
+        buf[..len].copy_from_slice(&multipart.buf[multipart.start..multipart.start + len]);
+        multipart.start += len;
+
+        Ok(len)
+    }
+}
+
+impl<'a> EspHttpConnection<'a> {
+    /// Returns a parser for the `multipart/form-data` request body.
+    ///
+    /// Fails with `ESP_ERR_INVALID_ARG` if the request has a different content type.
+    pub fn multipart(&mut self) -> Result<Multipart<&mut Self>, EspError> {
+        let boundary = self
+            .header("Content-Type")
+            .and_then(boundary)
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_ARG>)?
+            .to_owned();
+
+        Ok(Multipart::new(self, &boundary)?)
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        Hello\r\n\
+        --XyZ   \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a;b.bin\"\r\n\
+        Content-Type: application/octet-stream\r\n\
+        \r\n\
+        \r\n--Xy\r\n-XyZ\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    /// A reader returning at most `chunk_len` bytes per read
+    struct Chunked<'a>(&'a [u8], usize);
+
+    impl<'a> ErrorType for Chunked<'a> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl<'a> Read for Chunked<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = self.0.len().min(buf.len()).min(self.1);
+
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+
+            Ok(len)
+        }
+    }
+
+    fn read_to_end<R: Read>(reader: &mut R) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 3];
+
+        loop {
+            let len = reader.read(&mut buf).map_err(|_| ()).unwrap();
+            if len == 0 {
+                break data;
+            }
+
+            data.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn parse_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=\"XyZ\""),
+            Some("XyZ")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data;charset=utf-8;boundary=abc"),
+            Some("abc")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn parse_parts() {
+        for chunk_len in [1, 5, 64, BODY.len()] {
+            let mut multipart = Multipart::new(Chunked(BODY, chunk_len), "XyZ").unwrap();
+
+            let mut part = multipart.next_part().unwrap().unwrap();
+            assert_eq!(part.name(), Some("title"));
+            assert_eq!(part.filename(), None);
+            assert_eq!(read_to_end(&mut part), b"Hello");
+
+            let mut part = multipart.next_part().unwrap().unwrap();
+            assert_eq!(part.name(), Some("file"));
+            assert_eq!(part.filename(), Some("a;b.bin"));
+            assert_eq!(part.content_type(), Some("application/octet-stream"));
+            assert_eq!(read_to_end(&mut part), b"\r\n--Xy\r\n-XyZ");
+
+            assert!(multipart.next_part().unwrap().is_none());
+            assert!(multipart.next_part().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn skip_unread_parts() {
+        let body = b"--b\r\n\r\nfirst\r\n--b\r\nX-Test: 1\r\n\r\nsecond\r\n--b--";
+
+        let mut multipart = Multipart::new(Chunked(body, 4), "b").unwrap();
+
+        multipart.next_part().unwrap().unwrap();
+
+        let mut part = multipart.next_part().unwrap().unwrap();
+        assert_eq!(part.header("x-test"), Some("1"));
+        assert_eq!(read_to_end(&mut part), b"second");
+
+        assert!(multipart.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_body() {
+        let body = b"--b\r\n\r\nfirst\r\n--";
+
+        let mut multipart = Multipart::new(Chunked(body, 4), "b").unwrap();
+
+        let mut part = multipart.next_part().unwrap().unwrap();
+
+        let mut data = Vec::new();
+        let mut buf = [0; 16];
+
+        let err = loop {
+            match part.read(&mut buf) {
+                Ok(len) => data.extend_from_slice(&buf[..len]),
+                Err(err) => break err,
+            }
+        };
+
+        assert_eq!(data, b"first");
+        assert!(matches!(err, MultipartError::Malformed));
+    }
+}
@@ -0,0 +1,535 @@
+//! Routing of requests based on path patterns
+//!
+//! `Router` dispatches requests to handlers based on path patterns with named parameters,
+//! like `/api/devices/{id}/channels/{ch}`, and passes the extracted path and query string
+//! parameters to the handler as `RouteParams`. A trailing `{*name}` segment matches the
+//! rest of the path.
+//!
+//! Requests not matching any pattern are answered with `404 Not Found`, and requests
+//! matching a pattern but not its method with `405 Method Not Allowed`. If a handler fails
+//! before sending a response after `RouteParams::path_as` or `RouteParams::query_as` failed
+//! (i.e. a parameter is missing or could not be parsed) - whatever the error type of the
+//! handler, e.g. `anyhow::Error` - `400 Bad Request` is returned.
+//!
+//! The router is registered with `EspHttpServer::handler_chain` below a URI prefix, which
+//! requires the server to be configured with `uri_match_wildcard` enabled:
+//!
+//! ```
+//! use esp_idf_svc::http::server::router::Router;
+//! use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+//! use esp_idf_svc::http::Method;
+//!
+//! let mut server = EspHttpServer::new(&Configuration {
+//!     uri_match_wildcard: true,
+//!     ..Default::default()
+//! })?;
+//!
+//! server.handler_chain(
+//!     Router::new("/api")
+//!         .get("/devices/{id}/channels/{ch}", |request, params| {
+//!             let id: u32 = params.path_as("id")?;
+//!             let ch: u8 = params.path_as("ch")?;
+//!             let verbose = params.query_as::<bool>("verbose")?.unwrap_or(false);
+//!
+//!             request.into_ok_response()?.write_all(format!("{id}/{ch}/{verbose}").as_bytes())?;
+//!
+//!             Ok::<_, anyhow::Error>(())
+//!         })
+//!         .route("/devices/{id}", Method::Delete, |request, params| {
+//!             remove_device(params.path_as("id")?);
+//!
+//!             request.into_ok_response()?;
+//!
+//!             Ok::<_, anyhow::Error>(())
+//!         }),
+//! )?;
+//! ```
+use core::any::Any;
+use core::cell::RefCell;
+use core::fmt::{self, Debug, Display};
+use core::str::FromStr;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use super::urlencoded::{percent_decode, UrlEncoded};
+use super::{
+    method_str, EspHttpConnection, EspHttpServer, EspHttpTraversableChain, Handler, Method, Request,
+};
+
+/// The error returned when a path or query string parameter is missing or cannot be parsed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParamError {
+    pub name: String,
+}
+
+impl Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing or invalid parameter {}", self.name)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParamError {}
+
+/// The path and query string parameters of a routed request
+#[derive(Clone, Debug, Default)]
+pub struct RouteParams {
+    path: Vec<(String, String)>,
+    query: UrlEncoded,
+    /// The first failure of `path_as` or `query_as`, so that the router recognizes it
+    /// regardless of how the handler propagates the `ParamError`
+    error: RefCell<Option<ParamError>>,
+}
+
+impl RouteParams {
+    /// Returns the value of the path parameter `name`.
+    pub fn path(&self, name: &str) -> Option<&str> {
+        self.path
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Parses the value of the path parameter `name`.
+    pub fn path_as<T>(&self, name: &str) -> Result<T, ParamError>
+    where
+        T: FromStr,
+    {
+        let result = self
+            .path(name)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| ParamError {
+                name: name.to_owned(),
+            });
+
+        self.record(result)
+    }
+
+    /// Returns the value of the first query string parameter `name`.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name)
+    }
+
+    /// Parses the value of the first query string parameter `name`, if present.
+    pub fn query_as<T>(&self, name: &str) -> Result<Option<T>, ParamError>
+    where
+        T: FromStr,
+    {
+        let result = self.query.get_as(name);
+
+        self.record(result)
+    }
+
+    /// Returns all query string parameters, in the order of the query string.
+    pub fn query_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.query.iter()
+    }
+
+    fn record<T>(&self, result: Result<T, ParamError>) -> Result<T, ParamError> {
+        if let Err(err) = &result {
+            self.error.borrow_mut().get_or_insert_with(|| err.clone());
+        }
+
+        result
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self, EspError> {
+        let mut segments = Vec::new();
+        let mut parts = pattern
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .peekable();
+
+        while let Some(part) = parts.next() {
+            let segment = if let Some(name) = part
+                .strip_prefix('{')
+                .and_then(|part| part.strip_suffix('}'))
+            {
+                if let Some(name) = name.strip_prefix('*') {
+                    if parts.peek().is_some() {
+                        // A rest parameter must be the last segment
+                        return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+                    }
+
+                    Segment::Rest(name.to_owned())
+                } else {
+                    Segment::Param(name.to_owned())
+                }
+            } else if part.contains(['{', '}']) {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            } else {
+                Segment::Literal(part.to_owned())
+            };
+
+            segments.push(segment);
+        }
+
+        Ok(Self(segments))
+    }
+
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.push((name.clone(), percent_decode(parts.next()?, false)?));
+                }
+                Segment::Rest(name) => {
+                    let rest = parts.by_ref().collect::<Vec<_>>().join("/");
+
+                    params.push((name.clone(), percent_decode(&rest, false)?));
+                }
+            }
+        }
+
+        parts.next().is_none().then_some(params)
+    }
+}
+
+type RouteHandler<'a> = Box<
+    dyn for<'r> Fn(
+            Request<&mut EspHttpConnection<'r>>,
+            &RouteParams,
+        ) -> Result<(), Box<dyn RouteError>>
+        + Send
+        + Sync
+        + 'a,
+>;
+
+/// A type-erased handler error, which can be checked for being a `ParamError`
+trait RouteError: Debug {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<E> RouteError for E
+where
+    E: Debug + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct Route<'a> {
+    pattern: Pattern,
+    method: Method,
+    handler: RouteHandler<'a>,
+}
+
+pub struct Router<'a> {
+    prefix: String,
+    routes: Vec<(String, Method, RouteHandler<'a>)>,
+}
+
+impl<'a> Router<'a> {
+    /// Creates a router for all requests below `prefix` (e.g. `/api`, or `/` for all requests).
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Adds a route for `pattern` (relative to the prefix of the router) and `method`.
+    pub fn route<F, E>(mut self, pattern: &str, method: Method, handler: F) -> Self
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>, &RouteParams) -> Result<(), E>
+            + Send
+            + Sync
+            + 'a,
+        E: Debug + 'static,
+    {
+        self.routes.push((
+            pattern.to_owned(),
+            method,
+            Box::new(move |request, params| {
+                handler(request, params).map_err(|err| Box::new(err) as Box<dyn RouteError>)
+            }),
+        ));
+
+        self
+    }
+
+    pub fn get<F, E>(self, pattern: &str, handler: F) -> Self
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>, &RouteParams) -> Result<(), E>
+            + Send
+            + Sync
+            + 'a,
+        E: Debug + 'static,
+    {
+        self.route(pattern, Method::Get, handler)
+    }
+
+    pub fn post<F, E>(self, pattern: &str, handler: F) -> Self
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>, &RouteParams) -> Result<(), E>
+            + Send
+            + Sync
+            + 'a,
+        E: Debug + 'static,
+    {
+        self.route(pattern, Method::Post, handler)
+    }
+
+    pub fn put<F, E>(self, pattern: &str, handler: F) -> Self
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>, &RouteParams) -> Result<(), E>
+            + Send
+            + Sync
+            + 'a,
+        E: Debug + 'static,
+    {
+        self.route(pattern, Method::Put, handler)
+    }
+
+    pub fn patch<F, E>(self, pattern: &str, handler: F) -> Self
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>, &RouteParams) -> Result<(), E>
+            + Send
+            + Sync
+            + 'a,
+        E: Debug + 'static,
+    {
+        self.route(pattern, Method::Patch, handler)
+    }
+
+    pub fn delete<F, E>(self, pattern: &str, handler: F) -> Self
+    where
+        F: for<'r> Fn(Request<&mut EspHttpConnection<'r>>, &RouteParams) -> Result<(), E>
+            + Send
+            + Sync
+            + 'a,
+        E: Debug + 'static,
+    {
+        self.route(pattern, Method::Delete, handler)
+    }
+}
+
+impl<'a> EspHttpTraversableChain<'a> for Router<'a> {
+    fn accept(self, server: &mut EspHttpServer<'a>) -> Result<(), EspError> {
+        let mut routes = Vec::new();
+        let mut methods: Vec<Method> = Vec::new();
+
+        for (pattern, method, handler) in self.routes {
+            routes.push(Route {
+                pattern: Pattern::parse(&pattern)?,
+                method,
+                handler,
+            });
+
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+
+        let dispatcher = RouterHandler(Arc::new(Routes {
+            prefix: self.prefix,
+            routes,
+        }));
+
+        let uri = format!("{}/*", dispatcher.0.prefix);
+
+        for method in methods {
+            server.handler(&uri, method, dispatcher.clone())?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Routes<'a> {
+    prefix: String,
+    routes: Vec<Route<'a>>,
+}
+
+struct RouterHandler<'a>(Arc<Routes<'a>>);
+
+impl<'a> Clone for RouterHandler<'a> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<'a, 'r> Handler<EspHttpConnection<'r>> for RouterHandler<'a> {
+    type Error = Box<dyn Debug>;
+
+    fn handle(&self, connection: &mut EspHttpConnection<'r>) -> Result<(), Self::Error> {
+        let method = connection.method();
+
+        let uri = connection.uri();
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+
+        let Some(path) = path.strip_prefix(self.0.prefix.as_str()) else {
+            return Self::respond(connection, 404, "Not Found", &[]);
+        };
+
+        let mut allowed = Vec::new();
+        let mut matched = None;
+
+        for route in &self.0.routes {
+            if let Some(params) = route.pattern.matches(path) {
+                if route.method == method {
+                    matched = Some((route, params));
+                    break;
+                }
+
+                allowed.push(route.method);
+            }
+        }
+
+        let Some((route, path)) = matched else {
+            if allowed.is_empty() {
+                return Self::respond(connection, 404, "Not Found", &[]);
+            }
+
+            let allowed = allowed
+                .iter()
+                .map(|method| method_str(*method))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            return Self::respond(
+                connection,
+                405,
+                "Method Not Allowed",
+                &[("Allow", allowed.as_str())],
+            );
+        };
+
+        let params = RouteParams {
+            path,
+            query: UrlEncoded::parse(query),
+            error: RefCell::new(None),
+        };
+
+        match (route.handler)(Request::wrap(&mut *connection), &params) {
+            Ok(()) => Ok(()),
+            Err(err) => match params
+                .error
+                .into_inner()
+                .or_else(|| (*err).as_any().downcast_ref::<ParamError>().cloned())
+            {
+                Some(param_err) if !connection.is_response_initiated() => {
+                    debug!("Rejecting request to {}: {}", connection.uri(), param_err);
+
+                    Self::respond(connection, 400, "Bad Request", &[])
+                }
+                _ => Err(Box::new(err)),
+            },
+        }
+    }
+}
+
+impl<'a> RouterHandler<'a> {
+    fn respond(
+        connection: &mut EspHttpConnection<'_>,
+        status: u16,
+        message: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<(), Box<dyn Debug>> {
+        connection
+            .initiate_response(status, Some(message), headers)
+            .map_err(|err| Box::new(err) as Box<dyn Debug>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        Pattern::parse(pattern).unwrap().matches(path)
+    }
+
+    fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_patterns() {
+        assert_eq!(
+            Pattern::parse("/devices/{id}/{*rest}").unwrap().0,
+            [
+                Segment::Literal("devices".to_owned()),
+                Segment::Param("id".to_owned()),
+                Segment::Rest("rest".to_owned()),
+            ]
+        );
+        assert_eq!(Pattern::parse("/").unwrap().0, []);
+        assert_eq!(
+            Pattern::parse("//a//b/").unwrap().0,
+            [
+                Segment::Literal("a".to_owned()),
+                Segment::Literal("b".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(Pattern::parse("/{*rest}/more").is_err());
+        assert!(Pattern::parse("/a{id}").is_err());
+        assert!(Pattern::parse("/{id").is_err());
+        assert!(Pattern::parse("/id}").is_err());
+    }
+
+    #[test]
+    fn matches_literals_and_params() {
+        assert_eq!(matches("/devices", "/devices"), Some(params(&[])));
+        assert_eq!(matches("/devices", "/devices/"), Some(params(&[])));
+        assert_eq!(matches("/devices", "/device"), None);
+        assert_eq!(matches("/devices", "/devices/1"), None);
+        assert_eq!(
+            matches("/devices/{id}/channels/{ch}", "/devices/7/channels/2"),
+            Some(params(&[("id", "7"), ("ch", "2")]))
+        );
+        assert_eq!(matches("/devices/{id}", "/devices"), None);
+        assert_eq!(
+            matches("/files/{name}", "/files/a%20b"),
+            Some(params(&[("name", "a b")]))
+        );
+    }
+
+    #[test]
+    fn matches_rest() {
+        assert_eq!(
+            matches("/files/{*path}", "/files/a/b/c.txt"),
+            Some(params(&[("path", "a/b/c.txt")]))
+        );
+        assert_eq!(
+            matches("/files/{*path}", "/files"),
+            Some(params(&[("path", "")]))
+        );
+    }
+}
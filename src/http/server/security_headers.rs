@@ -0,0 +1,122 @@
+//! Security-related response headers
+//!
+//! `SecurityHeaders` is a `Middleware` which adds headers like `X-Content-Type-Options` and
+//! `Content-Security-Policy` to the responses of the handlers it wraps. Headers passed by a
+//! handler to `initiate_response` take precedence over the ones of the middleware.
+//!
+//! Note that the added headers count against the `max_resp_headers` limit of the server
+//! `Configuration` (8 by default), which may need to be raised when adding more headers.
+//!
+//! ```
+//! use esp_idf_svc::http::server::security_headers::SecurityHeaders;
+//! use esp_idf_svc::http::server::{fn_handler, Middleware};
+//! use esp_idf_svc::http::Method;
+//!
+//! let headers = SecurityHeaders::new().content_security_policy("default-src 'self'");
+//!
+//! server.handler("/", Method::Get, headers.compose(fn_handler(index)))?;
+//! ```
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{EspHttpConnection, Handler, Middleware};
+
+/// The headers added to responses, see the module documentation
+#[derive(Clone, Debug)]
+pub struct SecurityHeaders(Vec<(String, String)>);
+
+impl SecurityHeaders {
+    /// Creates the middleware with the headers
+    /// - `X-Content-Type-Options: nosniff`
+    /// - `X-Frame-Options: DENY`
+    /// - `Referrer-Policy: no-referrer`
+    pub fn new() -> Self {
+        Self::empty()
+            .header("X-Content-Type-Options", "nosniff")
+            .header("X-Frame-Options", "DENY")
+            .header("Referrer-Policy", "no-referrer")
+    }
+
+    /// Creates the middleware without any headers.
+    pub const fn empty() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Sets the header `name`, replacing a previously set value.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self = self.remove(name);
+        self.0.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Removes the header `name`, e.g. one of the default headers.
+    pub fn remove(mut self, name: &str) -> Self {
+        self.0
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        self
+    }
+
+    pub fn content_security_policy(self, policy: &str) -> Self {
+        self.header("Content-Security-Policy", policy)
+    }
+
+    /// Sets `Strict-Transport-Security`, which is only honored by clients for HTTPS servers.
+    pub fn strict_transport_security(self, max_age: Duration, include_subdomains: bool) -> Self {
+        let value = if include_subdomains {
+            format!("max-age={}; includeSubDomains", max_age.as_secs())
+        } else {
+            format!("max-age={}", max_age.as_secs())
+        };
+
+        self.header("Strict-Transport-Security", &value)
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'r, H> Middleware<EspHttpConnection<'r>, H> for SecurityHeaders
+where
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = H::Error;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        for (name, value) in &self.0 {
+            connection.add_response_header(name, value);
+        }
+
+        handler.handle(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_and_removes_headers() {
+        let headers = SecurityHeaders::new()
+            .header("x-frame-options", "SAMEORIGIN")
+            .remove("Referrer-Policy");
+
+        assert_eq!(
+            headers.0,
+            [
+                ("X-Content-Type-Options".to_owned(), "nosniff".to_owned()),
+                ("x-frame-options".to_owned(), "SAMEORIGIN".to_owned()),
+            ]
+        );
+    }
+}
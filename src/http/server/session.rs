@@ -0,0 +1,618 @@
+//! Server-side sessions
+//!
+//! `SessionStore` keeps per-session key/value data in memory and identifies the session of a
+//! request by a signed, `HttpOnly` session cookie. Sessions expire after not being used for the
+//! store's timeout, which is usually the `session_timeout` of the server `Configuration`.
+//!
+//! Sessions are created explicitly - e.g. after a successful login - with `SessionStore::create`,
+//! which issues the cookie. As a `Middleware`, the store extends the lifetime of the session of
+//! each request it passes on to the wrapped handler, and lets handlers access a session created
+//! earlier in the same request:
+//!
+//! ```
+//! use esp_idf_svc::http::server::session::SessionStore;
+//! use esp_idf_svc::http::server::{fn_handler, Configuration, Middleware};
+//! use esp_idf_svc::http::Method;
+//!
+//! let conf = Configuration::default();
+//! let sessions = SessionStore::new(conf.session_timeout, conf.max_sessions);
+//!
+//! server.handler("/login", Method::Post, {
+//!     let sessions = sessions.clone();
+//!
+//!     fn_handler(move |mut request| {
+//!         // Check the credentials...
+//!
+//!         sessions.create(request.connection()).set("user", "admin");
+//!
+//!         request.into_ok_response()?.write_all(b"Welcome")
+//!     })
+//! })?;
+//!
+//! server.handler("/whoami", Method::Get, {
+//!     let store = sessions.clone();
+//!
+//!     sessions.compose(fn_handler(move |request| {
+//!         let user = store
+//!             .session(request.connection())
+//!             .and_then(|session| session.get("user"));
+//!
+//!         request
+//!             .into_ok_response()?
+//!             .write_all(user.as_deref().unwrap_or("nobody").as_bytes())
+//!     }))
+//! })?;
+//! ```
+//!
+//! With `SessionStore::persistent`, the sessions are also saved to NVS whenever they are created
+//! or destroyed, and when a `Session` whose data was changed is dropped, so that logins survive a
+//! reboot. The expiry of persistent sessions is
+//! based on the system time, so they require the time to be set (e.g. with SNTP) after booting,
+//! whereas in-memory sessions expire based on the time since boot, which is not affected by
+//! changes of the system time.
+//!
+//! Note that the session cookie is added with `EspHttpConnection::add_response_header`, so it is
+//! not sent if the handler passes its own `Set-Cookie` header to `initiate_response`.
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use crate::private::hash::{constant_time_eq, Sha256};
+use crate::private::hex;
+use crate::private::mutex::Mutex;
+use crate::private::random::random;
+use crate::private::time::uptime_secs;
+use crate::systime::EspSystemTime;
+
+use super::{EspHttpConnection, Handler, Middleware};
+
+#[cfg(esp_idf_comp_nvs_flash_enabled)]
+use crate::nvs::{EspNvs, NvsPartitionId};
+
+const DEFAULT_COOKIE_NAME: &str = "session";
+const ID_LEN: usize = 16;
+const SECRET_LEN: usize = 32;
+
+#[cfg(esp_idf_comp_nvs_flash_enabled)]
+const NVS_SECRET_KEY: &str = "secret";
+#[cfg(esp_idf_comp_nvs_flash_enabled)]
+const NVS_SESSIONS_KEY: &str = "sessions";
+
+type Persist = Box<dyn FnMut(&[u8]) -> Result<(), EspError> + Send>;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct SessionData {
+    expires: u64,
+    values: BTreeMap<String, String>,
+}
+
+struct State {
+    sessions: BTreeMap<String, SessionData>,
+    persist: Option<Persist>,
+    /// `true` if the sessions were changed since they were saved
+    dirty: bool,
+}
+
+impl State {
+    fn get_mut(&mut self, id: &str, now: u64) -> Option<&mut SessionData> {
+        self.sessions
+            .get_mut(id)
+            .filter(|session| session.expires > now)
+    }
+
+    fn save(&mut self) {
+        if !core::mem::take(&mut self.dirty) {
+            return;
+        }
+
+        if let Some(persist) = self.persist.as_mut() {
+            if let Err(err) = persist(&encode(&self.sessions)) {
+                warn!("Saving the HTTP sessions failed: {}", err);
+            }
+        }
+    }
+}
+
+/// The sessions of a server, see the module documentation
+#[derive(Clone)]
+pub struct SessionStore {
+    state: Arc<Mutex<State>>,
+    secret: [u8; SECRET_LEN],
+    timeout: Duration,
+    max_sessions: usize,
+    cookie_name: String,
+    secure: bool,
+    persistent: bool,
+}
+
+impl SessionStore {
+    /// Creates an in-memory store, whose sessions expire after not being used for `timeout`.
+    ///
+    /// When `max_sessions` sessions exist, creating a new one removes the one expiring first.
+    pub fn new(timeout: Duration, max_sessions: usize) -> Self {
+        Self::with_state(timeout, max_sessions, random(), BTreeMap::new(), None)
+    }
+
+    /// Creates a store which saves its sessions - and the key signing the session cookies - to the
+    /// given NVS namespace, and loads the sessions saved there which have not expired yet.
+    #[cfg(esp_idf_comp_nvs_flash_enabled)]
+    pub fn persistent<T>(
+        timeout: Duration,
+        max_sessions: usize,
+        mut nvs: EspNvs<T>,
+    ) -> Result<Self, EspError>
+    where
+        T: NvsPartitionId + 'static,
+    {
+        let mut secret = [0; SECRET_LEN];
+
+        if nvs.get_blob(NVS_SECRET_KEY, &mut secret)?.map(<[u8]>::len) != Some(SECRET_LEN) {
+            secret = random();
+            nvs.set_blob(NVS_SECRET_KEY, &secret)?;
+        }
+
+        let mut sessions = BTreeMap::new();
+
+        if let Some(len) = nvs.blob_len(NVS_SESSIONS_KEY)? {
+            let mut data = alloc::vec![0; len];
+
+            if let Some(data) = nvs.get_blob(NVS_SESSIONS_KEY, &mut data)? {
+                match decode(data) {
+                    Some(loaded) => sessions = loaded,
+                    None => warn!("Ignoring malformed HTTP sessions in NVS"),
+                }
+            }
+        }
+
+        let now = EspSystemTime.now().as_secs();
+        sessions.retain(|_, session: &mut SessionData| session.expires > now);
+
+        info!("Loaded {} HTTP sessions from NVS", sessions.len());
+
+        Ok(Self::with_state(
+            timeout,
+            max_sessions,
+            secret,
+            sessions,
+            Some(Box::new(move |data: &[u8]| {
+                nvs.set_blob(NVS_SESSIONS_KEY, data)
+            })),
+        ))
+    }
+
+    fn with_state(
+        timeout: Duration,
+        max_sessions: usize,
+        secret: [u8; SECRET_LEN],
+        sessions: BTreeMap<String, SessionData>,
+        persist: Option<Persist>,
+    ) -> Self {
+        Self {
+            persistent: persist.is_some(),
+            state: Arc::new(Mutex::new(State {
+                sessions,
+                persist,
+                dirty: false,
+            })),
+            secret,
+            timeout,
+            max_sessions,
+            cookie_name: DEFAULT_COOKIE_NAME.into(),
+            secure: false,
+        }
+    }
+
+    /// Sets the name of the session cookie, `session` by default.
+    pub fn cookie_name(mut self, cookie_name: &str) -> Self {
+        self.cookie_name = cookie_name.into();
+        self
+    }
+
+    /// Marks the session cookie as `Secure`, so that it is only sent over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Returns the session of the request, if it has a valid session cookie or the
+    /// session was created while handling it.
+    pub fn session(&self, connection: &EspHttpConnection<'_>) -> Option<Session> {
+        let id = self.session_id(connection)?;
+
+        self.state
+            .lock()
+            .get_mut(&id, self.now())
+            .is_some()
+            .then(|| Session {
+                store: self.clone(),
+                id,
+            })
+    }
+
+    /// Creates a new, empty session and adds its cookie to the response.
+    ///
+    /// The current session of the request, if any, is destroyed, so that a session
+    /// created by a login cannot have been planted by an attacker.
+    pub fn create(&self, connection: &mut EspHttpConnection<'_>) -> Session {
+        self.issue(connection, BTreeMap::new())
+    }
+
+    /// Moves the data of the current session of the request to a new session with
+    /// a new cookie, or creates an empty one.
+    pub fn regenerate(&self, connection: &mut EspHttpConnection<'_>) -> Session {
+        let values = self
+            .session_id(connection)
+            .and_then(|id| self.state.lock().sessions.remove(&id))
+            .filter(|session| session.expires > self.now())
+            .map(|session| session.values)
+            .unwrap_or_default();
+
+        self.issue(connection, values)
+    }
+
+    /// Destroys the current session of the request and removes its cookie from the client.
+    pub fn destroy(&self, connection: &mut EspHttpConnection<'_>) {
+        if let Some(id) = self.session_id(connection) {
+            let mut state = self.state.lock();
+
+            if state.sessions.remove(&id).is_some() {
+                state.dirty = true;
+                state.save();
+            }
+        }
+
+        connection.session_id = None;
+        connection.add_response_header(
+            "Set-Cookie",
+            &format!(
+                "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax",
+                self.cookie_name
+            ),
+        );
+    }
+
+    /// Returns the number of sessions, including expired ones which have not been removed yet.
+    pub fn len(&self) -> usize {
+        self.state.lock().sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn issue(
+        &self,
+        connection: &mut EspHttpConnection<'_>,
+        values: BTreeMap<String, String>,
+    ) -> Session {
+        let id = hex::encode(&random::<ID_LEN>());
+        let now = self.now();
+
+        {
+            let mut state = self.state.lock();
+
+            if let Some(current) = self.session_id(connection) {
+                state.sessions.remove(&current);
+            }
+
+            state.sessions.retain(|_, session| session.expires > now);
+
+            while !state.sessions.is_empty() && state.sessions.len() >= self.max_sessions {
+                let oldest = state
+                    .sessions
+                    .iter()
+                    .min_by_key(|(_, session)| session.expires)
+                    .map(|(id, _)| id.clone())
+                    .unwrap();
+
+                debug!("Too many HTTP sessions, removing the one expiring first");
+
+                state.sessions.remove(&oldest);
+            }
+
+            state.sessions.insert(
+                id.clone(),
+                SessionData {
+                    expires: now + self.timeout.as_secs(),
+                    values,
+                },
+            );
+
+            state.dirty = true;
+            state.save();
+        }
+
+        connection.add_response_header(
+            "Set-Cookie",
+            &format!(
+                "{}={}.{}; Path=/; HttpOnly; SameSite=Lax{}",
+                self.cookie_name,
+                id,
+                self.sign(&id),
+                if self.secure { "; Secure" } else { "" }
+            ),
+        );
+
+        connection.session_id = Some(id.clone());
+
+        Session {
+            store: self.clone(),
+            id,
+        }
+    }
+
+    /// Returns the id of the session created in this request, or the one of a correctly
+    /// signed session cookie.
+    fn session_id(&self, connection: &EspHttpConnection<'_>) -> Option<String> {
+        if let Some(id) = &connection.session_id {
+            return Some(id.clone());
+        }
+
+        let (id, signature) = connection
+            .header("Cookie")
+            .and_then(|cookies| cookie(cookies, &self.cookie_name))?
+            .split_once('.')?;
+
+        (id.len() == ID_LEN * 2 && constant_time_eq(signature.as_bytes(), self.sign(id).as_bytes()))
+            .then(|| id.into())
+    }
+
+    fn sign(&self, id: &str) -> String {
+        hex::encode(&Sha256::hmac(&self.secret, id.as_bytes()))
+    }
+
+    /// Returns the current time in seconds: the system time for persistent stores, whose
+    /// sessions outlive a reboot, and the monotonic time since boot otherwise.
+    fn now(&self) -> u64 {
+        if self.persistent {
+            EspSystemTime.now().as_secs()
+        } else {
+            uptime_secs()
+        }
+    }
+}
+
+impl<'r, H> Middleware<EspHttpConnection<'r>, H> for SessionStore
+where
+    H: Handler<EspHttpConnection<'r>>,
+{
+    type Error = H::Error;
+
+    fn handle(
+        &self,
+        connection: &mut EspHttpConnection<'r>,
+        handler: &H,
+    ) -> Result<(), Self::Error> {
+        if let Some(id) = self.session_id(connection) {
+            let now = self.now();
+
+            // Only extending the lifetime in memory, as saving each request would wear out the flash
+            if let Some(session) = self.state.lock().get_mut(&id, now) {
+                session.expires = now + self.timeout.as_secs();
+                connection.session_id = Some(id);
+            }
+        }
+
+        handler.handle(connection)
+    }
+}
+
+/// The data of a session, see `SessionStore::session`
+///
+/// Once the session has expired or was destroyed, reading returns `None` and writing
+/// has no effect.
+///
+/// With a persistent store, changes are saved to NVS once - when the `Session` is dropped -
+/// rather than with each `set`, `remove` or `clear`.
+#[derive(Clone)]
+pub struct Session {
+    store: SessionStore,
+    id: String,
+}
+
+impl Session {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.store
+            .state
+            .lock()
+            .get_mut(&self.id, self.store.now())
+            .and_then(|session| session.values.get(key).cloned())
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        self.update(|values| {
+            values.insert(key.into(), value.into());
+        });
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.update(|values| {
+            values.remove(key);
+        });
+    }
+
+    pub fn clear(&self) {
+        self.update(BTreeMap::clear);
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut BTreeMap<String, String>),
+    {
+        let mut state = self.store.state.lock();
+
+        if let Some(session) = state.get_mut(&self.id, self.store.now()) {
+            f(&mut session.values);
+
+            state.dirty = true;
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.store.state.lock().save();
+    }
+}
+
+fn cookie<'a>(cookies: &'a str, name: &str) -> Option<&'a str> {
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+fn encode(sessions: &BTreeMap<String, SessionData>) -> Vec<u8> {
+    fn push_str(data: &mut Vec<u8>, s: &str) {
+        data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        data.extend_from_slice(s.as_bytes());
+    }
+
+    let mut data = Vec::new();
+
+    for (id, session) in sessions {
+        push_str(&mut data, id);
+        data.extend_from_slice(&session.expires.to_le_bytes());
+        data.extend_from_slice(&(session.values.len() as u32).to_le_bytes());
+
+        for (key, value) in &session.values {
+            push_str(&mut data, key);
+            push_str(&mut data, value);
+        }
+    }
+
+    data
+}
+
+fn decode(mut data: &[u8]) -> Option<BTreeMap<String, SessionData>> {
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        (data.len() >= len).then(|| {
+            let (taken, rest) = data.split_at(len);
+            *data = rest;
+
+            taken
+        })
+    }
+
+    fn take_u32(data: &mut &[u8]) -> Option<u32> {
+        Some(u32::from_le_bytes(take(data, 4)?.try_into().ok()?))
+    }
+
+    fn take_str(data: &mut &[u8]) -> Option<String> {
+        let len = take_u32(data)? as usize;
+
+        core::str::from_utf8(take(data, len)?).ok().map(Into::into)
+    }
+
+    let mut sessions = BTreeMap::new();
+
+    while !data.is_empty() {
+        let id = take_str(&mut data)?;
+        let expires = u64::from_le_bytes(take(&mut data, 8)?.try_into().ok()?);
+
+        let mut values = BTreeMap::new();
+
+        for _ in 0..take_u32(&mut data)? {
+            let key = take_str(&mut data)?;
+            let value = take_str(&mut data)?;
+
+            values.insert(key, value);
+        }
+
+        sessions.insert(id, SessionData { expires, values });
+    }
+
+    Some(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let mut sessions = BTreeMap::new();
+
+        sessions.insert("0123".into(), SessionData::default());
+        sessions.insert(
+            "4567".into(),
+            SessionData {
+                expires: 1_700_000_000,
+                values: [("user".into(), "admin".into()), ("lang".into(), "".into())].into(),
+            },
+        );
+
+        let data = encode(&sessions);
+
+        assert_eq!(decode(&data), Some(sessions));
+        assert_eq!(decode(&data[..data.len() - 1]), None);
+        assert_eq!(decode(&[]), Some(BTreeMap::new()));
+    }
+
+    #[test]
+    fn saves_changed_sessions_once() {
+        let saves = Arc::new(Mutex::new(0));
+
+        let store = SessionStore::with_state(
+            Duration::from_secs(60),
+            4,
+            [0; SECRET_LEN],
+            [(
+                "0123".into(),
+                SessionData {
+                    expires: u64::MAX,
+                    values: BTreeMap::new(),
+                },
+            )]
+            .into(),
+            Some(Box::new({
+                let saves = saves.clone();
+                move |_: &[u8]| {
+                    *saves.lock() += 1;
+                    Ok(())
+                }
+            })),
+        );
+
+        let session = || Session {
+            store: store.clone(),
+            id: "0123".into(),
+        };
+
+        let changed = session();
+        changed.set("user", "admin");
+        changed.set("lang", "en");
+        changed.remove("lang");
+
+        assert_eq!(*saves.lock(), 0);
+
+        drop(changed);
+        assert_eq!(*saves.lock(), 1);
+
+        assert_eq!(session().get("user").as_deref(), Some("admin"));
+        assert_eq!(*saves.lock(), 1);
+    }
+
+    #[test]
+    fn cookies() {
+        let cookies = "theme=dark; session=\"abc.def\";other=1";
+
+        assert_eq!(cookie(cookies, "session"), Some("abc.def"));
+        assert_eq!(cookie(cookies, "other"), Some("1"));
+        assert_eq!(cookie(cookies, "missing"), None);
+    }
+}
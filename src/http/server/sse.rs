@@ -0,0 +1,428 @@
+//! Server-Sent Events (SSE)
+//!
+//! `EspHttpConnection::start_sse` answers a request with an open-ended `text/event-stream`
+//! response and returns an `EspHttpSseSender`, which - like `EspHttpWsDetachedSender` - can be
+//! moved to another thread to send events to the client after the handler has returned.
+//! In contrast to Websockets, this does not need `CONFIG_HTTPD_WS_SUPPORT`, and browsers
+//! reconnect automatically (with the `EventSource` API).
+//!
+//! `SseBroadcaster` sends events to all subscribed clients. It assigns increasing ids to the
+//! events and keeps the most recent ones, so that clients reconnecting with a `Last-Event-ID`
+//! header receive the events they missed:
+//!
+//! ```
+//! use esp_idf_svc::http::server::sse::{SseBroadcaster, SseEvent};
+//! use esp_idf_svc::http::Method;
+//!
+//! let broadcaster = SseBroadcaster::new(16);
+//!
+//! server.handler("/events", Method::Get, broadcaster.clone())?;
+//!
+//! loop {
+//!     let reading = sensor.read()?;
+//!
+//!     broadcaster.broadcast(&SseEvent::new(&format!("{reading}")).event("temperature"));
+//!
+//!     FreeRtos::delay_ms(1000);
+//! }
+//! ```
+//!
+//! Note that senders block until the event is handed over to the socket by the HTTP server task,
+//! so they must not be used from within a handler. Also note that each open stream occupies one
+//! of the `max_open_sockets` of the server.
+use core::ffi;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use crate::private::mutex::{Condvar, Mutex};
+
+use super::{EspHttpConnection, Handler, OPEN_SESSIONS};
+
+/// An event, see https://html.spec.whatwg.org/multipage/server-sent-events.html
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SseEvent<'a> {
+    pub event: Option<&'a str>,
+    pub id: Option<&'a str>,
+    pub data: &'a str,
+    pub retry: Option<Duration>,
+}
+
+impl<'a> SseEvent<'a> {
+    pub const fn new(data: &'a str) -> Self {
+        Self {
+            event: None,
+            id: None,
+            data,
+            retry: None,
+        }
+    }
+
+    /// Sets the event type, which is `message` if not set.
+    pub const fn event(mut self, event: &'a str) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    pub const fn id(mut self, id: &'a str) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Sets the delay after which the client reconnects if the stream is closed.
+    pub const fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Formats the event as it is sent in the stream.
+    ///
+    /// Multi-line data (with `\r\n`, `\r` or `\n` line breaks) is sent as multiple `data:` fields;
+    /// line breaks in the other fields are removed.
+    pub fn format(&self) -> String {
+        let mut frame = String::with_capacity(self.data.len() + 16);
+
+        if let Some(event) = self.event {
+            write_field(&mut frame, "event", event);
+        }
+
+        if let Some(id) = self.id {
+            write_field(&mut frame, "id", id);
+        }
+
+        if let Some(retry) = self.retry {
+            writeln!(&mut frame, "retry: {}", retry.as_millis()).unwrap();
+        }
+
+        // Any of `\r\n`, `\r` and `\n` ends a line in the stream
+        for line in self
+            .data
+            .split("\r\n")
+            .flat_map(|line| line.split(['\r', '\n']))
+        {
+            writeln!(&mut frame, "data: {line}").unwrap();
+        }
+
+        frame.push('\n');
+
+        frame
+    }
+}
+
+fn write_field(frame: &mut String, name: &str, value: &str) {
+    frame.push_str(name);
+    frame.push_str(": ");
+    frame.extend(value.chars().filter(|c| *c != '\r' && *c != '\n'));
+    frame.push('\n');
+}
+
+impl<'a> EspHttpConnection<'a> {
+    /// Answers the request with a `text/event-stream` response which is kept open after the
+    /// handler returns, and returns a sender for the events.
+    ///
+    /// Headers added with `add_response_header` are sent with the response.
+    pub fn start_sse(&mut self) -> Result<EspHttpSseSender, EspError> {
+        self.assert_request();
+
+        let mut head = String::from(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n",
+        );
+
+        for (name, value) in &self.extra_response_headers {
+            write!(&mut head, "{name}: {value}\r\n").unwrap();
+        }
+
+        head.push_str("\r\n");
+
+        let sd = self.request.0.handle;
+        let fd = unsafe { httpd_req_to_sockfd(self.request.0) };
+
+        socket_send(sd, fd, head.as_bytes())?;
+
+        self.headers = None;
+        self.detached = true;
+
+        let closed = OPEN_SESSIONS
+            .lock()
+            .entry((sd as u32, fd))
+            .or_insert_with(|| Arc::new(AtomicBool::new(false)))
+            .clone();
+
+        Ok(EspHttpSseSender { sd, fd, closed })
+    }
+}
+
+/// Sends events to a client from outside of the HTTP server task
+pub struct EspHttpSseSender {
+    sd: httpd_handle_t,
+    fd: ffi::c_int,
+    closed: Arc<AtomicBool>,
+}
+
+impl EspHttpSseSender {
+    pub fn session(&self) -> i32 {
+        self.fd
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn send(&mut self, event: &SseEvent<'_>) -> Result<(), EspError> {
+        self.send_raw(event.format().as_bytes())
+    }
+
+    /// Sends a comment, which is ignored by clients but keeps the connection alive
+    /// through proxies and detects closed connections.
+    pub fn send_comment(&mut self, comment: &str) -> Result<(), EspError> {
+        let mut frame = String::with_capacity(comment.len() + 3);
+        write_field(&mut frame, "", comment);
+        frame.push('\n');
+
+        self.send_raw(frame.as_bytes())
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<(), EspError> {
+        if self.is_closed() {
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        let request = SendRequest {
+            sd: self.sd,
+            fd: self.fd,
+            closed: self.closed.clone(),
+            data: data.as_ptr(),
+            len: data.len(),
+            error_code: Mutex::new(None),
+            condvar: Condvar::new(),
+        };
+
+        esp!(unsafe {
+            httpd_queue_work(self.sd, Some(Self::enqueue), &request as *const _ as *mut _)
+        })?;
+
+        let mut guard = request.error_code.lock();
+
+        while guard.is_none() {
+            guard = request.condvar.wait(guard);
+        }
+
+        esp!((*guard).unwrap())
+    }
+
+    extern "C" fn enqueue(arg: *mut ffi::c_void) {
+        let request = unsafe { (arg as *const SendRequest).as_ref().unwrap() };
+
+        let ret = if !request.closed.load(Ordering::SeqCst) {
+            let data = unsafe { core::slice::from_raw_parts(request.data, request.len) };
+
+            match socket_send(request.sd, request.fd, data) {
+                Ok(()) => ESP_OK,
+                Err(err) => {
+                    // Have the server close the session, so that all senders see it as closed
+                    unsafe { httpd_sess_trigger_close(request.sd, request.fd) };
+
+                    err.code()
+                }
+            }
+        } else {
+            ESP_FAIL
+        };
+
+        let mut guard = request.error_code.lock();
+
+        *guard = Some(ret);
+
+        request.condvar.notify_all();
+    }
+}
+
+unsafe impl Send for EspHttpSseSender {}
+
+impl Clone for EspHttpSseSender {
+    fn clone(&self) -> Self {
+        Self {
+            sd: self.sd,
+            fd: self.fd,
+            closed: self.closed.clone(),
+        }
+    }
+}
+
+struct SendRequest {
+    sd: httpd_handle_t,
+    fd: ffi::c_int,
+    closed: Arc<AtomicBool>,
+    data: *const u8,
+    len: usize,
+    error_code: Mutex<Option<esp_err_t>>,
+    condvar: Condvar,
+}
+
+struct BroadcasterState {
+    subscribers: Vec<EspHttpSseSender>,
+    history: VecDeque<(u64, String)>,
+    next_id: u64,
+}
+
+/// Sends events to all subscribed clients, see the module documentation
+///
+/// As a `Handler`, it subscribes the clients requesting its URI.
+#[derive(Clone)]
+pub struct SseBroadcaster {
+    state: Arc<Mutex<BroadcasterState>>,
+    history_len: usize,
+}
+
+impl SseBroadcaster {
+    /// Creates a broadcaster keeping the last `history_len` events for replaying them
+    /// to reconnecting clients.
+    pub fn new(history_len: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BroadcasterState {
+                subscribers: Vec::new(),
+                history: VecDeque::with_capacity(history_len),
+                next_id: 1,
+            })),
+            history_len,
+        }
+    }
+
+    /// Starts an SSE stream on `connection` and adds it to the subscribers.
+    ///
+    /// If the request has a `Last-Event-ID` header, the events after that one which are
+    /// still in the history are sent first.
+    pub fn subscribe(&self, connection: &mut EspHttpConnection<'_>) -> Result<(), EspError> {
+        let last_id = connection
+            .header("Last-Event-ID")
+            .and_then(|id| id.trim().parse::<u64>().ok());
+
+        let mut state = self.state.lock();
+
+        let sender = connection.start_sse()?;
+
+        if let Some(last_id) = last_id {
+            // Still in the HTTP server task, so sending directly
+            for (_, frame) in state.history.iter().filter(|(id, _)| *id > last_id) {
+                socket_send(sender.sd, sender.fd, frame.as_bytes())?;
+            }
+        }
+
+        state.subscribers.push(sender);
+
+        Ok(())
+    }
+
+    /// Sends `event` to all subscribers and returns the id assigned to it
+    /// (any id set in `event` is replaced).
+    ///
+    /// Subscribers which have disconnected are removed.
+    pub fn broadcast(&self, event: &SseEvent<'_>) -> u64 {
+        let (id, frame, mut subscribers) = {
+            let mut state = self.state.lock();
+
+            let id = state.next_id;
+            state.next_id += 1;
+
+            let id_str = format!("{id}");
+            let frame = SseEvent {
+                id: Some(&id_str),
+                ..*event
+            }
+            .format();
+
+            if self.history_len > 0 {
+                if state.history.len() == self.history_len {
+                    state.history.pop_front();
+                }
+
+                state.history.push_back((id, frame.clone()));
+            }
+
+            (id, frame, state.subscribers.clone())
+        };
+
+        // Not sending with the lock held, as `subscribe` needs it in the HTTP server task
+        let mut failed = Vec::new();
+
+        for subscriber in &mut subscribers {
+            if let Err(err) = subscriber.send_raw(frame.as_bytes()) {
+                debug!("Dropping SSE subscriber {}: {}", subscriber.session(), err);
+
+                failed.push(subscriber.closed.clone());
+            }
+        }
+
+        self.state.lock().subscribers.retain(|subscriber| {
+            !subscriber.is_closed()
+                && !failed
+                    .iter()
+                    .any(|closed| Arc::ptr_eq(closed, &subscriber.closed))
+        });
+
+        id
+    }
+
+    /// Returns the number of subscribers, including ones which have disconnected since the last broadcast.
+    pub fn subscribers(&self) -> usize {
+        self.state.lock().subscribers.len()
+    }
+}
+
+impl<'r> Handler<EspHttpConnection<'r>> for SseBroadcaster {
+    type Error = EspError;
+
+    fn handle(&self, connection: &mut EspHttpConnection<'r>) -> Result<(), Self::Error> {
+        self.subscribe(connection)
+    }
+}
+
+fn socket_send(sd: httpd_handle_t, fd: ffi::c_int, mut data: &[u8]) -> Result<(), EspError> {
+    while !data.is_empty() {
+        let len = unsafe { httpd_socket_send(sd, fd, data.as_ptr() as *const _, data.len(), 0) };
+
+        if len < 0 {
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        data = &data[len as usize..];
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_event() {
+        assert_eq!(SseEvent::new("").format(), "data: \n\n");
+        assert_eq!(
+            SseEvent::new("a\nb").event("update").id("1").format(),
+            "event: update\nid: 1\ndata: a\ndata: b\n\n"
+        );
+        assert_eq!(
+            SseEvent::new("a\r\nb\rc\n").format(),
+            "data: a\ndata: b\ndata: c\ndata: \n\n"
+        );
+        assert_eq!(
+            SseEvent::new("a\revent: injected").format(),
+            "data: a\ndata: event: injected\n\n"
+        );
+        assert_eq!(
+            SseEvent::new("a").event("x\rid: 2").format(),
+            "event: xid: 2\ndata: a\n\n"
+        );
+    }
+}
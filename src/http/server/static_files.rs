@@ -0,0 +1,484 @@
+//! Serving of static files
+//!
+//! `StaticFiles` is a handler which serves files either from a table of files embedded in the
+//! firmware (e.g. with `include_bytes!`), or - with the `std` feature - from a directory of a
+//! mounted VFS filesystem (SPIFFS, FAT, LittleFS).
+//!
+//! The handler supports:
+//! - MIME type detection based on the file extension
+//! - `ETag` / `If-None-Match` validation (responding with `304 Not Modified`), for embedded files
+//!   and for files on filesystems which keep track of the modification time
+//! - Pre-compressed variants: if the client accepts `gzip` and a file with the same name plus
+//!   a `.gz` suffix exists, it is served instead, with `Content-Encoding: gzip`
+//! - Single range requests (`Range: bytes=...`)
+//!
+//! As the handler serves all files below a URI prefix, the server needs to be configured
+//! with `uri_match_wildcard` enabled:
+//!
+//! ```
+//! use esp_idf_svc::http::server::static_files::StaticFiles;
+//! use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+//! use esp_idf_svc::http::Method;
+//!
+//! static FILES: &[(&str, &[u8])] = &[
+//!     ("index.html", include_bytes!("www/index.html")),
+//!     ("app.js.gz", include_bytes!("www/app.js.gz")),
+//! ];
+//!
+//! let mut server = EspHttpServer::new(&Configuration {
+//!     uri_match_wildcard: true,
+//!     ..Default::default()
+//! })?;
+//!
+//! server.handler("/*", Method::Get, StaticFiles::embedded(FILES))?;
+//! server.handler("/*", Method::Head, StaticFiles::embedded(FILES))?;
+//! ```
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use super::urlencoded::percent_decode;
+use super::{EspHttpConnection, Handler, Method};
+
+const CHUNK_LEN: usize = 2048;
+
+enum Source {
+    Embedded(Vec<EmbeddedFile>),
+    #[cfg(feature = "std")]
+    Vfs(std::path::PathBuf),
+}
+
+enum Content {
+    Embedded(&'static [u8]),
+    #[cfg(feature = "std")]
+    Vfs(std::fs::File),
+}
+
+struct EmbeddedFile {
+    path: &'static str,
+    data: &'static [u8],
+    /// Computed once, as hashing the content for every request would be costly
+    etag: String,
+}
+
+struct File {
+    content: Content,
+    len: u64,
+    /// `None` if the file has no reliable validator, i.e. its modification time is unknown
+    etag: Option<String>,
+}
+
+impl File {
+    fn write_range(
+        &mut self,
+        connection: &mut EspHttpConnection<'_>,
+        start: u64,
+        len: u64,
+    ) -> Result<(), EspError> {
+        match &mut self.content {
+            Content::Embedded(data) => {
+                let data = &data[start as usize..(start + len) as usize];
+
+                for chunk in data.chunks(CHUNK_LEN) {
+                    connection.write_all(chunk)?;
+                }
+            }
+            #[cfg(feature = "std")]
+            Content::Vfs(file) => {
+                use std::io::{Read, Seek, SeekFrom};
+
+                file.seek(SeekFrom::Start(start)).map_err(io_error)?;
+
+                let mut buf = vec![0; CHUNK_LEN];
+                let mut left = len;
+
+                while left > 0 {
+                    let read = file
+                        .read(&mut buf[..(left as usize).min(CHUNK_LEN)])
+                        .map_err(io_error)?;
+
+                    if read == 0 {
+                        // The file was truncated in the meantime
+                        return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+                    }
+
+                    connection.write_all(&buf[..read])?;
+                    left -= read as u64;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A handler serving static files, see the module documentation.
+pub struct StaticFiles {
+    source: Source,
+    prefix: String,
+    index: String,
+    cache_control: Option<String>,
+}
+
+impl StaticFiles {
+    /// Serves files from a table of `(path, content)` entries, where paths are relative
+    /// (e.g. `index.html` or `css/style.css`). Pre-compressed variants are entries
+    /// with a `.gz` suffix.
+    pub fn embedded(files: &'static [(&'static str, &'static [u8])]) -> Self {
+        let files = files
+            .iter()
+            .map(|&(path, data)| EmbeddedFile {
+                path: path.trim_start_matches('/'),
+                data,
+                etag: format!("\"{:016x}\"", fnv1a(data)),
+            })
+            .collect();
+
+        Self::new(Source::Embedded(files))
+    }
+
+    /// Serves files from the directory `root` of a mounted VFS filesystem.
+    #[cfg(feature = "std")]
+    pub fn vfs<P>(root: P) -> Self
+    where
+        P: Into<std::path::PathBuf>,
+    {
+        Self::new(Source::Vfs(root.into()))
+    }
+
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            prefix: String::new(),
+            index: "index.html".to_owned(),
+            cache_control: None,
+        }
+    }
+
+    /// Sets the URI prefix which is stripped from the request URI before looking up the file,
+    /// e.g. `/static` when the handler is registered for `/static/*`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('/').to_owned();
+
+        self
+    }
+
+    /// Sets the file which is served for requests of a directory. Defaults to `index.html`.
+    pub fn index(mut self, index: &str) -> Self {
+        self.index = index.to_owned();
+
+        self
+    }
+
+    /// Sets the value of the `Cache-Control` header sent with every file, e.g. `max-age=3600`.
+    pub fn cache_control(mut self, cache_control: &str) -> Self {
+        self.cache_control = Some(cache_control.to_owned());
+
+        self
+    }
+
+    /// Serves the file requested by `connection`.
+    pub fn serve(&self, connection: &mut EspHttpConnection<'_>) -> Result<(), EspError> {
+        let uri = connection.uri();
+        let uri = uri.split_once('?').map(|(path, _)| path).unwrap_or(uri);
+
+        let Some(path) = uri
+            .strip_prefix(self.prefix.as_str())
+            .and_then(|path| percent_decode(path, false))
+        else {
+            return Self::respond_status(connection, 404, "Not Found");
+        };
+
+        if path.split('/').any(|segment| segment == "..") {
+            return Self::respond_status(connection, 400, "Bad Request");
+        }
+
+        let mut path = path.trim_start_matches('/').to_owned();
+        if path.is_empty() || path.ends_with('/') {
+            path.push_str(&self.index);
+        }
+
+        let accepts_gzip = connection
+            .header("Accept-Encoding")
+            .map(|encodings| {
+                encodings.split(',').any(|encoding| {
+                    let encoding = encoding.split(';').next().unwrap_or("").trim();
+
+                    encoding.eq_ignore_ascii_case("gzip")
+                })
+            })
+            .unwrap_or(false);
+
+        let gz_path = format!("{path}.gz");
+        let gzipped = self.open(&gz_path)?;
+        let has_gzipped = gzipped.is_some();
+
+        let (mut file, gzip) = match gzipped {
+            Some(file) if accepts_gzip => (file, true),
+            _ => match self.open(&path)? {
+                Some(file) => (file, false),
+                None => return Self::respond_status(connection, 404, "Not Found"),
+            },
+        };
+
+        let mut headers: Vec<(&str, String)> = vec![
+            ("Content-Type", mime_type(&path).to_owned()),
+            ("Accept-Ranges", "bytes".to_owned()),
+        ];
+
+        if let Some(etag) = &file.etag {
+            headers.push(("ETag", etag.clone()));
+        }
+
+        if gzip {
+            headers.push(("Content-Encoding", "gzip".to_owned()));
+        }
+
+        if has_gzipped {
+            headers.push(("Vary", "Accept-Encoding".to_owned()));
+        }
+
+        if let Some(cache_control) = &self.cache_control {
+            headers.push(("Cache-Control", cache_control.clone()));
+        }
+
+        if let Some((if_none_match, file_etag)) =
+            connection.header("If-None-Match").zip(file.etag.as_deref())
+        {
+            let matches = if_none_match.split(',').any(|etag| {
+                let etag = etag.trim();
+
+                etag == "*" || etag.trim_start_matches("W/") == file_etag.trim_start_matches("W/")
+            });
+
+            if matches {
+                return Self::respond(connection, 304, "Not Modified", &headers);
+            }
+        }
+
+        let range = connection
+            .header("Range")
+            .and_then(|range| parse_range(range, file.len));
+
+        let (status, message, start, len) = match range {
+            Some(Ok((start, end))) => {
+                headers.push((
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end, file.len),
+                ));
+
+                (206, "Partial Content", start, end - start + 1)
+            }
+            Some(Err(())) => {
+                headers.push(("Content-Range", format!("bytes */{}", file.len)));
+
+                return Self::respond(connection, 416, "Range Not Satisfiable", &headers);
+            }
+            None => (200, "OK", 0, file.len),
+        };
+
+        let head = matches!(connection.method(), Method::Head);
+
+        Self::respond(connection, status, message, &headers)?;
+
+        if !head {
+            file.write_range(connection, start, len)?;
+        }
+
+        Ok(())
+    }
+
+    fn open(&self, path: &str) -> Result<Option<File>, EspError> {
+        match &self.source {
+            Source::Embedded(files) => {
+                Ok(files
+                    .iter()
+                    .find(|file| file.path == path)
+                    .map(|file| File {
+                        content: Content::Embedded(file.data),
+                        len: file.data.len() as _,
+                        etag: Some(file.etag.clone()),
+                    }))
+            }
+            #[cfg(feature = "std")]
+            Source::Vfs(root) => {
+                let path = root.join(path);
+
+                let file = match std::fs::File::open(&path) {
+                    Ok(file) => file,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(io_error(err)),
+                };
+
+                let metadata = file.metadata().map_err(io_error)?;
+                if !metadata.is_file() {
+                    return Ok(None);
+                }
+
+                let len = metadata.len();
+
+                // Not all filesystems (e.g. SPIFFS) keep track of the modification time, and the
+                // length alone cannot tell whether a file changed, so there is no ETag then
+                let etag = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|modified| format!("\"{:x}-{:x}\"", len, modified.as_secs()));
+
+                Ok(Some(File {
+                    content: Content::Vfs(file),
+                    len,
+                    etag,
+                }))
+            }
+        }
+    }
+
+    fn respond(
+        connection: &mut EspHttpConnection<'_>,
+        status: u16,
+        message: &str,
+        headers: &[(&str, String)],
+    ) -> Result<(), EspError> {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+
+        connection.initiate_response(status, Some(message), &headers)
+    }
+
+    fn respond_status(
+        connection: &mut EspHttpConnection<'_>,
+        status: u16,
+        message: &str,
+    ) -> Result<(), EspError> {
+        debug!("Static file {} - {} {}", connection.uri(), status, message);
+
+        connection.initiate_response(status, Some(message), &[])
+    }
+}
+
+impl<'a> Handler<EspHttpConnection<'a>> for StaticFiles {
+    type Error = EspError;
+
+    fn handle(&self, connection: &mut EspHttpConnection<'a>) -> Result<(), Self::Error> {
+        self.serve(connection)
+    }
+}
+
+/// Returns the MIME type of a file, based on its extension.
+pub fn mime_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+        .unwrap_or("");
+
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "csv" => "text/csv",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "webmanifest" => "application/manifest+json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Parses a `Range` header value with a single byte range.
+///
+/// Returns `None` if the header should be ignored (i.e. the whole file is served), which
+/// includes invalid ranges whose end precedes their start, and `Some(Err(()))` if the range
+/// is not satisfiable.
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+
+    if range.contains(',') {
+        // Multiple ranges are not supported, serving the whole file is allowed instead
+        return None;
+    }
+
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range, i.e. the last N bytes
+        let suffix: u64 = end.parse().ok()?;
+
+        (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end: Option<u64> = if end.is_empty() {
+            None
+        } else {
+            Some(end.parse().ok()?)
+        };
+
+        if end.is_some_and(|end| end < start) {
+            return None;
+        }
+
+        (start < len).then(|| (start, end.unwrap_or(u64::MAX).min(len - 1)))
+    };
+
+    Some(range.ok_or(()))
+}
+
+/// FNV-1a (64 bit), used for the `ETag` of embedded files
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(feature = "std")]
+fn io_error(err: std::io::Error) -> EspError {
+    warn!("Static file I/O error: {}", err);
+
+    EspError::from_infallible::<ESP_FAIL>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=100-", 1000), Some(Ok((100, 999))));
+        assert_eq!(parse_range("bytes=900-1999", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range(" bytes= 5 - 9 ", 1000), Some(Ok((5, 9))));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+        assert_eq!(parse_range("bytes=-1", 0), Some(Err(())));
+
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), None);
+        assert_eq!(parse_range("items=0-9", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=10", 1000), None);
+        assert_eq!(parse_range("bytes=99-0", 1000), None);
+        assert_eq!(parse_range("bytes=1000-999", 1000), None);
+    }
+}
@@ -0,0 +1,159 @@
+//! Decoding of `application/x-www-form-urlencoded` data
+//!
+//! HTML forms are posted with this encoding by default, and it is also the format of query strings.
+//! `UrlEncoded` decodes such data into name-value pairs, and `EspHttpConnection::read_form`
+//! reads and decodes a form posted in the request body:
+//!
+//! ```
+//! use esp_idf_svc::http::Method;
+//!
+//! server.fn_handler("/settings", Method::Post, |mut request| {
+//!     let form = request.connection().read_form(1024)?;
+//!
+//!     let ssid = form.get("ssid").unwrap_or_default();
+//!     let channel = form.get_as::<u8>("channel")?.unwrap_or(1);
+//!
+//!     request.into_ok_response()?;
+//!
+//!     Ok::<_, anyhow::Error>(())
+//! })?;
+//! ```
+use core::str::FromStr;
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::sys::*;
+
+use super::router::ParamError;
+use super::EspHttpConnection;
+
+const CONTENT_TYPE_FORM: &str = "application/x-www-form-urlencoded";
+
+/// Decoded `application/x-www-form-urlencoded` name-value pairs
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UrlEncoded(Vec<(String, String)>);
+
+impl UrlEncoded {
+    /// Decodes `data`, skipping pairs which are not valid percent-encoded UTF-8.
+    pub fn parse(data: &str) -> Self {
+        Self(
+            data.split('&')
+                .filter(|pair| !pair.is_empty())
+                .filter_map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+                    Some((percent_decode(name, true)?, percent_decode(value, true)?))
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the value of the first pair with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Parses the value of the first pair with the given name, if present.
+    pub fn get_as<T>(&self, name: &str) -> Result<Option<T>, ParamError>
+    where
+        T: FromStr,
+    {
+        self.get(name)
+            .map(|value| {
+                value.parse().map_err(|_| ParamError {
+                    name: name.to_owned(),
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns the values of all pairs with the given name, e.g. of a multi-select field.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns all pairs, in the order of the data.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> EspHttpConnection<'a> {
+    /// Reads the request body - which must not be larger than `max_len` bytes - and decodes it
+    /// as `application/x-www-form-urlencoded` data.
+    ///
+    /// Fails with `ESP_ERR_INVALID_SIZE` if the body is too large and with `ESP_ERR_INVALID_ARG`
+    /// if the request has a different content type or the body is not valid UTF-8.
+    pub fn read_form(&mut self, max_len: usize) -> Result<UrlEncoded, EspError> {
+        if let Some(content_type) = self.header("Content-Type") {
+            let mime = content_type.split(';').next().unwrap_or("").trim();
+
+            if !mime.eq_ignore_ascii_case(CONTENT_TYPE_FORM) {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+            }
+        }
+
+        let mut body = Vec::new();
+        let mut buf = [0; 256];
+
+        loop {
+            let len = self.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+
+            if body.len() + len > max_len {
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>());
+            }
+
+            body.extend_from_slice(&buf[..len]);
+        }
+
+        let body = core::str::from_utf8(&body)
+            .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+
+        Ok(UrlEncoded::parse(body))
+    }
+}
+
+/// Decodes `%XX` escapes and - if `plus_as_space` is set - `+` as space.
+///
+/// Returns `None` if an escape is malformed or the result is not valid UTF-8.
+pub(crate) fn percent_decode(value: &str, plus_as_space: bool) -> Option<String> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = core::str::from_utf8(&hex).ok()?;
+
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
@@ -0,0 +1,277 @@
+//! A registry of the open Websockets sessions of a server
+//!
+//! `WsSessionRegistry` keeps a detached sender for every session, grouped by the URI of its
+//! `ws_handler` and by rooms the sessions can join, and sends frames to single sessions or
+//! broadcasts them to all sessions, the sessions of a URI or the members of a room:
+//!
+//! ```
+//! use esp_idf_svc::http::server::ws::registry::WsSessionRegistry;
+//! use esp_idf_svc::sys::EspError;
+//! use esp_idf_svc::ws::FrameType;
+//!
+//! let registry = WsSessionRegistry::new();
+//!
+//! server.ws_handler("/chat", {
+//!     let registry = registry.clone();
+//!
+//!     move |connection| {
+//!         registry.track("/chat", connection)?;
+//!
+//!         if !connection.is_new() && !connection.is_closed() {
+//!             let mut buf = [0; 256];
+//!             let (frame_type, len) = connection.recv(&mut buf)?;
+//!
+//!             if let FrameType::Text(false) = frame_type {
+//!                 registry.join(connection.session(), "lobby");
+//!                 registry.broadcast_room("lobby", FrameType::Text(false), &buf[..len - 1]);
+//!             }
+//!         }
+//!
+//!         Ok::<_, EspError>(())
+//!     }
+//! })?;
+//! ```
+//!
+//! Broadcasts do not wait for the frames to be sent, so - unlike `EspHttpWsDetachedSender::send` -
+//! they can also be used from within a `ws_handler`.
+//!
+//! Sessions are identified by their socket descriptors, so a registry should only track
+//! the sessions of a single server.
+use core::ffi;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use embedded_svc::ws::FrameType;
+
+use crate::sys::*;
+
+use crate::private::mutex::{Mutex, MutexGuard};
+
+use super::{EspHttpWsConnection, EspHttpWsDetachedSender};
+
+struct Session {
+    uri: String,
+    sender: EspHttpWsDetachedSender,
+    rooms: BTreeSet<String>,
+}
+
+/// The open Websockets sessions, see the module documentation
+#[derive(Clone)]
+pub struct WsSessionRegistry(Arc<Mutex<BTreeMap<ffi::c_int, Session>>>);
+
+impl WsSessionRegistry {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(BTreeMap::new())))
+    }
+
+    /// Registers new sessions and unregisters closed ones; to be called from the `ws_handler`
+    /// of `uri` for every connection event.
+    pub fn track(&self, uri: &str, connection: &EspHttpWsConnection) -> Result<(), EspError> {
+        if connection.is_new() {
+            self.register(uri, connection)?;
+        } else if connection.is_closed() {
+            self.unregister(connection.session());
+        }
+
+        Ok(())
+    }
+
+    pub fn register(&self, uri: &str, connection: &EspHttpWsConnection) -> Result<(), EspError> {
+        let sender = connection.create_detached_sender()?;
+
+        self.0.lock().insert(
+            sender.session(),
+            Session {
+                uri: uri.into(),
+                sender,
+                rooms: BTreeSet::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Removes the session, returning `false` if it was not registered.
+    pub fn unregister(&self, session: i32) -> bool {
+        self.0.lock().remove(&session).is_some()
+    }
+
+    /// Adds the session to `room`, returning `false` if the session is not registered.
+    pub fn join(&self, session: i32, room: &str) -> bool {
+        match self.0.lock().get_mut(&session) {
+            Some(entry) => {
+                entry.rooms.insert(room.into());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn leave(&self, session: i32, room: &str) {
+        if let Some(entry) = self.0.lock().get_mut(&session) {
+            entry.rooms.remove(room);
+        }
+    }
+
+    /// Returns the open sessions.
+    pub fn sessions(&self) -> Vec<i32> {
+        self.select(|_| true)
+    }
+
+    /// Returns the open sessions of the `ws_handler` of `uri`.
+    pub fn sessions_of(&self, uri: &str) -> Vec<i32> {
+        self.select(|entry| entry.uri == uri)
+    }
+
+    /// Returns the open sessions which have joined `room`.
+    pub fn members(&self, room: &str) -> Vec<i32> {
+        self.select(|entry| entry.rooms.contains(room))
+    }
+
+    /// Returns the rooms `session` has joined.
+    pub fn rooms(&self, session: i32) -> Vec<String> {
+        self.0
+            .lock()
+            .get(&session)
+            .map(|entry| entry.rooms.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns a detached sender for `session`, if it is open.
+    pub fn sender(&self, session: i32) -> Option<EspHttpWsDetachedSender> {
+        self.prune_locked()
+            .get(&session)
+            .map(|entry| entry.sender.clone())
+    }
+
+    /// Removes the sessions which have been closed, returning their number.
+    ///
+    /// Closed sessions are also removed by all other methods, so calling this is only
+    /// necessary for releasing their memory early.
+    pub fn prune(&self) -> usize {
+        let mut sessions = self.0.lock();
+        let len = sessions.len();
+
+        sessions.retain(|_, entry| !entry.sender.is_closed());
+
+        len - sessions.len()
+    }
+
+    /// Sends a frame to all open sessions, returning their number.
+    pub fn broadcast(&self, frame_type: FrameType, frame_data: &[u8]) -> usize {
+        self.broadcast_to(frame_type, frame_data, |_| true)
+    }
+
+    /// Sends a frame to the open sessions of the `ws_handler` of `uri`, returning their number.
+    pub fn broadcast_uri(&self, uri: &str, frame_type: FrameType, frame_data: &[u8]) -> usize {
+        self.broadcast_to(frame_type, frame_data, |entry| entry.uri == uri)
+    }
+
+    /// Sends a frame to the open sessions which have joined `room`, returning their number.
+    pub fn broadcast_room(&self, room: &str, frame_type: FrameType, frame_data: &[u8]) -> usize {
+        self.broadcast_to(frame_type, frame_data, |entry| entry.rooms.contains(room))
+    }
+
+    fn select<F>(&self, filter: F) -> Vec<i32>
+    where
+        F: Fn(&Session) -> bool,
+    {
+        self.prune_locked()
+            .iter()
+            .filter(|(_, entry)| filter(entry))
+            .map(|(session, _)| *session)
+            .collect()
+    }
+
+    fn prune_locked(&self) -> MutexGuard<'_, BTreeMap<ffi::c_int, Session>> {
+        let mut sessions = self.0.lock();
+
+        sessions.retain(|_, entry| !entry.sender.is_closed());
+
+        sessions
+    }
+
+    fn broadcast_to<F>(&self, frame_type: FrameType, frame_data: &[u8], filter: F) -> usize
+    where
+        F: Fn(&Session) -> bool,
+    {
+        // Grouping the recipients per server, so that a single work item is queued for each server
+        let mut recipients: BTreeMap<u32, Vec<EspHttpWsDetachedSender>> = BTreeMap::new();
+
+        for entry in self.prune_locked().values().filter(|entry| filter(entry)) {
+            recipients
+                .entry(entry.sender.sd as u32)
+                .or_default()
+                .push(entry.sender.clone());
+        }
+
+        let frame_data: Arc<[u8]> = frame_data.into();
+
+        let mut count = 0;
+
+        for senders in recipients.into_values() {
+            let len = senders.len();
+            let sd = senders[0].sd;
+
+            let work = Box::new(BroadcastWork {
+                senders,
+                frame_type,
+                frame_data: frame_data.clone(),
+            });
+
+            let arg = Box::into_raw(work);
+
+            match esp!(unsafe { httpd_queue_work(sd, Some(BroadcastWork::send), arg as *mut _) }) {
+                Ok(()) => count += len,
+                Err(err) => {
+                    drop(unsafe { Box::from_raw(arg) });
+
+                    warn!(
+                        "Queueing a WS broadcast to {} sessions failed: {}",
+                        len, err
+                    );
+                }
+            }
+        }
+
+        count
+    }
+}
+
+impl Default for WsSessionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct BroadcastWork {
+    senders: Vec<EspHttpWsDetachedSender>,
+    frame_type: FrameType,
+    frame_data: Arc<[u8]>,
+}
+
+impl BroadcastWork {
+    extern "C" fn send(arg: *mut ffi::c_void) {
+        let work = unsafe { Box::from_raw(arg as *mut BroadcastWork) };
+
+        let raw_frame = EspHttpWsConnection::create_raw_frame(work.frame_type, &work.frame_data);
+
+        for sender in work.senders.iter().filter(|sender| !sender.is_closed()) {
+            if let Err(err) = esp!(unsafe {
+                httpd_ws_send_frame_async(sender.sd, sender.fd, &raw_frame as *const _ as *mut _)
+            }) {
+                debug!(
+                    "Broadcasting to WS connection {} failed: {}",
+                    sender.fd, err
+                );
+            }
+        }
+    }
+}
@@ -8,6 +8,10 @@
 //!
 //! - `std`: Enable the use of std. Enabled by default.
 //! - `experimental`: Enable the use of experimental features.
+//! - `postcard`: Enable the typed, `serde`-based NVS store in `nvs::store`.
+//! - `json`: Enable the JSON request and response helpers in `http::server::json`.
+//! - `ota-ed25519`: Enable verification of Ed25519-signed OTA images in `ota::verify`.
+//! - `ota-ecdsa`: Enable verification of ECDSA (P-256) signed OTA images in `ota::verify`.
 //! - `embassy-time-driver`: Implement an embassy time driver.
 #![no_std]
 #![allow(async_fn_in_trait)]
@@ -84,6 +88,8 @@ pub mod netif;
 pub mod nvs;
 #[cfg(all(esp_idf_comp_app_update_enabled, esp_idf_comp_spi_flash_enabled))]
 pub mod ota;
+#[cfg(esp_idf_comp_spi_flash_enabled)]
+pub mod partition;
 #[cfg(esp_idf_comp_esp_netif_enabled)]
 pub mod ping;
 #[cfg(all(feature = "alloc", esp_idf_comp_esp_netif_enabled))]
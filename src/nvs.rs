@@ -3,6 +3,7 @@ use core::ptr;
 
 extern crate alloc;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use ::log::*;
 
@@ -11,13 +12,20 @@ use embedded_svc::storage::{RawStorage, StorageBase};
 use crate::sys::*;
 
 use crate::handle::RawHandle;
+use crate::private::common::Newtype;
 use crate::private::cstr::*;
 use crate::private::mutex;
 
+#[cfg(feature = "postcard")]
+pub mod store;
+
 static DEFAULT_TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
 static NONDEFAULT_LOCKED: mutex::Mutex<alloc::collections::BTreeSet<CString>> =
     mutex::Mutex::new(alloc::collections::BTreeSet::new());
 
+/// The key under which `EspNvsTransaction::commit` stores the journal of a transaction
+const TRANSACTION_JOURNAL_KEY: &str = "__nvs_txn";
+
 pub type EspDefaultNvsPartition = EspNvsPartition<NvsDefault>;
 pub type EspCustomNvsPartition = EspNvsPartition<NvsCustom>;
 pub type EspEncryptedNvsPartition = EspNvsPartition<NvsEncrypted>;
@@ -27,6 +35,12 @@ pub trait NvsPartitionId {
         self.name().to_bytes().is_empty()
     }
 
+    /// Returns `true` if the partition was taken in read-only mode, in which case
+    /// it can only be opened with `EspNvs::new(..., false)`.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
     fn name(&self) -> &CStr;
 }
 
@@ -76,17 +90,18 @@ impl NvsPartitionId for NvsDefault {
     }
 }
 
-pub struct NvsCustom(CString);
+pub struct NvsCustom(CString, bool);
 
 impl NvsCustom {
-    fn new(partition: &str) -> Result<Self, EspError> {
+    fn new(partition: &str, read_only: bool) -> Result<Self, EspError> {
         let mut registrations = NONDEFAULT_LOCKED.lock();
 
-        Self::init(partition, &mut registrations)
+        Self::init(partition, read_only, &mut registrations)
     }
 
     fn init(
         partition: &str,
+        read_only: bool,
         registrations: &mut alloc::collections::BTreeSet<CString>,
     ) -> Result<Self, EspError> {
         let c_partition = to_cstring_arg(partition)?;
@@ -98,7 +113,8 @@ impl NvsCustom {
         unsafe {
             if let Some(err) = EspError::from(nvs_flash_init_partition(c_partition.as_ptr())) {
                 match err.code() {
-                    ESP_ERR_NVS_NO_FREE_PAGES | ESP_ERR_NVS_NEW_VERSION_FOUND => {
+                    // A read-only partition is never erased, even if it cannot be initialized
+                    ESP_ERR_NVS_NO_FREE_PAGES | ESP_ERR_NVS_NEW_VERSION_FOUND if !read_only => {
                         esp!(nvs_flash_erase_partition(c_partition.as_ptr()))?;
                         esp!(nvs_flash_init_partition(c_partition.as_ptr()))?;
                     }
@@ -109,7 +125,19 @@ impl NvsCustom {
 
         registrations.insert(c_partition.clone());
 
-        Ok(Self(c_partition))
+        Ok(Self(c_partition, read_only))
+    }
+
+    fn erase(&self) -> Result<(), EspError> {
+        if self.1 {
+            return Err(EspError::from_infallible::<ESP_ERR_NVS_READ_ONLY>());
+        }
+
+        // nvs_flash_erase_partition de-initializes the partition first
+        esp!(unsafe { nvs_flash_erase_partition(self.0.as_ptr()) })?;
+        esp!(unsafe { nvs_flash_init_partition(self.0.as_ptr()) })?;
+
+        Ok(())
     }
 }
 
@@ -130,19 +158,174 @@ impl NvsPartitionId for NvsCustom {
     fn name(&self) -> &CStr {
         self.0.as_c_str()
     }
+
+    fn is_read_only(&self) -> bool {
+        self.1
+    }
+}
+
+/// The scheme protecting the keys of an encrypted NVS partition
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NvsKeyProtection<'a> {
+    /// The keys are stored in an NVS keys partition (the first one found, if `None`),
+    /// which is protected by flash encryption
+    FlashEncryption(Option<&'a str>),
+    /// The keys are derived with the HMAC peripheral from the eFuse key block with the given ID.
+    ///
+    /// If no eFuse key block with the HMAC_UP purpose is burned yet, generating the keys
+    /// generates and burns one into the given key block.
+    #[cfg(esp_idf_nvs_sec_key_protect_using_hmac)]
+    Hmac(u8),
+}
+
+impl<'a> NvsKeyProtection<'a> {
+    /// Reads the keys, optionally generating them if they are not yet initialized.
+    ///
+    /// Returns `None` if the keys are not initialized and `generate` is `false`.
+    fn security_config(&self, generate: bool) -> Result<Option<NvsKeys>, EspError> {
+        self.source()?.security_config(generate)
+    }
+
+    fn source(&self) -> Result<NvsKeySource, EspError> {
+        Ok(match self {
+            Self::FlashEncryption(keys_partition) => {
+                NvsKeySource::FlashEncryption(keys_partition.map(to_cstring_arg).transpose()?)
+            }
+            #[cfg(esp_idf_nvs_sec_key_protect_using_hmac)]
+            Self::Hmac(key_id) => NvsKeySource::Hmac(*key_id),
+        })
+    }
+}
+
+/// An owned `NvsKeyProtection`, from which the keys are read again whenever they are needed,
+/// rather than keeping them in memory
+#[derive(Debug)]
+enum NvsKeySource {
+    FlashEncryption(Option<CString>),
+    #[cfg(esp_idf_nvs_sec_key_protect_using_hmac)]
+    Hmac(u8),
+}
+
+impl NvsKeySource {
+    fn security_config(&self, generate: bool) -> Result<Option<NvsKeys>, EspError> {
+        let mut keys = NvsKeys(Default::default());
+
+        match self {
+            Self::FlashEncryption(keys_partition) => {
+                let keys_partition_ptr = unsafe {
+                    esp_partition_find_first(
+                        esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                        esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS_KEYS,
+                        match keys_partition {
+                            Some(ref v) => v.as_ptr(),
+                            None => core::ptr::null(),
+                        },
+                    )
+                };
+
+                if keys_partition_ptr.is_null() {
+                    warn!("No NVS keys partition found");
+                    return Err(EspError::from_infallible::<ESP_FAIL>());
+                }
+
+                match unsafe { nvs_flash_read_security_cfg(keys_partition_ptr, &mut keys.0) } {
+                    ESP_ERR_NVS_KEYS_NOT_INITIALIZED | ESP_ERR_NVS_CORRUPT_KEY_PART => {
+                        if !generate {
+                            return Ok(None);
+                        }
+
+                        info!("Keys partition not initialized, generating keys");
+                        esp!(unsafe { nvs_flash_generate_keys(keys_partition_ptr, &mut keys.0) })?;
+                    }
+                    other => esp!(other)?,
+                }
+            }
+            #[cfg(esp_idf_nvs_sec_key_protect_using_hmac)]
+            Self::Hmac(key_id) => {
+                let scheme = hmac_scheme(*key_id)?;
+
+                match unsafe { nvs_flash_read_security_cfg_v2(scheme, &mut keys.0) } {
+                    ESP_ERR_NVS_SEC_HMAC_KEY_NOT_FOUND if !generate => return Ok(None),
+                    ESP_ERR_NVS_SEC_HMAC_KEY_NOT_FOUND => {
+                        info!("HMAC key not found, generating keys");
+                        esp!(unsafe { nvs_flash_generate_keys_v2(scheme, &mut keys.0) })?;
+                    }
+                    other => esp!(other)?,
+                }
+            }
+        }
+
+        Ok(Some(keys))
+    }
+}
+
+/// The HMAC schemes registered for the key IDs other than the one configured with
+/// `CONFIG_NVS_SEC_HMAC_EFUSE_KEY_ID`, as addresses of their `nvs_sec_scheme_t`
+#[cfg(esp_idf_nvs_sec_key_protect_using_hmac)]
+static HMAC_SCHEMES: mutex::Mutex<alloc::collections::BTreeMap<u8, usize>> =
+    mutex::Mutex::new(alloc::collections::BTreeMap::new());
+
+/// Returns the HMAC scheme for the eFuse key block with the given ID.
+///
+/// This is the default scheme which ESP-IDF registers on startup if the ID is the configured one.
+/// Schemes for other IDs are registered once and kept for the lifetime of the program, as
+/// de-registering a scheme frees it, even while it is ESP-IDF's default scheme.
+#[cfg(esp_idf_nvs_sec_key_protect_using_hmac)]
+fn hmac_scheme(key_id: u8) -> Result<*mut nvs_sec_scheme_t, EspError> {
+    if key_id as i64 == CONFIG_NVS_SEC_HMAC_EFUSE_KEY_ID as i64 {
+        let scheme = unsafe { nvs_flash_get_default_security_scheme() };
+
+        if !scheme.is_null() {
+            return Ok(scheme);
+        }
+    }
+
+    let mut schemes = HMAC_SCHEMES.lock();
+
+    if let Some(scheme) = schemes.get(&key_id) {
+        return Ok(*scheme as *mut _);
+    }
+
+    let hmac_config = nvs_sec_config_hmac_t {
+        hmac_key_id: key_id as _,
+    };
+
+    let mut scheme: *mut nvs_sec_scheme_t = ptr::null_mut();
+
+    esp!(unsafe { nvs_sec_provider_register_hmac(&hmac_config, &mut scheme as *mut _) })?;
+
+    schemes.insert(key_id, scheme as usize);
+
+    Ok(scheme)
 }
-pub struct NvsEncrypted(CString);
+
+/// The XTS keys of an encrypted NVS partition, which are zeroized when dropped
+struct NvsKeys(nvs_sec_cfg_t);
+
+impl Drop for NvsKeys {
+    fn drop(&mut self) {
+        unsafe { ptr::write_volatile(&mut self.0, Default::default()) };
+        core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+pub struct NvsEncrypted(CString, NvsKeySource, bool);
 
 impl NvsEncrypted {
-    fn new(partition: &str, key_partition: Option<&str>) -> Result<Self, EspError> {
+    fn new(
+        partition: &str,
+        protection: NvsKeyProtection,
+        read_only: bool,
+    ) -> Result<Self, EspError> {
         let mut registrations = NONDEFAULT_LOCKED.lock();
 
-        Self::init(partition, key_partition, &mut registrations)
+        Self::init(partition, protection, read_only, &mut registrations)
     }
 
     fn init(
         partition: &str,
-        key_partition: Option<&str>,
+        protection: NvsKeyProtection,
+        read_only: bool,
         registrations: &mut alloc::collections::BTreeSet<CString>,
     ) -> Result<Self, EspError> {
         let c_partition = to_cstring_arg(partition)?;
@@ -151,46 +334,38 @@ impl NvsEncrypted {
             return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
         }
 
-        let c_key_partition = if let Some(key_partition) = key_partition {
-            Some(to_cstring_arg(key_partition)?)
-        } else {
-            None
-        };
+        let source = protection.source()?;
 
-        let keys_partition_ptr = unsafe {
-            esp_partition_find_first(
-                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
-                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS_KEYS,
-                match c_key_partition {
-                    Some(ref v) => v.as_ptr(),
-                    None => core::ptr::null(),
-                },
-            )
-        };
+        // A read-only partition never gets its keys generated
+        Self::init_partition(&c_partition, &source, !read_only)?;
 
-        if keys_partition_ptr.is_null() {
-            warn!("No NVS keys partition found");
-            return Err(EspError::from_infallible::<ESP_FAIL>());
-        }
+        registrations.insert(c_partition.clone());
 
-        let mut config = nvs_sec_cfg_t::default();
-        match unsafe { nvs_flash_read_security_cfg(keys_partition_ptr, &mut config as *mut _) } {
-            ESP_ERR_NVS_KEYS_NOT_INITIALIZED | ESP_ERR_NVS_CORRUPT_KEY_PART => {
-                info!("Partition not initialized, generating keys");
-                esp!(unsafe {
-                    nvs_flash_generate_keys(keys_partition_ptr, &mut config as *mut _)
-                })?;
-            }
-            other => esp!(other)?,
-        }
+        Ok(Self(c_partition, source, read_only))
+    }
 
-        esp!(unsafe {
-            nvs_flash_secure_init_partition(c_partition.as_ptr(), &mut config as *mut _)
-        })?;
+    /// Initializes the partition with the keys read from `source`, which are zeroized right after.
+    fn init_partition(
+        partition: &CStr,
+        source: &NvsKeySource,
+        generate: bool,
+    ) -> Result<(), EspError> {
+        let mut keys = source
+            .security_config(generate)?
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_NVS_KEYS_NOT_INITIALIZED>)?;
+
+        esp!(unsafe { nvs_flash_secure_init_partition(partition.as_ptr(), &mut keys.0) })
+    }
 
-        registrations.insert(c_partition.clone());
+    fn erase(&self) -> Result<(), EspError> {
+        if self.2 {
+            return Err(EspError::from_infallible::<ESP_ERR_NVS_READ_ONLY>());
+        }
+
+        // nvs_flash_erase_partition de-initializes the partition first
+        esp!(unsafe { nvs_flash_erase_partition(self.0.as_ptr()) })?;
 
-        Ok(Self(c_partition))
+        Self::init_partition(&self.0, &self.1, false)
     }
 }
 
@@ -212,6 +387,10 @@ impl NvsPartitionId for NvsEncrypted {
     fn name(&self) -> &CStr {
         self.0.as_c_str()
     }
+
+    fn is_read_only(&self) -> bool {
+        self.2
+    }
 }
 
 #[derive(Debug)]
@@ -221,21 +400,191 @@ impl EspNvsPartition<NvsDefault> {
     pub fn take() -> Result<Self, EspError> {
         Ok(Self(Arc::new(NvsDefault::new()?)))
     }
+
+    /// Erases all namespaces of the partition and re-initializes it.
+    ///
+    /// Fails with `ESP_ERR_INVALID_STATE` if the partition is still shared, i.e. if there are
+    /// clones of it or `EspNvs` instances opened on it.
+    pub fn erase(&mut self) -> Result<(), EspError> {
+        self.check_exclusive()?;
+
+        esp!(unsafe { nvs_flash_erase() })?;
+        esp!(unsafe { nvs_flash_init() })?;
+
+        Ok(())
+    }
 }
 
 impl EspNvsPartition<NvsCustom> {
     pub fn take(partition: &str) -> Result<Self, EspError> {
-        Ok(Self(Arc::new(NvsCustom::new(partition)?)))
+        Ok(Self(Arc::new(NvsCustom::new(partition, false)?)))
+    }
+
+    /// Takes the partition in read-only mode.
+    ///
+    /// In contrast to `take`, the partition is never erased, even if it cannot be initialized,
+    /// and `EspNvs` instances can only be opened on it in read-only mode.
+    ///
+    /// Note that ESP-IDF has no read-only way to initialize an NVS partition: initializing it
+    /// may still write to the flash, to complete or clean up operations which were interrupted
+    /// by a power loss (e.g. erasing a page which was being freed). A partition which must not be
+    /// written at all should additionally be marked as `readonly` in the partition table (which
+    /// is supported since ESP-IDF 5.3).
+    pub fn take_read_only(partition: &str) -> Result<Self, EspError> {
+        Ok(Self(Arc::new(NvsCustom::new(partition, true)?)))
+    }
+
+    /// Erases all namespaces of the partition and re-initializes it.
+    ///
+    /// Fails with `ESP_ERR_INVALID_STATE` if the partition is still shared, i.e. if there are
+    /// clones of it or `EspNvs` instances opened on it, and with `ESP_ERR_NVS_READ_ONLY`
+    /// if the partition was taken in read-only mode.
+    pub fn erase(&mut self) -> Result<(), EspError> {
+        self.check_exclusive()?;
+
+        self.0.erase()
     }
 }
 
 impl EspNvsPartition<NvsEncrypted> {
     pub fn take(partition: &str, keys_partition: Option<&str>) -> Result<Self, EspError> {
+        Self::take_with_protection(partition, NvsKeyProtection::FlashEncryption(keys_partition))
+    }
+
+    /// Takes the partition, using keys protected with the given scheme.
+    ///
+    /// The keys are generated (and - for the flash encryption scheme - written
+    /// to the keys partition) if they are not initialized yet.
+    pub fn take_with_protection(
+        partition: &str,
+        protection: NvsKeyProtection,
+    ) -> Result<Self, EspError> {
         Ok(Self(Arc::new(NvsEncrypted::new(
-            partition,
-            keys_partition,
+            partition, protection, false,
         )?)))
     }
+
+    /// Takes the partition in read-only mode.
+    ///
+    /// In contrast to `take_with_protection`, no keys are generated if they are not initialized
+    /// yet (failing with `ESP_ERR_NVS_KEYS_NOT_INITIALIZED`), and `EspNvs` instances can only be opened on the partition in read-only mode.
+    ///
+    /// As with `EspNvsPartition::<NvsCustom>::take_read_only`, initializing the partition may
+    /// still write to the flash to recover from interrupted operations.
+    pub fn take_read_only(partition: &str, protection: NvsKeyProtection) -> Result<Self, EspError> {
+        Ok(Self(Arc::new(NvsEncrypted::new(
+            partition, protection, true,
+        )?)))
+    }
+
+    /// Returns `true` if the keys protected with the given scheme are already initialized.
+    pub fn are_keys_provisioned(protection: NvsKeyProtection) -> Result<bool, EspError> {
+        Ok(protection.security_config(false)?.is_some())
+    }
+
+    /// Generates the keys protected with the given scheme, unless they are already initialized.
+    ///
+    /// Useful for provisioning the keys on first boot, before any encrypted partition is taken.
+    pub fn provision_keys(protection: NvsKeyProtection) -> Result<(), EspError> {
+        protection.security_config(true)?;
+
+        Ok(())
+    }
+
+    /// Erases all namespaces of the partition and re-initializes it with the same keys.
+    ///
+    /// Fails with `ESP_ERR_INVALID_STATE` if the partition is still shared, i.e. if there are
+    /// clones of it or `EspNvs` instances opened on it, and with `ESP_ERR_NVS_READ_ONLY`
+    /// if the partition was taken in read-only mode.
+    pub fn erase(&mut self) -> Result<(), EspError> {
+        self.check_exclusive()?;
+
+        self.0.erase()
+    }
+}
+
+impl<T: NvsPartitionId> EspNvsPartition<T> {
+    /// Returns the entry statistics of this partition.
+    pub fn stats(&self) -> Result<NvsPartitionStats, EspError> {
+        let mut stats: nvs_stats_t = Default::default();
+
+        esp!(unsafe { nvs_get_stats(self.raw_name(), &mut stats as *mut _) })?;
+
+        Ok(NvsPartitionStats {
+            used_entries: stats.used_entries as _,
+            free_entries: stats.free_entries as _,
+            total_entries: stats.total_entries as _,
+            namespace_count: stats.namespace_count as _,
+        })
+    }
+
+    /// Returns an iterator over the entries stored in this partition.
+    ///
+    /// When `namespace` is `None`, entries from all namespaces are returned.
+    /// The entries can be further filtered by their data type with `data_type`;
+    /// use `NvsDataType::Any` to return all entries.
+    pub fn entries(
+        &self,
+        namespace: Option<&str>,
+        data_type: NvsDataType,
+    ) -> Result<EspNvsEntries<T>, EspError> {
+        let c_namespace = if let Some(namespace) = namespace {
+            Some(to_cstring_arg(namespace)?)
+        } else {
+            None
+        };
+
+        let namespace_ptr = match c_namespace {
+            Some(ref v) => v.as_ptr(),
+            None => ptr::null(),
+        };
+
+        let data_type = Newtype::<nvs_type_t>::from(data_type).0;
+
+        #[cfg(esp_idf_version_major = "4")]
+        let iterator = unsafe { nvs_entry_find(self.raw_name(), namespace_ptr, data_type) };
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        let iterator = {
+            let mut iterator: nvs_iterator_t = ptr::null_mut();
+
+            match unsafe {
+                nvs_entry_find(
+                    self.raw_name(),
+                    namespace_ptr,
+                    data_type,
+                    &mut iterator as *mut _,
+                )
+            } {
+                ESP_ERR_NVS_NOT_FOUND => (),
+                err => esp!(err)?,
+            }
+
+            iterator
+        };
+
+        Ok(EspNvsEntries {
+            _partition: self.clone(),
+            iterator,
+            fetched: false,
+        })
+    }
+
+    fn check_exclusive(&mut self) -> Result<(), EspError> {
+        if Arc::get_mut(&mut self.0).is_some() {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>())
+        }
+    }
+
+    fn raw_name(&self) -> *const c_char {
+        if self.0.is_default() {
+            NVS_DEFAULT_PART_NAME.as_ptr() as *const _
+        } else {
+            self.0.name().as_ptr()
+        }
+    }
 }
 
 impl<T> Clone for EspNvsPartition<T>
@@ -263,6 +612,164 @@ impl RawHandle for EspNvsPartition<NvsEncrypted> {
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum NvsDataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    Str,
+    Blob,
+    Any,
+}
+
+impl From<NvsDataType> for Newtype<nvs_type_t> {
+    fn from(data_type: NvsDataType) -> Self {
+        Self(match data_type {
+            NvsDataType::U8 => nvs_type_t_NVS_TYPE_U8,
+            NvsDataType::I8 => nvs_type_t_NVS_TYPE_I8,
+            NvsDataType::U16 => nvs_type_t_NVS_TYPE_U16,
+            NvsDataType::I16 => nvs_type_t_NVS_TYPE_I16,
+            NvsDataType::U32 => nvs_type_t_NVS_TYPE_U32,
+            NvsDataType::I32 => nvs_type_t_NVS_TYPE_I32,
+            NvsDataType::U64 => nvs_type_t_NVS_TYPE_U64,
+            NvsDataType::I64 => nvs_type_t_NVS_TYPE_I64,
+            NvsDataType::Str => nvs_type_t_NVS_TYPE_STR,
+            NvsDataType::Blob => nvs_type_t_NVS_TYPE_BLOB,
+            NvsDataType::Any => nvs_type_t_NVS_TYPE_ANY,
+        })
+    }
+}
+
+impl From<Newtype<nvs_type_t>> for NvsDataType {
+    #[allow(non_upper_case_globals)]
+    fn from(data_type: Newtype<nvs_type_t>) -> Self {
+        match data_type.0 {
+            nvs_type_t_NVS_TYPE_U8 => NvsDataType::U8,
+            nvs_type_t_NVS_TYPE_I8 => NvsDataType::I8,
+            nvs_type_t_NVS_TYPE_U16 => NvsDataType::U16,
+            nvs_type_t_NVS_TYPE_I16 => NvsDataType::I16,
+            nvs_type_t_NVS_TYPE_U32 => NvsDataType::U32,
+            nvs_type_t_NVS_TYPE_I32 => NvsDataType::I32,
+            nvs_type_t_NVS_TYPE_U64 => NvsDataType::U64,
+            nvs_type_t_NVS_TYPE_I64 => NvsDataType::I64,
+            nvs_type_t_NVS_TYPE_STR => NvsDataType::Str,
+            nvs_type_t_NVS_TYPE_BLOB => NvsDataType::Blob,
+            _ => NvsDataType::Any,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct NvsPartitionStats {
+    pub used_entries: usize,
+    pub free_entries: usize,
+    pub total_entries: usize,
+    pub namespace_count: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NvsEntryInfo {
+    pub namespace: heapless::String<16>,
+    pub key: heapless::String<16>,
+    pub data_type: NvsDataType,
+}
+
+/// An iterator over the entries of an NVS partition, as returned by `EspNvsPartition::entries`.
+pub struct EspNvsEntries<T: NvsPartitionId> {
+    _partition: EspNvsPartition<T>,
+    iterator: nvs_iterator_t,
+    fetched: bool,
+}
+
+impl<T: NvsPartitionId> EspNvsEntries<T> {
+    fn advance(&mut self) -> Result<(), EspError> {
+        #[cfg(esp_idf_version_major = "4")]
+        {
+            self.iterator = unsafe { nvs_entry_next(self.iterator) };
+        }
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        {
+            // nvs_entry_next releases the iterator and sets it to null once the last entry is passed
+            match unsafe { nvs_entry_next(&mut self.iterator as *mut _) } {
+                ESP_ERR_NVS_NOT_FOUND => self.iterator = ptr::null_mut(),
+                err => {
+                    if let Err(err) = esp!(err) {
+                        self.release();
+                        Err(err)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn info(&self) -> Result<NvsEntryInfo, EspError> {
+        let mut info: nvs_entry_info_t = Default::default();
+
+        #[cfg(esp_idf_version_major = "4")]
+        unsafe {
+            nvs_entry_info(self.iterator, &mut info as *mut _)
+        };
+
+        #[cfg(not(esp_idf_version_major = "4"))]
+        esp!(unsafe { nvs_entry_info(self.iterator, &mut info as *mut _) })?;
+
+        Ok(NvsEntryInfo {
+            namespace: unsafe { from_cstr_ptr(&info.namespace_name as *const _) }
+                .try_into()
+                .unwrap(),
+            key: unsafe { from_cstr_ptr(&info.key as *const _) }
+                .try_into()
+                .unwrap(),
+            data_type: Newtype(info.type_).into(),
+        })
+    }
+
+    fn release(&mut self) {
+        if !self.iterator.is_null() {
+            unsafe { nvs_release_iterator(self.iterator) };
+
+            self.iterator = ptr::null_mut();
+        }
+    }
+}
+
+impl<T: NvsPartitionId> Iterator for EspNvsEntries<T> {
+    type Item = Result<NvsEntryInfo, EspError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.fetched && !self.iterator.is_null() {
+            if let Err(err) = self.advance() {
+                return Some(Err(err));
+            }
+        }
+
+        self.fetched = true;
+
+        if self.iterator.is_null() {
+            None
+        } else {
+            Some(self.info())
+        }
+    }
+}
+
+impl<T: NvsPartitionId> Drop for EspNvsEntries<T> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+unsafe impl<T: NvsPartitionId> Send for EspNvsEntries<T> {}
+
 pub type EspDefaultNvs = EspNvs<NvsDefault>;
 pub type EspCustomNvs = EspNvs<NvsCustom>;
 pub type EspEncryptedNvs = EspNvs<NvsEncrypted>;
@@ -276,6 +783,10 @@ impl<T: NvsPartitionId> EspNvs<T> {
         namespace: &str,
         read_write: bool,
     ) -> Result<Self, EspError> {
+        if read_write && partition.0.is_read_only() {
+            return Err(EspError::from_infallible::<ESP_ERR_NVS_READ_ONLY>());
+        }
+
         let c_namespace = to_cstring_arg(namespace)?;
 
         let mut handle: nvs_handle_t = 0;
@@ -307,7 +818,49 @@ impl<T: NvsPartitionId> EspNvs<T> {
             })?;
         }
 
-        Ok(Self(partition, handle))
+        let nvs = Self(partition, handle);
+
+        if read_write {
+            EspNvsTransaction::<T>::recover(handle)?;
+        }
+
+        Ok(nvs)
+    }
+
+    /// Returns the number of entries used by the namespace of this handle.
+    pub fn used_entries(&self) -> Result<usize, EspError> {
+        let mut used_entries: usize = 0;
+
+        esp!(unsafe { nvs_get_used_entry_count(self.1, &mut used_entries as *mut _) })?;
+
+        Ok(used_entries)
+    }
+
+    /// Starts a transaction on this handle.
+    ///
+    /// Writes done through the returned `EspNvsTransaction` are buffered in memory and only
+    /// applied when `EspNvsTransaction::commit` is called. Dropping the transaction without
+    /// committing discards all buffered writes.
+    ///
+    /// Committing is atomic: the writes are first stored as a journal under the reserved key
+    /// `__nvs_txn` of the namespace, and only then applied. Should applying them be interrupted
+    /// (e.g. by a power loss), the journal is replayed when the namespace is opened again in
+    /// read-write mode with `EspNvs::new`, so that either none or all of the writes take effect.
+    /// Note that handles opened in read-only mode do not replay the journal, so they may observe
+    /// a partially applied transaction until the namespace is opened in read-write mode.
+    pub fn transaction(&mut self) -> EspNvsTransaction<'_, T> {
+        EspNvsTransaction {
+            nvs: self,
+            writes: Vec::new(),
+        }
+    }
+
+    /// Removes all keys from the namespace of this handle.
+    pub fn erase_all(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { nvs_erase_all(self.1) })?;
+        esp!(unsafe { nvs_commit(self.1) })?;
+
+        Ok(())
     }
 
     pub fn contains(&self, name: &str) -> Result<bool, EspError> {
@@ -431,10 +984,19 @@ impl<T: NvsPartitionId> EspNvs<T> {
 
     pub fn set_raw(&mut self, name: &str, buf: &[u8]) -> Result<bool, EspError> {
         let c_key = to_cstring_arg(name)?;
+
+        Self::write_raw(self.1, &c_key, buf)?;
+
+        esp!(unsafe { nvs_commit(self.1) })?;
+
+        Ok(true)
+    }
+
+    fn write_raw(handle: nvs_handle_t, c_key: &CStr, buf: &[u8]) -> Result<(), EspError> {
         let mut u64value: u_int64_t = 0;
 
         // start by just clearing this key
-        unsafe { nvs_erase_key(self.1, c_key.as_ptr()) };
+        unsafe { nvs_erase_key(handle, c_key.as_ptr()) };
 
         if buf.len() < 8 {
             for v in buf.iter().rev() {
@@ -445,14 +1007,12 @@ impl<T: NvsPartitionId> EspNvs<T> {
             u64value <<= 8;
             u64value |= buf.len() as u_int64_t;
 
-            esp!(unsafe { nvs_set_u64(self.1, c_key.as_ptr(), u64value) })?;
+            esp!(unsafe { nvs_set_u64(handle, c_key.as_ptr(), u64value) })?;
         } else {
-            esp!(unsafe { nvs_set_blob(self.1, c_key.as_ptr(), buf.as_ptr().cast(), buf.len()) })?;
+            esp!(unsafe { nvs_set_blob(handle, c_key.as_ptr(), buf.as_ptr().cast(), buf.len()) })?;
         }
 
-        esp!(unsafe { nvs_commit(self.1) })?;
-
-        Ok(true)
+        Ok(())
     }
 
     pub fn blob_len(&self, name: &str) -> Result<Option<usize>, EspError> {
@@ -767,6 +1327,310 @@ impl<T: NvsPartitionId> EspNvs<T> {
     }
 }
 
+#[derive(Debug, PartialEq)]
+enum NvsWrite {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    Str(CString),
+    Blob(Vec<u8>),
+    Raw(Vec<u8>),
+    Remove,
+}
+
+/// A batch of writes to an `EspNvs` namespace, as returned by `EspNvs::transaction`.
+pub struct EspNvsTransaction<'a, T: NvsPartitionId> {
+    nvs: &'a mut EspNvs<T>,
+    writes: Vec<(CString, NvsWrite)>,
+}
+
+impl<'a, T: NvsPartitionId> EspNvsTransaction<'a, T> {
+    /// Returns the number of buffered writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), EspError> {
+        self.push(name, NvsWrite::Remove)
+    }
+
+    pub fn set_raw(&mut self, name: &str, buf: &[u8]) -> Result<(), EspError> {
+        self.push(name, NvsWrite::Raw(buf.to_vec()))
+    }
+
+    pub fn set_blob(&mut self, name: &str, buf: &[u8]) -> Result<(), EspError> {
+        self.push(name, NvsWrite::Blob(buf.to_vec()))
+    }
+
+    pub fn set_str(&mut self, name: &str, val: &str) -> Result<(), EspError> {
+        let c_val = to_cstring_arg(val)?;
+
+        self.push(name, NvsWrite::Str(c_val))
+    }
+
+    pub fn set_u8(&mut self, name: &str, val: u8) -> Result<(), EspError> {
+        self.push(name, NvsWrite::U8(val))
+    }
+
+    pub fn set_i8(&mut self, name: &str, val: i8) -> Result<(), EspError> {
+        self.push(name, NvsWrite::I8(val))
+    }
+
+    pub fn set_u16(&mut self, name: &str, val: u16) -> Result<(), EspError> {
+        self.push(name, NvsWrite::U16(val))
+    }
+
+    pub fn set_i16(&mut self, name: &str, val: i16) -> Result<(), EspError> {
+        self.push(name, NvsWrite::I16(val))
+    }
+
+    pub fn set_u32(&mut self, name: &str, val: u32) -> Result<(), EspError> {
+        self.push(name, NvsWrite::U32(val))
+    }
+
+    pub fn set_i32(&mut self, name: &str, val: i32) -> Result<(), EspError> {
+        self.push(name, NvsWrite::I32(val))
+    }
+
+    pub fn set_u64(&mut self, name: &str, val: u64) -> Result<(), EspError> {
+        self.push(name, NvsWrite::U64(val))
+    }
+
+    pub fn set_i64(&mut self, name: &str, val: i64) -> Result<(), EspError> {
+        self.push(name, NvsWrite::I64(val))
+    }
+
+    /// Atomically applies all buffered writes in order, see `EspNvs::transaction`.
+    ///
+    /// If storing the journal fails, none of the writes are applied. If applying the writes
+    /// fails after the journal is stored, the error is returned and the journal is kept, so that
+    /// the writes are applied again when the namespace is opened the next time.
+    pub fn commit(mut self) -> Result<(), EspError> {
+        let handle = self.nvs.1;
+        let writes = core::mem::take(&mut self.writes);
+
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let c_journal_key = to_cstring_arg(TRANSACTION_JOURNAL_KEY)?;
+        let journal = encode_journal(&writes);
+
+        esp!(unsafe {
+            nvs_set_blob(
+                handle,
+                c_journal_key.as_ptr(),
+                journal.as_ptr().cast(),
+                journal.len(),
+            )
+        })?;
+        esp!(unsafe { nvs_commit(handle) })?;
+
+        Self::apply_journal(handle, &c_journal_key, &writes)
+    }
+
+    /// Discards all buffered writes.
+    pub fn discard(self) {}
+
+    fn push(&mut self, name: &str, write: NvsWrite) -> Result<(), EspError> {
+        let c_key = to_cstring_arg(name)?;
+
+        self.writes.push((c_key, write));
+
+        Ok(())
+    }
+
+    /// Completes a transaction which was interrupted while applying its writes, if any.
+    fn recover(handle: nvs_handle_t) -> Result<(), EspError> {
+        let c_journal_key = to_cstring_arg(TRANSACTION_JOURNAL_KEY)?;
+
+        let mut len: usize = 0;
+
+        match unsafe { nvs_get_blob(handle, c_journal_key.as_ptr(), ptr::null_mut(), &mut len) } {
+            ESP_ERR_NVS_NOT_FOUND => return Ok(()),
+            err => esp!(err)?,
+        }
+
+        let mut journal = vec![0_u8; len];
+
+        esp!(unsafe {
+            nvs_get_blob(
+                handle,
+                c_journal_key.as_ptr(),
+                journal.as_mut_ptr().cast(),
+                &mut len,
+            )
+        })?;
+
+        if let Some(writes) = decode_journal(&journal) {
+            info!(
+                "Completing an interrupted transaction of {} write(s)",
+                writes.len()
+            );
+
+            Self::apply_journal(handle, &c_journal_key, &writes)
+        } else {
+            warn!("Discarding a corrupted transaction journal");
+
+            esp!(unsafe { nvs_erase_key(handle, c_journal_key.as_ptr()) })?;
+            esp!(unsafe { nvs_commit(handle) })
+        }
+    }
+
+    /// Applies the writes of a stored journal and removes the journal afterwards.
+    fn apply_journal(
+        handle: nvs_handle_t,
+        c_journal_key: &CStr,
+        writes: &[(CString, NvsWrite)],
+    ) -> Result<(), EspError> {
+        for (c_key, write) in writes {
+            Self::apply(handle, c_key, write)?;
+        }
+
+        esp!(unsafe { nvs_erase_key(handle, c_journal_key.as_ptr()) })?;
+        esp!(unsafe { nvs_commit(handle) })
+    }
+
+    fn apply(handle: nvs_handle_t, c_key: &CStr, write: &NvsWrite) -> Result<(), EspError> {
+        let key = c_key.as_ptr();
+
+        match write {
+            NvsWrite::U8(val) => esp!(unsafe { nvs_set_u8(handle, key, *val) }),
+            NvsWrite::I8(val) => esp!(unsafe { nvs_set_i8(handle, key, *val) }),
+            NvsWrite::U16(val) => esp!(unsafe { nvs_set_u16(handle, key, *val) }),
+            NvsWrite::I16(val) => esp!(unsafe { nvs_set_i16(handle, key, *val) }),
+            NvsWrite::U32(val) => esp!(unsafe { nvs_set_u32(handle, key, *val) }),
+            NvsWrite::I32(val) => esp!(unsafe { nvs_set_i32(handle, key, *val) }),
+            NvsWrite::U64(val) => esp!(unsafe { nvs_set_u64(handle, key, *val) }),
+            NvsWrite::I64(val) => esp!(unsafe { nvs_set_i64(handle, key, *val) }),
+            NvsWrite::Str(val) => {
+                // start by just clearing this key
+                unsafe { nvs_erase_key(handle, key) };
+
+                esp!(unsafe { nvs_set_str(handle, key, val.as_ptr()) })
+            }
+            NvsWrite::Blob(buf) => {
+                // start by just clearing this key
+                unsafe { nvs_erase_key(handle, key) };
+
+                esp!(unsafe { nvs_set_blob(handle, key, buf.as_ptr().cast(), buf.len()) })
+            }
+            NvsWrite::Raw(buf) => EspNvs::<T>::write_raw(handle, c_key, buf),
+            NvsWrite::Remove => match unsafe { nvs_erase_key(handle, key) } {
+                ESP_ERR_NVS_NOT_FOUND => Ok(()),
+                err => esp!(err),
+            },
+        }
+    }
+}
+
+/// Serializes the writes of a transaction, as a sequence of key length, key, type tag and value.
+fn encode_journal(writes: &[(CString, NvsWrite)]) -> Vec<u8> {
+    let mut journal = Vec::new();
+
+    for (c_key, write) in writes {
+        let key = c_key.as_bytes();
+
+        journal.push(key.len() as u8);
+        journal.extend_from_slice(key);
+
+        let (tag, value) = match write {
+            NvsWrite::U8(val) => (0, val.to_le_bytes().to_vec()),
+            NvsWrite::I8(val) => (1, val.to_le_bytes().to_vec()),
+            NvsWrite::U16(val) => (2, val.to_le_bytes().to_vec()),
+            NvsWrite::I16(val) => (3, val.to_le_bytes().to_vec()),
+            NvsWrite::U32(val) => (4, val.to_le_bytes().to_vec()),
+            NvsWrite::I32(val) => (5, val.to_le_bytes().to_vec()),
+            NvsWrite::U64(val) => (6, val.to_le_bytes().to_vec()),
+            NvsWrite::I64(val) => (7, val.to_le_bytes().to_vec()),
+            NvsWrite::Str(val) => (8, val.as_bytes().to_vec()),
+            NvsWrite::Blob(buf) => (9, buf.clone()),
+            NvsWrite::Raw(buf) => (10, buf.clone()),
+            NvsWrite::Remove => (11, Vec::new()),
+        };
+
+        journal.push(tag);
+
+        if matches!(tag, 8..=10) {
+            journal.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        }
+
+        journal.extend_from_slice(&value);
+    }
+
+    journal
+}
+
+/// Deserializes the writes of a transaction, returning `None` if the journal is malformed.
+fn decode_journal(mut journal: &[u8]) -> Option<Vec<(CString, NvsWrite)>> {
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = (data.get(..len)?, data.get(len..)?);
+        *data = rest;
+
+        Some(taken)
+    }
+
+    fn take_array<const N: usize>(data: &mut &[u8]) -> Option<[u8; N]> {
+        take(data, N)?.try_into().ok()
+    }
+
+    let mut writes = Vec::new();
+
+    while !journal.is_empty() {
+        let [key_len] = take_array(&mut journal)?;
+        let c_key = CString::new(take(&mut journal, key_len as usize)?).ok()?;
+
+        let [tag] = take_array(&mut journal)?;
+
+        let write = match tag {
+            0 => NvsWrite::U8(u8::from_le_bytes(take_array(&mut journal)?)),
+            1 => NvsWrite::I8(i8::from_le_bytes(take_array(&mut journal)?)),
+            2 => NvsWrite::U16(u16::from_le_bytes(take_array(&mut journal)?)),
+            3 => NvsWrite::I16(i16::from_le_bytes(take_array(&mut journal)?)),
+            4 => NvsWrite::U32(u32::from_le_bytes(take_array(&mut journal)?)),
+            5 => NvsWrite::I32(i32::from_le_bytes(take_array(&mut journal)?)),
+            6 => NvsWrite::U64(u64::from_le_bytes(take_array(&mut journal)?)),
+            7 => NvsWrite::I64(i64::from_le_bytes(take_array(&mut journal)?)),
+            8..=10 => {
+                let len = u32::from_le_bytes(take_array(&mut journal)?) as usize;
+                let value = take(&mut journal, len)?.to_vec();
+
+                match tag {
+                    8 => NvsWrite::Str(CString::new(value).ok()?),
+                    9 => NvsWrite::Blob(value),
+                    _ => NvsWrite::Raw(value),
+                }
+            }
+            11 => NvsWrite::Remove,
+            _ => return None,
+        };
+
+        writes.push((c_key, write));
+    }
+
+    Some(writes)
+}
+
+impl<'a, T: NvsPartitionId> Drop for EspNvsTransaction<'a, T> {
+    fn drop(&mut self) {
+        if !self.writes.is_empty() {
+            info!(
+                "EspNvsTransaction dropped, {} write(s) discarded",
+                self.writes.len()
+            );
+        }
+    }
+}
+
 impl<T: NvsPartitionId> Drop for EspNvs<T> {
     fn drop(&mut self) {
         unsafe {
@@ -819,3 +1683,41 @@ impl<T: NvsPartitionId> RawStorage for EspNvs<T> {
         EspNvs::set_raw(self, name, buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transaction_journal_roundtrip() {
+        let key = |key: &str| CString::new(key).unwrap();
+
+        let writes = vec![
+            (key("a"), NvsWrite::U8(0xab)),
+            (key("b"), NvsWrite::I16(-2)),
+            (key("c"), NvsWrite::U64(u64::MAX - 1)),
+            (key("d"), NvsWrite::Str(key("value"))),
+            (key("e"), NvsWrite::Blob(vec![1, 2, 3])),
+            (key("f"), NvsWrite::Raw(vec![])),
+            (key("g"), NvsWrite::Remove),
+        ];
+
+        let journal = encode_journal(&writes);
+
+        assert_eq!(decode_journal(&journal), Some(writes));
+        assert_eq!(decode_journal(&[]), Some(vec![]));
+    }
+
+    #[test]
+    fn rejects_malformed_transaction_journals() {
+        let journal =
+            encode_journal(&[(CString::new("key").unwrap(), NvsWrite::Blob(vec![1, 2, 3]))]);
+
+        for len in 1..journal.len() {
+            assert_eq!(decode_journal(&journal[..len]), None);
+        }
+
+        assert_eq!(decode_journal(&[1, b'a', 12]), None);
+        assert_eq!(decode_journal(&[1, 0, 11]), None);
+    }
+}
@@ -0,0 +1,395 @@
+//! Typed and versioned storage of `serde` values on top of `EspNvs`
+//!
+//! `NvsStore` serializes a value with `postcard` and stores it as a blob which is prefixed
+//! with a schema version and a generation counter, and followed by a CRC32 checksum.
+//! Corrupted blobs, as well as blobs with a schema version which cannot be migrated to
+//! the current one, are reported as errors rather than deserialized.
+//!
+//! Each value occupies two NVS keys (`<key>.0` and `<key>.1`) which are written alternately,
+//! so that a power loss in the middle of a write always leaves the previous value intact.
+//!
+//! ```
+//! use esp_idf_svc::nvs::store::NvsStore;
+//! use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+//!
+//! let nvs = EspNvs::new(EspDefaultNvsPartition::take()?, "config", true)?;
+//!
+//! let mut store = NvsStore::<Settings, _>::new(nvs, "settings", 2)?
+//!     .migration(1, |payload| migrate_settings_v1_to_v2(payload));
+//!
+//! let settings = store.load()?.unwrap_or_default();
+//! store.store(&settings)?;
+//! ```
+use core::fmt::Write as _;
+use core::marker::PhantomData;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::sys::*;
+
+use super::{EspNvs, NvsPartitionId};
+
+const HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 4;
+
+type Migration = Box<dyn Fn(&[u8]) -> Result<Vec<u8>, EspError> + Send>;
+
+enum Slot {
+    Empty,
+    Corrupted,
+    Valid {
+        version: u8,
+        generation: u32,
+        payload: Vec<u8>,
+    },
+}
+
+pub struct NvsStore<T, P>
+where
+    P: NvsPartitionId,
+{
+    nvs: EspNvs<P>,
+    keys: [heapless::String<15>; 2],
+    version: u8,
+    migrations: BTreeMap<u8, Migration>,
+    current: Option<(usize, u32)>,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T, P> NvsStore<T, P>
+where
+    T: Serialize + DeserializeOwned,
+    P: NvsPartitionId,
+{
+    /// Creates a new store for values of type `T`, persisted under `key` in the namespace of `nvs`.
+    ///
+    /// `version` is the current schema version of `T`. As the store uses two NVS keys
+    /// derived from `key`, `key` should not be longer than 13 characters.
+    pub fn new(nvs: EspNvs<P>, key: &str, version: u8) -> Result<Self, EspError> {
+        let mut keys: [heapless::String<15>; 2] = Default::default();
+
+        for (index, slot_key) in keys.iter_mut().enumerate() {
+            write!(slot_key, "{key}.{index}")
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_NVS_KEY_TOO_LONG>())?;
+        }
+
+        Ok(Self {
+            nvs,
+            keys,
+            version,
+            migrations: BTreeMap::new(),
+            current: None,
+            _type: PhantomData,
+        })
+    }
+
+    /// Registers a migration hook converting a serialized payload of schema version `from_version`
+    /// into a serialized payload of schema version `from_version + 1`.
+    ///
+    /// When a value with an older schema version is loaded, all hooks up to the current schema
+    /// version are applied in sequence and the migrated value is written back.
+    pub fn migration<F>(mut self, from_version: u8, migration: F) -> Self
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>, EspError> + Send + 'static,
+    {
+        self.migrations.insert(from_version, Box::new(migration));
+
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Loads the stored value, if any.
+    ///
+    /// Returns `ESP_ERR_INVALID_CRC` if the stored value is corrupted and
+    /// `ESP_ERR_INVALID_VERSION` if it cannot be migrated to the current schema version.
+    pub fn load(&mut self) -> Result<Option<T>, EspError> {
+        let slots = [self.read_slot(0)?, self.read_slot(1)?];
+        let corrupted = slots.iter().any(|slot| matches!(slot, Slot::Corrupted));
+
+        let Some((index, mut version, generation, mut payload)) = latest(slots) else {
+            self.current = None;
+
+            if corrupted {
+                warn!("No valid copy of key {} found", self.keys[0]);
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_CRC>());
+            }
+
+            return Ok(None);
+        };
+
+        if corrupted {
+            warn!(
+                "Falling back to the last valid copy of key {}",
+                self.keys[index]
+            );
+        }
+
+        if version > self.version {
+            warn!(
+                "Stored schema version {} of key {} is newer than the current one ({})",
+                version, self.keys[index], self.version
+            );
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_VERSION>());
+        }
+
+        let migrated = version < self.version;
+
+        while version < self.version {
+            let Some(migration) = self.migrations.get(&version) else {
+                warn!(
+                    "No migration from schema version {} registered for key {}",
+                    version, self.keys[index]
+                );
+                return Err(EspError::from_infallible::<ESP_ERR_INVALID_VERSION>());
+            };
+
+            payload = migration(&payload)?;
+            version += 1;
+        }
+
+        let value = postcard::from_bytes(&payload).map_err(|err| {
+            warn!("Deserializing key {} failed: {}", self.keys[index], err);
+            EspError::from_infallible::<ESP_FAIL>()
+        })?;
+
+        self.current = Some((index, generation));
+
+        if migrated {
+            info!(
+                "Key {} migrated to schema version {}",
+                self.keys[index], self.version
+            );
+
+            self.store(&value)?;
+        }
+
+        Ok(Some(value))
+    }
+
+    /// Stores the value, replacing the previously stored one only once the new value is committed.
+    pub fn store(&mut self, value: &T) -> Result<(), EspError> {
+        let (index, generation) = match self.current {
+            Some((index, generation)) => (1 - index, generation.wrapping_add(1)),
+            None => {
+                let slots = [self.read_slot(0)?, self.read_slot(1)?];
+
+                match latest(slots) {
+                    Some((index, _, generation, _)) => (1 - index, generation.wrapping_add(1)),
+                    None => (0, 0),
+                }
+            }
+        };
+
+        let mut data = Vec::new();
+        data.push(self.version);
+        data.extend_from_slice(&generation.to_le_bytes());
+
+        let mut data = postcard::to_extend(value, data).map_err(|err| {
+            warn!("Serializing key {} failed: {}", self.keys[index], err);
+            EspError::from_infallible::<ESP_FAIL>()
+        })?;
+
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+
+        self.nvs.set_blob(&self.keys[index], &data)?;
+
+        self.current = Some((index, generation));
+
+        Ok(())
+    }
+
+    /// Removes the stored value.
+    pub fn remove(&mut self) -> Result<bool, EspError> {
+        let removed1 = self.nvs.remove(&self.keys[1])?;
+        let removed0 = self.nvs.remove(&self.keys[0])?;
+
+        self.current = None;
+
+        Ok(removed0 || removed1)
+    }
+
+    /// Consumes the store, returning the underlying `EspNvs` handle.
+    pub fn release(self) -> EspNvs<P> {
+        self.nvs
+    }
+
+    fn read_slot(&self, index: usize) -> Result<Slot, EspError> {
+        let key = &self.keys[index];
+
+        let Some(len) = self.nvs.blob_len(key)? else {
+            return Ok(Slot::Empty);
+        };
+
+        let mut data = vec![0; len];
+        let Some(data) = self.nvs.get_blob(key, &mut data)? else {
+            return Ok(Slot::Empty);
+        };
+
+        let slot = decode_slot(data);
+
+        if matches!(slot, Slot::Corrupted) {
+            warn!("Key {} is truncated or corrupted (CRC mismatch)", key);
+        }
+
+        Ok(slot)
+    }
+}
+
+fn decode_slot(data: &[u8]) -> Slot {
+    if data.len() < HEADER_LEN + CRC_LEN {
+        return Slot::Corrupted;
+    }
+
+    let (data, crc) = data.split_at(data.len() - CRC_LEN);
+
+    if crc32(data).to_le_bytes() != crc {
+        return Slot::Corrupted;
+    }
+
+    Slot::Valid {
+        version: data[0],
+        generation: u32::from_le_bytes([data[1], data[2], data[3], data[4]]),
+        payload: data[HEADER_LEN..].to_vec(),
+    }
+}
+
+/// Returns the index, version, generation and payload of the most recently written valid slot.
+fn latest(slots: [Slot; 2]) -> Option<(usize, u8, u32, Vec<u8>)> {
+    slots
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, slot)| match slot {
+            Slot::Valid {
+                version,
+                generation,
+                payload,
+            } => Some((index, version, generation, payload)),
+            _ => None,
+        })
+        .reduce(|latest, slot| {
+            // The generation counter wraps around, so `u32::MAX` precedes 0
+            if (slot.2.wrapping_sub(latest.2) as i32) > 0 {
+                slot
+            } else {
+                latest
+            }
+        })
+}
+
+/// CRC-32 (IEEE 802.3)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn slot(version: u8, generation: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        data.push(version);
+        data.extend_from_slice(&generation.to_le_bytes());
+        data.extend_from_slice(payload);
+        data.extend_from_slice(&crc32(&data).to_le_bytes());
+
+        data
+    }
+
+    #[test]
+    fn computes_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn decodes_slots() {
+        assert!(matches!(
+            decode_slot(&slot(2, 7, b"value")),
+            Slot::Valid { version: 2, generation: 7, payload } if payload == b"value"
+        ));
+
+        let mut corrupted = slot(2, 7, b"value");
+        corrupted[HEADER_LEN] ^= 1;
+
+        assert!(matches!(decode_slot(&corrupted), Slot::Corrupted));
+        assert!(matches!(
+            decode_slot(&corrupted[..HEADER_LEN + CRC_LEN - 1]),
+            Slot::Corrupted
+        ));
+    }
+
+    #[test]
+    fn selects_latest_slot() {
+        let latest_index = |slots: [&[u8]; 2]| {
+            latest(slots.map(|data| {
+                if data.is_empty() {
+                    Slot::Empty
+                } else {
+                    decode_slot(data)
+                }
+            }))
+            .map(|(index, _, generation, _)| (index, generation))
+        };
+
+        assert_eq!(
+            latest_index([&slot(1, 4, b"a"), &slot(1, 5, b"b")]),
+            Some((1, 5))
+        );
+        assert_eq!(
+            latest_index([&slot(1, 6, b"a"), &slot(1, 5, b"b")]),
+            Some((0, 6))
+        );
+        assert_eq!(
+            latest_index([&slot(1, u32::MAX, b"a"), &slot(1, 0, b"b")]),
+            Some((1, 0))
+        );
+        assert_eq!(latest_index([&[], &slot(1, 3, b"b")]), Some((1, 3)));
+        assert_eq!(latest_index([&[], &[]]), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_other_slot() {
+        let mut corrupted = slot(1, 5, b"new");
+        corrupted[HEADER_LEN] ^= 1;
+
+        let slots = [decode_slot(&slot(1, 4, b"old")), decode_slot(&corrupted)];
+
+        assert!(matches!(
+            latest(slots),
+            Some((0, 1, 4, payload)) if payload == b"old"
+        ));
+
+        assert!(latest([decode_slot(&corrupted), Slot::Empty]).is_none());
+    }
+}
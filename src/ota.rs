@@ -23,6 +23,20 @@ use crate::sys::*;
 use crate::io::EspIOError;
 use crate::private::{common::*, cstr::*, mutex};
 
+pub mod delta;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_esp_timer_enabled,
+    esp_idf_comp_nvs_flash_enabled
+))]
+pub mod health;
+#[cfg(all(feature = "alloc", esp_idf_comp_esp_http_client_enabled))]
+pub mod http;
+pub mod verify;
+
+use self::delta::{DeltaDecoder, DeltaSource};
+use self::verify::{ImageVerifier, Sha256, SHA256_LEN};
+
 static TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
 
 impl From<Newtype<&esp_app_desc_t>> for FirmwareInfo {
@@ -188,6 +202,22 @@ impl<'a> EspOtaUpdate<'a> {
         Ok(())
     }
 
+    /// Adds a verification stage to the update.
+    ///
+    /// The SHA-256 digest of all data written from now on is computed and checked with
+    /// `verifier` when the update is finished or completed. If the check fails, the update
+    /// is aborted with `ESP_ERR_OTA_VALIDATE_FAILED` and the slot is never activated.
+    pub fn verified<V>(self, verifier: V) -> EspVerifiedOtaUpdate<'a, V>
+    where
+        V: ImageVerifier,
+    {
+        EspVerifiedOtaUpdate {
+            update: self,
+            sha: Sha256::new(),
+            verifier,
+        }
+    }
+
     fn check_write(&self) -> Result<(), EspError> {
         if !self.update_partition.is_null() {
             Ok(())
@@ -211,6 +241,126 @@ impl Drop for EspOtaUpdate<'_> {
     }
 }
 
+/// An OTA update which is verified before being finished.
+///
+/// Created with `EspOtaUpdate::verified`.
+#[derive(Debug)]
+pub struct EspVerifiedOtaUpdate<'a, V> {
+    update: EspOtaUpdate<'a>,
+    sha: Sha256,
+    verifier: V,
+}
+
+impl<'a, V> EspVerifiedOtaUpdate<'a, V>
+where
+    V: ImageVerifier,
+{
+    pub fn write(&mut self, buf: &[u8]) -> Result<(), EspError> {
+        self.update.write(buf)?;
+        self.sha.update(buf);
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), EspError> {
+        self.update.flush()
+    }
+
+    /// Returns the SHA-256 digest of the data written so far.
+    pub fn digest(&self) -> [u8; SHA256_LEN] {
+        self.sha.clone().finalize()
+    }
+
+    pub fn finish(self) -> Result<EspOtaUpdateFinished<'a>, EspError> {
+        self.verify()?;
+
+        self.update.finish()
+    }
+
+    pub fn complete(self) -> Result<(), EspError> {
+        self.verify()?;
+
+        self.update.complete()
+    }
+
+    pub fn abort(self) -> Result<(), EspError> {
+        self.update.abort()
+    }
+
+    fn verify(&self) -> Result<(), EspError> {
+        if self.verifier.verify(&self.digest()) {
+            Ok(())
+        } else {
+            // The OTA update is aborted when `self` is dropped by the caller
+            warn!("Image verification failed, aborting the update");
+
+            Err(EspError::from_infallible::<ESP_ERR_OTA_VALIDATE_FAILED>())
+        }
+    }
+}
+
+/// The firmware of the running slot, which delta updates are applied to
+#[derive(Debug)]
+struct RunningImage(*const esp_partition_t);
+
+impl DeltaSource for RunningImage {
+    type Error = EspError;
+
+    fn size(&self) -> u64 {
+        unsafe { (*self.0).size as _ }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        esp!(unsafe {
+            esp_partition_read(self.0, offset as _, buf.as_mut_ptr() as _, buf.len() as _)
+        })
+    }
+}
+
+/// A delta OTA update, i.e. an update where a patch against the firmware of the running
+/// slot is written, rather than the new image itself.
+///
+/// Created with `EspOta::initiate_delta_update`. See the `delta` module for the patch format.
+#[derive(Debug)]
+pub struct EspDeltaOtaUpdate<'a> {
+    update: EspOtaUpdate<'a>,
+    decoder: DeltaDecoder<RunningImage>,
+}
+
+impl<'a> EspDeltaOtaUpdate<'a> {
+    /// Writes the next chunk of the patch.
+    pub fn write(&mut self, patch: &[u8]) -> Result<(), EspError> {
+        let update = &mut self.update;
+
+        self.decoder.decode(patch, |data| update.write(data))
+    }
+
+    pub fn flush(&mut self) -> Result<(), EspError> {
+        self.update.flush()
+    }
+
+    /// Returns the number of bytes of the new image written so far.
+    pub fn written(&self) -> u64 {
+        self.decoder.written()
+    }
+
+    pub fn finish(self) -> Result<EspOtaUpdateFinished<'a>, EspError> {
+        self.decoder.finish()?;
+
+        self.update.finish()
+    }
+
+    pub fn complete(self) -> Result<(), EspError> {
+        self.decoder.finish()?;
+
+        self.update.complete()
+    }
+
+    pub fn abort(self) -> Result<(), EspError> {
+        self.update.abort()
+    }
+}
+
 #[derive(Debug)]
 pub struct EspOtaUpdateFinished<'a> {
     update_partition: *const esp_partition_t,
@@ -302,6 +452,23 @@ impl EspOta {
         })
     }
 
+    /// Initiates a delta update, which reconstructs the new image into the update slot
+    /// from a patch against the firmware of the running slot.
+    pub fn initiate_delta_update(&mut self) -> Result<EspDeltaOtaUpdate<'_>, EspError> {
+        let running = unsafe { esp_ota_get_running_partition() };
+
+        if running.is_null() {
+            return Err(EspError::from_infallible::<ESP_ERR_NOT_FOUND>());
+        }
+
+        let update = self.initiate_update()?;
+
+        Ok(EspDeltaOtaUpdate {
+            update,
+            decoder: DeltaDecoder::new(RunningImage(running)),
+        })
+    }
+
     pub fn mark_running_slot_valid(&mut self) -> Result<(), EspError> {
         Ok(esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() })?)
     }
@@ -479,6 +646,94 @@ impl<'a> io::Write for EspOtaUpdate<'a> {
     }
 }
 
+impl<'a, V> io::ErrorType for EspVerifiedOtaUpdate<'a, V> {
+    type Error = EspIOError;
+}
+
+impl<'a, V> OtaUpdate for EspVerifiedOtaUpdate<'a, V>
+where
+    V: ImageVerifier,
+{
+    type OtaUpdateFinished = EspOtaUpdateFinished<'a>;
+
+    fn finish(self) -> Result<Self::OtaUpdateFinished, Self::Error> {
+        let finish = EspVerifiedOtaUpdate::finish(self)?;
+
+        Ok(finish)
+    }
+
+    fn complete(self) -> Result<(), Self::Error> {
+        EspVerifiedOtaUpdate::complete(self)?;
+
+        Ok(())
+    }
+
+    fn abort(self) -> Result<(), Self::Error> {
+        EspVerifiedOtaUpdate::abort(self)?;
+
+        Ok(())
+    }
+}
+
+impl<'a, V> io::Write for EspVerifiedOtaUpdate<'a, V>
+where
+    V: ImageVerifier,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        EspVerifiedOtaUpdate::write(self, buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        EspVerifiedOtaUpdate::flush(self)?;
+
+        Ok(())
+    }
+}
+
+unsafe impl<'a> Send for EspDeltaOtaUpdate<'a> {}
+
+impl<'a> io::ErrorType for EspDeltaOtaUpdate<'a> {
+    type Error = EspIOError;
+}
+
+impl<'a> OtaUpdate for EspDeltaOtaUpdate<'a> {
+    type OtaUpdateFinished = EspOtaUpdateFinished<'a>;
+
+    fn finish(self) -> Result<Self::OtaUpdateFinished, Self::Error> {
+        let finish = EspDeltaOtaUpdate::finish(self)?;
+
+        Ok(finish)
+    }
+
+    fn complete(self) -> Result<(), Self::Error> {
+        EspDeltaOtaUpdate::complete(self)?;
+
+        Ok(())
+    }
+
+    fn abort(self) -> Result<(), Self::Error> {
+        EspDeltaOtaUpdate::abort(self)?;
+
+        Ok(())
+    }
+}
+
+impl<'a> io::Write for EspDeltaOtaUpdate<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        EspDeltaOtaUpdate::write(self, buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        EspDeltaOtaUpdate::flush(self)?;
+
+        Ok(())
+    }
+}
+
 unsafe impl<'a> Send for EspOtaUpdateFinished<'a> {}
 
 impl<'a> io::ErrorType for EspOtaUpdateFinished<'a> {
@@ -0,0 +1,405 @@
+//! Streaming decoder for delta (patch-based) OTA updates
+//!
+//! A delta update only transfers the difference between the running firmware and the new one.
+//! The patch format is the one of `bsdiff`, without compression (as produced by e.g. the `bsdiff`
+//! Rust crate): a sequence of records, each consisting of
+//! - a control block of three 8-byte sign-magnitude little-endian integers:
+//!   the "add" length, the "copy" length and the "seek" offset
+//! - "add" bytes, which are added bytewise to the same number of bytes of the old image
+//! - "copy" bytes, which are copied as-is
+//!
+//! after which the position in the old image is moved by the seek offset.
+//! Compression, if any, should be applied to the transfer (e.g. `Content-Encoding`).
+//!
+//! `DeltaDecoder` consumes the patch in chunks of any size and emits the new image as it is
+//! reconstructed, so neither the patch nor the new image need to be kept in memory.
+//! The decoder does not depend on ESP-IDF: the old image is read through the `DeltaSource` trait,
+//! which is implemented for byte slices as well as for the running OTA slot
+//! (see `EspOta::initiate_delta_update`).
+
+use core::convert::Infallible;
+use core::fmt::{self, Debug, Display};
+
+use crate::sys::{EspError, ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_SIZE};
+
+const CONTROL_LEN: usize = 24;
+const CHUNK_LEN: usize = 256;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeltaError {
+    /// The patch is malformed or truncated
+    InvalidPatch,
+    /// The patch refers to data outside of the old image
+    OutOfBounds,
+}
+
+impl Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPatch => write!(f, "Invalid patch"),
+            Self::OutOfBounds => write!(f, "Patch refers to data outside of the old image"),
+        }
+    }
+}
+
+impl From<Infallible> for DeltaError {
+    fn from(err: Infallible) -> Self {
+        match err {}
+    }
+}
+
+impl From<DeltaError> for EspError {
+    fn from(err: DeltaError) -> Self {
+        match err {
+            DeltaError::InvalidPatch => EspError::from_infallible::<ESP_ERR_INVALID_ARG>(),
+            DeltaError::OutOfBounds => EspError::from_infallible::<ESP_ERR_INVALID_SIZE>(),
+        }
+    }
+}
+
+/// The old image a patch is applied to
+pub trait DeltaSource {
+    type Error;
+
+    /// The length of the old image
+    fn size(&self) -> u64;
+
+    /// Reads `buf.len()` bytes at `offset`, which is guaranteed to be within the old image.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+impl DeltaSource for &[u8] {
+    type Error = Infallible;
+
+    fn size(&self) -> u64 {
+        <[u8]>::len(self) as _
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+
+        buf.copy_from_slice(&self[offset..offset + buf.len()]);
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Control,
+    Add(u64),
+    Copy(u64),
+}
+
+pub struct DeltaDecoder<S> {
+    source: S,
+    state: State,
+    control: [u8; CONTROL_LEN],
+    control_len: usize,
+    copy: u64,
+    seek: i64,
+    old_pos: u64,
+    written: u64,
+}
+
+impl<S> DeltaDecoder<S>
+where
+    S: DeltaSource,
+{
+    pub const fn new(source: S) -> Self {
+        Self {
+            source,
+            state: State::Control,
+            control: [0; CONTROL_LEN],
+            control_len: 0,
+            copy: 0,
+            seek: 0,
+            old_pos: 0,
+            written: 0,
+        }
+    }
+
+    /// The number of bytes of the new image emitted so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Decodes the next chunk of the patch, passing the reconstructed data of the new image to `sink`.
+    pub fn decode<F, E>(&mut self, mut patch: &[u8], mut sink: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+        E: From<DeltaError> + From<S::Error>,
+    {
+        while !patch.is_empty() {
+            match self.state {
+                State::Control => {
+                    let len = (CONTROL_LEN - self.control_len).min(patch.len());
+
+                    self.control[self.control_len..self.control_len + len]
+                        .copy_from_slice(&patch[..len]);
+                    self.control_len += len;
+                    patch = &patch[len..];
+
+                    if self.control_len == CONTROL_LEN {
+                        self.control_len = 0;
+                        self.start_record()?;
+                    }
+                }
+                State::Add(left) => {
+                    let len = (left.min(CHUNK_LEN as u64) as usize).min(patch.len());
+
+                    let mut chunk = [0; CHUNK_LEN];
+                    let chunk = &mut chunk[..len];
+
+                    self.source.read_at(self.old_pos, chunk)?;
+
+                    for (new, diff) in chunk.iter_mut().zip(&patch[..len]) {
+                        *new = new.wrapping_add(*diff);
+                    }
+
+                    sink(chunk)?;
+
+                    self.old_pos += len as u64;
+                    self.written += len as u64;
+                    patch = &patch[len..];
+
+                    self.state = State::Add(left - len as u64);
+                    self.next_state()?;
+                }
+                State::Copy(left) => {
+                    let len = left.min(patch.len() as u64) as usize;
+
+                    sink(&patch[..len])?;
+
+                    self.written += len as u64;
+                    patch = &patch[len..];
+
+                    self.state = State::Copy(left - len as u64);
+                    self.next_state()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the patch ended on a record boundary and returns the size of the new image.
+    pub fn finish(self) -> Result<u64, DeltaError> {
+        if self.state == State::Control && self.control_len == 0 {
+            Ok(self.written)
+        } else {
+            Err(DeltaError::InvalidPatch)
+        }
+    }
+
+    /// Consumes the decoder, returning the old image source.
+    pub fn release(self) -> S {
+        self.source
+    }
+
+    fn start_record(&mut self) -> Result<(), DeltaError> {
+        let add = offtin(&self.control[0..8]);
+        let copy = offtin(&self.control[8..16]);
+
+        if add < 0 || copy < 0 {
+            return Err(DeltaError::InvalidPatch);
+        }
+
+        let add_end = self
+            .old_pos
+            .checked_add(add as u64)
+            .ok_or(DeltaError::OutOfBounds)?;
+
+        if add_end > self.source.size() {
+            return Err(DeltaError::OutOfBounds);
+        }
+
+        self.copy = copy as u64;
+        self.seek = offtin(&self.control[16..24]);
+        self.state = State::Add(add as u64);
+
+        self.next_state()
+    }
+
+    /// Moves past the parts of the current record which are done
+    fn next_state(&mut self) -> Result<(), DeltaError> {
+        loop {
+            match self.state {
+                State::Add(0) => self.state = State::Copy(self.copy),
+                State::Copy(0) => {
+                    self.old_pos = self
+                        .old_pos
+                        .checked_add_signed(self.seek)
+                        .ok_or(DeltaError::OutOfBounds)?;
+                    self.state = State::Control;
+                }
+                _ => break Ok(()),
+            }
+        }
+    }
+}
+
+impl<S> Debug for DeltaDecoder<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeltaDecoder")
+            .field("state", &self.state)
+            .field("old_pos", &self.old_pos)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+/// Decodes an 8-byte sign-magnitude little-endian integer
+fn offtin(buf: &[u8]) -> i64 {
+    let value = u64::from_le_bytes([
+        buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
+    ]);
+
+    let magnitude = (value & !(1 << 63)) as i64;
+
+    if value & (1 << 63) != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn offtout(value: i64, out: &mut Vec<u8>) {
+        let encoded = if value < 0 {
+            value.unsigned_abs() | (1 << 63)
+        } else {
+            value as u64
+        };
+
+        out.extend_from_slice(&encoded.to_le_bytes());
+    }
+
+    /// Builds a patch from `(add, copy, seek)` records, where `add` is the
+    /// new data which is diffed against the old image at the current position
+    fn patch(old: &[u8], records: &[(&[u8], &[u8], i64)]) -> Vec<u8> {
+        let mut patch = Vec::new();
+        let mut old_pos = 0_i64;
+
+        for (add, copy, seek) in records {
+            offtout(add.len() as _, &mut patch);
+            offtout(copy.len() as _, &mut patch);
+            offtout(*seek, &mut patch);
+
+            for (index, new) in add.iter().enumerate() {
+                patch.push(new.wrapping_sub(old[old_pos as usize + index]));
+            }
+
+            patch.extend_from_slice(copy);
+
+            old_pos += add.len() as i64 + seek;
+        }
+
+        patch
+    }
+
+    fn apply(old: &[u8], patch: &[u8], chunk_len: usize) -> Result<Vec<u8>, DeltaError> {
+        let mut decoder = DeltaDecoder::new(old);
+        let mut new = Vec::new();
+
+        for chunk in patch.chunks(chunk_len) {
+            decoder.decode(chunk, |data| {
+                new.extend_from_slice(data);
+                Ok::<_, DeltaError>(())
+            })?;
+        }
+
+        assert_eq!(decoder.finish()?, new.len() as u64);
+
+        Ok(new)
+    }
+
+    #[test]
+    fn offtin_sign_magnitude() {
+        let mut buf = Vec::new();
+
+        offtout(1234, &mut buf);
+        offtout(-1234, &mut buf);
+
+        assert_eq!(offtin(&buf[0..8]), 1234);
+        assert_eq!(offtin(&buf[8..16]), -1234);
+    }
+
+    #[test]
+    fn apply_patch() {
+        let old = b"The quick brown fox jumps over the lazy dog";
+        let new = b"The quick red fox jumps over the lazy cat!";
+
+        let patch = patch(
+            old,
+            &[
+                (b"The quick ", b"red", 5),
+                (b" fox jumps over the lazy ", b"cat!", 0),
+            ],
+        );
+
+        for chunk_len in [1, 7, 24, patch.len()] {
+            assert_eq!(apply(old, &patch, chunk_len).unwrap(), new);
+        }
+    }
+
+    #[test]
+    fn apply_patch_with_backward_seek() {
+        let old = b"abcdefgh";
+        let new = b"abcdXYabcd";
+
+        let patch = patch(old, &[(b"abcd", b"XY", -4), (b"abcd", b"", 0)]);
+
+        assert_eq!(apply(old, &patch, 3).unwrap(), new);
+    }
+
+    #[test]
+    fn large_add() {
+        let old: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let new: Vec<u8> = (0..2000).map(|i| (i * 7) as u8).collect();
+
+        let patch = patch(&old, &[(&new, b"", 0)]);
+
+        assert_eq!(apply(&old, &patch, 100).unwrap(), new);
+    }
+
+    #[test]
+    fn truncated_patch() {
+        let old = b"abcdefgh";
+
+        let patch = patch(old, &[(b"abcd", b"XY", 0)]);
+
+        assert_eq!(
+            apply(old, &patch[..patch.len() - 1], 5),
+            Err(DeltaError::InvalidPatch)
+        );
+        assert_eq!(apply(old, &patch[..10], 5), Err(DeltaError::InvalidPatch));
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let old = b"abcd";
+
+        let mut patch = Vec::new();
+        offtout(5, &mut patch);
+        offtout(0, &mut patch);
+        offtout(0, &mut patch);
+        patch.extend_from_slice(&[0; 5]);
+
+        assert_eq!(apply(old, &patch, 8), Err(DeltaError::OutOfBounds));
+
+        let mut patch = Vec::new();
+        offtout(0, &mut patch);
+        offtout(0, &mut patch);
+        offtout(-1, &mut patch);
+
+        assert_eq!(apply(old, &patch, 8), Err(DeltaError::OutOfBounds));
+    }
+}
@@ -0,0 +1,306 @@
+//! Boot-health confirmation of freshly updated firmware
+//!
+//! After an update, the bootloader starts the new firmware in the `Unverified` state and - with
+//! `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE` - rolls back to the previous firmware on the next boot,
+//! unless the new firmware marks itself as valid.
+//!
+//! `OtaHealthCheck` runs a set of user-supplied checks (e.g. "network is up", "MQTT is connected")
+//! until they all pass or a deadline expires, and then either marks the running slot as valid or
+//! rolls back. A watchdog timer rolls back as well, should one of the checks hang past the deadline.
+//! The outcome is recorded in NVS, so that it can be reported after the reboot.
+//!
+//! ```
+//! use core::time::Duration;
+//!
+//! use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+//! use esp_idf_svc::ota::health::OtaHealthCheck;
+//! use esp_idf_svc::ota::EspOta;
+//! use esp_idf_svc::timer::EspTaskTimerService;
+//!
+//! let nvs = EspNvs::new(EspDefaultNvsPartition::take()?, "ota", true)?;
+//!
+//! if let Some(report) = OtaHealthCheck::last_report(&nvs)? {
+//!     info!("Last update: {:?}", report);
+//! }
+//!
+//! let mut ota = EspOta::new()?;
+//!
+//! OtaHealthCheck::new(EspTaskTimerService::new()?, nvs, Duration::from_secs(60))
+//!     .check("wifi", || wifi.is_up().unwrap_or(false))
+//!     .check("mqtt", || mqtt_connected.load(Ordering::SeqCst))
+//!     .run(&mut ota)?;
+//! ```
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::hal::delay::FreeRtos;
+use crate::sys::*;
+
+use crate::nvs::{EspNvs, NvsPartitionId};
+use crate::private::mutex::Mutex;
+use crate::timer::EspTaskTimerService;
+
+use super::{EspOta, SlotState};
+
+const OUTCOME_KEY: &str = "ota_hc_outcome";
+const VERSION_KEY: &str = "ota_hc_version";
+const CHECK_KEY: &str = "ota_hc_check";
+
+/// The name recorded as the failed check when the watchdog expires
+const WATCHDOG_CHECK: &str = "watchdog";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OtaHealthOutcome {
+    /// All checks passed and the running slot was marked as valid
+    Confirmed,
+    /// A check did not pass in time and the device rolled back to the previous firmware
+    RolledBack,
+}
+
+impl OtaHealthOutcome {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Confirmed => 0,
+            Self::RolledBack => 1,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Confirmed),
+            1 => Some(Self::RolledBack),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of the last health check, as recorded in NVS
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OtaHealthReport {
+    pub outcome: OtaHealthOutcome,
+    /// The version of the checked firmware
+    pub version: String,
+    /// The name of the first check which did not pass, if the firmware was rolled back
+    pub failed_check: Option<String>,
+}
+
+struct Shared<P>
+where
+    P: NvsPartitionId,
+{
+    nvs: EspNvs<P>,
+    version: String,
+    decided: bool,
+}
+
+impl<P> Shared<P>
+where
+    P: NvsPartitionId,
+{
+    fn record(
+        &mut self,
+        outcome: OtaHealthOutcome,
+        failed_check: Option<&str>,
+    ) -> Result<(), EspError> {
+        let mut transaction = self.nvs.transaction();
+
+        transaction.set_u8(OUTCOME_KEY, outcome.to_u8())?;
+        transaction.set_str(VERSION_KEY, &self.version)?;
+
+        if let Some(failed_check) = failed_check {
+            transaction.set_str(CHECK_KEY, failed_check)?;
+        } else {
+            transaction.remove(CHECK_KEY)?;
+        }
+
+        transaction.commit()
+    }
+}
+
+type Check<'a> = (&'a str, Box<dyn FnMut() -> bool + 'a>);
+
+pub struct OtaHealthCheck<'a, P>
+where
+    P: NvsPartitionId,
+{
+    timer_service: EspTaskTimerService,
+    nvs: EspNvs<P>,
+    timeout: Duration,
+    poll_interval: Duration,
+    checks: Vec<Check<'a>>,
+}
+
+impl<'a, P> OtaHealthCheck<'a, P>
+where
+    P: NvsPartitionId + 'static,
+{
+    /// Creates a new health check which has to pass within `timeout`.
+    ///
+    /// The outcome is recorded in the namespace of `nvs`, which must be opened in read-write mode.
+    pub fn new(timer_service: EspTaskTimerService, nvs: EspNvs<P>, timeout: Duration) -> Self {
+        Self {
+            timer_service,
+            nvs,
+            timeout,
+            poll_interval: Duration::from_millis(500),
+            checks: Vec::new(),
+        }
+    }
+
+    /// Sets the interval at which the checks which did not pass yet are retried. Defaults to 500ms.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+
+        self
+    }
+
+    /// Adds a check. The check is called repeatedly until it returns `true` or the deadline expires.
+    pub fn check<F>(mut self, name: &'a str, check: F) -> Self
+    where
+        F: FnMut() -> bool + 'a,
+    {
+        self.checks.push((name, Box::new(check)));
+
+        self
+    }
+
+    /// Runs the checks if the running slot is `Unverified`, and then either marks the running slot
+    /// as valid, or rolls back to the previous firmware and reboots.
+    ///
+    /// Returns `None` if the running slot does not need to be verified, i.e. the checks are not run.
+    /// Otherwise returns `Some(OtaHealthOutcome::Confirmed)`, as a rollback does not return.
+    pub fn run(self, ota: &mut EspOta) -> Result<Option<OtaHealthOutcome>, EspError> {
+        let running = ota.get_running_slot()?;
+
+        if !matches!(running.state, SlotState::Unverified) {
+            debug!("Running slot is {:?}, skipping health check", running.state);
+            return Ok(None);
+        }
+
+        let shared = Arc::new(Mutex::new(Shared {
+            nvs: self.nvs,
+            version: running
+                .firmware
+                .map(|firmware| String::from(firmware.version.as_str()))
+                .unwrap_or_default(),
+            decided: false,
+        }));
+
+        // The watchdog only fires if a check does not return in time, as the checks are
+        // otherwise stopped at the deadline and the outcome is decided below
+        let watchdog = {
+            let shared = shared.clone();
+
+            self.timer_service.timer(move || {
+                let mut shared = shared.lock();
+
+                if !shared.decided {
+                    shared.decided = true;
+
+                    error!("Health check watchdog expired, rolling back");
+
+                    if let Err(err) =
+                        shared.record(OtaHealthOutcome::RolledBack, Some(WATCHDOG_CHECK))
+                    {
+                        warn!("Recording the health check outcome failed: {}", err);
+                    }
+
+                    unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() };
+                }
+            })?
+        };
+
+        watchdog.after(self.timeout + self.poll_interval * 2)?;
+
+        let deadline = self.timer_service.now() + self.timeout;
+        let mut pending = self.checks;
+
+        info!(
+            "Running {} health check(s) on firmware {}",
+            pending.len(),
+            shared.lock().version
+        );
+
+        let failed_check = loop {
+            pending.retain_mut(|(name, check)| {
+                let passed = check();
+
+                if passed {
+                    info!("Health check {} passed", name);
+                }
+
+                !passed
+            });
+
+            if pending.is_empty() {
+                break None;
+            }
+
+            if self.timer_service.now() >= deadline {
+                break Some(pending[0].0);
+            }
+
+            FreeRtos::delay_ms(self.poll_interval.as_millis() as _);
+        };
+
+        watchdog.cancel()?;
+
+        let mut shared = shared.lock();
+
+        if shared.decided {
+            // The watchdog expired in the meantime and is rolling back
+            return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+        }
+
+        shared.decided = true;
+
+        if let Some(failed_check) = failed_check {
+            error!(
+                "Health check {} did not pass in time, rolling back",
+                failed_check
+            );
+
+            if let Err(err) = shared.record(OtaHealthOutcome::RolledBack, Some(failed_check)) {
+                warn!("Recording the health check outcome failed: {}", err);
+            }
+
+            Err(ota.mark_running_slot_invalid_and_reboot())
+        } else {
+            ota.mark_running_slot_valid()?;
+
+            info!("All health checks passed, running slot marked as valid");
+
+            shared.record(OtaHealthOutcome::Confirmed, None)?;
+
+            Ok(Some(OtaHealthOutcome::Confirmed))
+        }
+    }
+
+    /// Returns the outcome of the last health check recorded in the namespace of `nvs`, if any.
+    pub fn last_report(nvs: &EspNvs<P>) -> Result<Option<OtaHealthReport>, EspError> {
+        let Some(outcome) = nvs.get_u8(OUTCOME_KEY)?.and_then(OtaHealthOutcome::from_u8) else {
+            return Ok(None);
+        };
+
+        let mut buf = [0; 64];
+
+        let version = nvs
+            .get_str(VERSION_KEY, &mut buf)?
+            .map(String::from)
+            .unwrap_or_default();
+        let failed_check = nvs.get_str(CHECK_KEY, &mut buf)?.map(String::from);
+
+        Ok(Some(OtaHealthReport {
+            outcome,
+            version,
+            failed_check,
+        }))
+    }
+}
@@ -0,0 +1,587 @@
+//! OTA updates downloaded over HTTP(S)
+//!
+//! `EspHttpOta` streams a firmware image from a URL into the update slot of `EspOta`.
+//! Interrupted downloads are resumed with HTTP `Range` requests, and the firmware info
+//! at the start of the image is validated (version, project name) before anything is
+//! written to the update slot, so that an unwanted image is rejected as early as possible.
+//!
+//! ```
+//! use esp_idf_svc::ota::http::{Configuration, EspHttpOta};
+//! use esp_idf_svc::ota::EspOta;
+//!
+//! let mut ota = EspOta::new()?;
+//! let mut http_ota = EspHttpOta::new(&Configuration::default());
+//!
+//! let info = http_ota.update(&mut ota, "https://example.com/firmware.bin", |downloaded, total| {
+//!     info!("Downloaded {downloaded} of {total:?} bytes");
+//! })?;
+//!
+//! info!("Updated to {:?}, restarting", info.version);
+//! ```
+use core::cmp::Ordering;
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use crate::sys::*;
+
+use crate::http::client::{self, EspHttpConnection};
+use crate::http::Method;
+use crate::private::cstr::CStr;
+use crate::private::unblocker::Unblocker;
+use crate::private::zerocopy::Channel;
+
+use super::{EspFirmwareInfoLoader, EspOta, EspOtaUpdate, FirmwareInfo};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Configuration {
+    pub http: client::Configuration,
+    /// The size of the buffer used for streaming the image
+    pub buffer_size: usize,
+    /// How many times an interrupted download is resumed before giving up
+    pub resume_attempts: u32,
+    /// Whether an image with the same version as the running firmware is accepted
+    pub allow_same_version: bool,
+    /// Whether an image with an older version than the running firmware is accepted
+    pub allow_downgrade: bool,
+    /// Whether the project name of the image must match the one of the running firmware
+    pub check_project_name: bool,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            http: Default::default(),
+            buffer_size: 1024,
+            resume_attempts: 3,
+            allow_same_version: false,
+            allow_downgrade: false,
+            check_project_name: true,
+        }
+    }
+}
+
+enum Failure {
+    Retry(EspError),
+    Abort(EspError),
+}
+
+impl From<EspError> for Failure {
+    fn from(err: EspError) -> Self {
+        Self::Retry(err)
+    }
+}
+
+struct Download<'a, 'b, F> {
+    update: &'a mut EspOtaUpdate<'b>,
+    loader: EspFirmwareInfoLoader,
+    /// The start of the image, which is only written once its firmware info is validated
+    head: Vec<u8>,
+    running: Option<FirmwareInfo>,
+    info: Option<FirmwareInfo>,
+    downloaded: usize,
+    total: Option<usize>,
+    progress: F,
+}
+
+pub struct EspHttpOta {
+    conf: Configuration,
+}
+
+impl EspHttpOta {
+    pub fn new(conf: &Configuration) -> Self {
+        Self { conf: *conf }
+    }
+
+    /// Downloads the image at `url` into the update slot of `ota` and - once the
+    /// download is complete - sets the updated slot as the boot one.
+    ///
+    /// `progress` is called with the number of bytes downloaded so far and the total
+    /// image size, if known. Returns the firmware info of the downloaded image.
+    ///
+    /// The update is aborted with `ESP_ERR_OTA_VALIDATE_FAILED` if the image
+    /// is rejected by the version or project name checks.
+    pub fn update<F>(
+        &mut self,
+        ota: &mut EspOta,
+        url: &str,
+        progress: F,
+    ) -> Result<FirmwareInfo, EspError>
+    where
+        F: FnMut(usize, Option<usize>),
+    {
+        let running = ota.get_running_slot()?.firmware;
+
+        let mut update = ota.initiate_update()?;
+
+        let mut download = Download {
+            update: &mut update,
+            loader: EspFirmwareInfoLoader::new(),
+            head: Vec::new(),
+            running,
+            info: None,
+            downloaded: 0,
+            total: None,
+            progress,
+        };
+
+        let mut buf = vec![0; self.conf.buffer_size];
+        let mut attempts = 0;
+
+        loop {
+            match self.download(url, &mut download, &mut buf) {
+                Ok(()) => break,
+                Err(Failure::Retry(err)) if attempts < self.conf.resume_attempts => {
+                    attempts += 1;
+
+                    warn!(
+                        "Download of {} interrupted at {} bytes ({}), resuming (attempt {}/{})",
+                        url, download.downloaded, err, attempts, self.conf.resume_attempts
+                    );
+                }
+                Err(Failure::Retry(err)) | Err(Failure::Abort(err)) => {
+                    // The update is aborted when dropped
+                    return Err(err);
+                }
+            }
+        }
+
+        let info = download
+            .info
+            .take()
+            .ok_or_else(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>)?;
+
+        update.complete()?;
+
+        info!("Update from {} complete", url);
+
+        Ok(info)
+    }
+
+    fn download<F>(
+        &self,
+        url: &str,
+        download: &mut Download<'_, '_, F>,
+        buf: &mut [u8],
+    ) -> Result<(), Failure>
+    where
+        F: FnMut(usize, Option<usize>),
+    {
+        // Nothing is written before the firmware info is validated, so the download starts over
+        if download.info.is_none() {
+            download.loader = EspFirmwareInfoLoader::new();
+            download.head.clear();
+        }
+
+        // A new connection is used for each attempt, as the previous one might be in an undefined state
+        let mut connection = EspHttpConnection::new(&self.conf.http)?;
+
+        let range = format!("bytes={}-", download.downloaded);
+        let resume = download.downloaded > 0;
+
+        let headers: &[(&str, &str)] = if resume {
+            &[("Range", range.as_str())]
+        } else {
+            &[]
+        };
+
+        connection.initiate_request(Method::Get, url, headers)?;
+        connection.initiate_response()?;
+
+        match connection.status() {
+            200 if !resume => {
+                download.total = connection
+                    .header("Content-Length")
+                    .and_then(|len| len.parse().ok());
+            }
+            200 => {
+                warn!("Server does not support resuming the download of {}", url);
+                return Err(Failure::Abort(EspError::from_infallible::<
+                    ESP_ERR_NOT_SUPPORTED,
+                >()));
+            }
+            206 if resume => {
+                let content_range = connection.header("Content-Range");
+
+                let total =
+                    check_content_range(content_range, download.downloaded).map_err(|err| {
+                        warn!("Unexpected Content-Range: {:?}", content_range);
+                        Failure::Abort(err)
+                    })?;
+
+                if total.is_some() {
+                    download.total = total;
+                }
+            }
+            status => {
+                warn!("Unexpected HTTP status {} when downloading {}", status, url);
+                return Err(Failure::Abort(EspError::from_infallible::<
+                    ESP_ERR_INVALID_RESPONSE,
+                >()));
+            }
+        }
+
+        loop {
+            let len = connection.read(buf)?;
+            if len == 0 {
+                break;
+            }
+
+            let data = &buf[..len];
+
+            if download.info.is_none() {
+                download.loader.load(data).map_err(Failure::Abort)?;
+
+                if !download.loader.is_loaded() {
+                    download.head.extend_from_slice(data);
+                    continue;
+                }
+
+                let info = download.loader.get_info().map_err(Failure::Abort)?;
+
+                self.check(&info, download.running.as_ref())
+                    .map_err(Failure::Abort)?;
+
+                download.info = Some(info);
+
+                let head = core::mem::take(&mut download.head);
+
+                download.update.write(&head).map_err(Failure::Abort)?;
+                download.downloaded += head.len();
+            }
+
+            download.update.write(data).map_err(Failure::Abort)?;
+            download.downloaded += len;
+
+            (download.progress)(download.downloaded, download.total);
+        }
+
+        if let Some(total) = download.total {
+            if download.downloaded < total {
+                return Err(Failure::Retry(EspError::from_infallible::<
+                    ESP_ERR_INVALID_SIZE,
+                >()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check(&self, info: &FirmwareInfo, running: Option<&FirmwareInfo>) -> Result<(), EspError> {
+        let Some(running) = running else {
+            return Ok(());
+        };
+
+        if self.conf.check_project_name && info.description != running.description {
+            warn!(
+                "Rejecting image of project {:?}, running project is {:?}",
+                info.description, running.description
+            );
+
+            return Err(EspError::from_infallible::<ESP_ERR_OTA_VALIDATE_FAILED>());
+        }
+
+        let accepted = match compare_versions(&info.version, &running.version) {
+            Some(Ordering::Greater) => true,
+            Some(Ordering::Equal) => self.conf.allow_same_version,
+            Some(Ordering::Less) => self.conf.allow_downgrade,
+            // Versions which cannot be compared are only checked for equality
+            None => info.version != running.version || self.conf.allow_same_version,
+        };
+
+        if !accepted {
+            warn!(
+                "Rejecting image with version {}, running version is {}",
+                info.version, running.version
+            );
+
+            return Err(EspError::from_infallible::<ESP_ERR_OTA_VALIDATE_FAILED>());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct AsyncWork {
+    url: String,
+    result: Result<FirmwareInfo, EspError>,
+}
+
+/// An async variant of `EspHttpOta`.
+///
+/// The download is done by a separate task owning the `EspOta` instance, so that
+/// it does not block the executor.
+pub struct EspAsyncHttpOta(Unblocker<AsyncWork>);
+
+impl EspAsyncHttpOta {
+    /// Creates a new instance, taking ownership of `ota`.
+    ///
+    /// `progress` is called - from the download task - with the number of bytes
+    /// downloaded so far and the total image size, if known.
+    pub fn new<F>(ota: EspOta, conf: &Configuration, progress: F) -> Result<Self, EspError>
+    where
+        F: FnMut(usize, Option<usize>) + Send + 'static,
+    {
+        let http_ota = EspHttpOta::new(conf);
+
+        let unblocker = Unblocker::new(
+            CStr::from_bytes_until_nul(b"OTA download task\0").unwrap(),
+            8192,
+            None,
+            None,
+            move |channel| Self::work(channel, ota, http_ota, progress),
+        )?;
+
+        Ok(Self(unblocker))
+    }
+
+    /// Async variant of `EspHttpOta::update`.
+    pub async fn update(&mut self, url: &str) -> Result<FirmwareInfo, EspError> {
+        let work = self.0.exec_in_out().await.unwrap();
+
+        work.url.clear();
+        work.url.push_str(url);
+
+        self.0.do_exec().await;
+
+        let work = self.0.exec_in_out().await.unwrap();
+
+        work.result.clone()
+    }
+
+    fn work<F>(
+        channel: Arc<Channel<AsyncWork>>,
+        mut ota: EspOta,
+        mut http_ota: EspHttpOta,
+        mut progress: F,
+    ) where
+        F: FnMut(usize, Option<usize>) + Send + 'static,
+    {
+        let mut work = AsyncWork {
+            url: String::new(),
+            result: Err(EspError::from_infallible::<ESP_FAIL>()),
+        };
+
+        while channel.share(&mut work) {
+            work.result = http_ota.update(&mut ota, &work.url, &mut progress);
+        }
+    }
+}
+
+/// Parses the start offset and the total length from a `Content-Range` header value,
+/// e.g. `bytes 1024-4095/4096`.
+fn parse_content_range(content_range: &str) -> (Option<usize>, Option<usize>) {
+    let Some(range) = content_range.trim().strip_prefix("bytes ") else {
+        return (None, None);
+    };
+
+    let (range, total) = range.split_once('/').unwrap_or((range, "*"));
+
+    let start = range
+        .split_once('-')
+        .and_then(|(start, _)| start.trim().parse().ok());
+
+    (start, total.trim().parse().ok())
+}
+
+/// Checks the `Content-Range` header of a `206 Partial Content` response to a request resuming
+/// the download at `offset`, returning the total length, if known.
+///
+/// Returns `ESP_ERR_INVALID_RESPONSE` if the header is missing or the range does not start at
+/// `offset`, as the received data could not be appended to the already downloaded data then.
+fn check_content_range(
+    content_range: Option<&str>,
+    offset: usize,
+) -> Result<Option<usize>, EspError> {
+    let (start, total) = content_range.map(parse_content_range).unwrap_or_default();
+
+    if start != Some(offset) {
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+    }
+
+    Ok(total)
+}
+
+/// Compares dotted numeric versions (e.g. `v1.2.3`), ignoring a leading `v` and any build
+/// metadata (e.g. `1.2.3+build5`).
+///
+/// A pre-release suffix (e.g. `1.2.3-rc.1`) makes a version older than the one without it,
+/// with pre-releases ordered as in Semantic Versioning, whereas a `git describe` suffix
+/// (e.g. `1.2.3-4-g1a2b3c4`, i.e. 4 commits after `1.2.3`) makes it newer.
+///
+/// Returns `None` if either of the versions is not in that format.
+fn compare_versions(version: &str, other: &str) -> Option<Ordering> {
+    fn parse(version: &str) -> Option<(Vec<u32>, Option<&str>)> {
+        let version = version.trim();
+        let version = version.strip_prefix('v').unwrap_or(version);
+        let version = version.split('+').next()?;
+
+        let (core, suffix) = match version.split_once('-') {
+            Some((core, suffix)) => (core, Some(suffix)),
+            None => (version, None),
+        };
+
+        let core = core
+            .split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<_>>()?;
+
+        Some((core, suffix))
+    }
+
+    /// Returns the number of commits of a `git describe` suffix, e.g. 4 for `4-g1a2b3c4`
+    fn commits(suffix: &str) -> Option<u32> {
+        let mut parts = suffix.split('-');
+
+        let commits = parts.next()?.parse().ok()?;
+        let hash = parts.next()?.strip_prefix('g')?;
+
+        let valid = !hash.is_empty()
+            && hash.chars().all(|c| c.is_ascii_hexdigit())
+            && parts.all(|part| part == "dirty");
+
+        valid.then_some(commits)
+    }
+
+    fn compare_pre_releases(pre_release: &str, other: &str) -> Ordering {
+        let mut ids = pre_release.split('.');
+        let mut other_ids = other.split('.');
+
+        loop {
+            let ordering = match (ids.next(), other_ids.next()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(id), Some(other_id)) => match (id.parse::<u64>(), other_id.parse::<u64>()) {
+                    (Ok(id), Ok(other_id)) => id.cmp(&other_id),
+                    (Ok(_), Err(_)) => Ordering::Less,
+                    (Err(_), Ok(_)) => Ordering::Greater,
+                    (Err(_), Err(_)) => id.cmp(other_id),
+                },
+            };
+
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+    }
+
+    fn compare_suffixes(suffix: Option<&str>, other: Option<&str>) -> Ordering {
+        // Pre-releases, then releases, then commits after releases
+        let rank = |suffix: Option<&str>| match suffix {
+            None => 1,
+            Some(suffix) if commits(suffix).is_some() => 2,
+            Some(_) => 0,
+        };
+
+        match (suffix, other) {
+            (Some(suffix), Some(other)) if rank(Some(suffix)) == rank(Some(other)) => {
+                match (commits(suffix), commits(other)) {
+                    (Some(commits), Some(other_commits)) => commits.cmp(&other_commits),
+                    _ => compare_pre_releases(suffix, other),
+                }
+            }
+            _ => rank(suffix).cmp(&rank(other)),
+        }
+    }
+
+    let (mut version, suffix) = parse(version)?;
+    let (mut other, other_suffix) = parse(other)?;
+
+    let len = version.len().max(other.len());
+    version.resize(len, 0);
+    other.resize(len, 0);
+
+    Some(
+        version
+            .cmp(&other)
+            .then_with(|| compare_suffixes(suffix, other_suffix)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 1024-4095/4096"),
+            (Some(1024), Some(4096))
+        );
+        assert_eq!(parse_content_range("bytes 1024-4095/*"), (Some(1024), None));
+        assert_eq!(parse_content_range("bytes */1234"), (None, Some(1234)));
+        assert_eq!(parse_content_range("bytes=1024-4095/4096"), (None, None));
+        assert_eq!(parse_content_range("bytes abc-def/ghi"), (None, None));
+        assert_eq!(parse_content_range(""), (None, None));
+    }
+
+    #[test]
+    fn checks_content_range() {
+        assert_eq!(
+            check_content_range(Some("bytes 1024-4095/4096"), 1024),
+            Ok(Some(4096))
+        );
+        assert_eq!(
+            check_content_range(Some("bytes 1024-4095/*"), 1024),
+            Ok(None)
+        );
+        assert!(check_content_range(Some("bytes 0-4095/4096"), 1024).is_err());
+        assert!(check_content_range(Some("bytes */4096"), 1024).is_err());
+        assert!(check_content_range(None, 1024).is_err());
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(compare_versions("1.2", "1.2.0"), Some(Ordering::Equal));
+        assert_eq!(compare_versions("v1.2.3", "1.2.4"), Some(Ordering::Less));
+        assert_eq!(compare_versions("1.10.0", "1.9.0"), Some(Ordering::Greater));
+        assert_eq!(
+            compare_versions("1.2.3+build5", "1.2.3"),
+            Some(Ordering::Equal)
+        );
+
+        assert_eq!(compare_versions("1.2.3-rc1", "1.2.3"), Some(Ordering::Less));
+        assert_eq!(
+            compare_versions("1.2.4-rc1", "1.2.3"),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            compare_versions("1.2.3-rc.2", "1.2.3-rc.10"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_versions("1.2.3-alpha", "1.2.3-beta"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_versions("1.2.3-1", "1.2.3-alpha"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_versions("1.2.3-rc.1", "1.2.3-rc"),
+            Some(Ordering::Greater)
+        );
+
+        assert_eq!(
+            compare_versions("1.2.3-4-g1a2b3c4", "1.2.3"),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            compare_versions("v1.2.3-4-g1a2b3c4-dirty", "v1.2.3-12-g0a0b0c0"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            compare_versions("1.2.4-rc1", "1.2.3-4-g1a2b3c4"),
+            Some(Ordering::Greater)
+        );
+
+        assert_eq!(compare_versions("abc", "1.2.3"), None);
+        assert_eq!(compare_versions("1.2.3", ""), None);
+    }
+}
@@ -0,0 +1,217 @@
+//! Verification of OTA images before activation
+//!
+//! `EspOtaUpdate::verified` wraps an update so that the SHA-256 digest of all written
+//! data is computed on the fly. When the update is finished or completed, the digest is
+//! checked with an `ImageVerifier` - against an expected digest, or against a signature
+//! supplied by the update manifest - and the update is aborted if the check fails.
+//!
+//! The digest and signature logic in this module does not depend on ESP-IDF.
+//!
+//! The following verifiers are available:
+//! - `ExpectedDigest`: the image digest must match a known SHA-256 digest
+//! - `Ed25519Verifier` (feature `ota-ed25519`): an Ed25519 signature of the image digest
+//! - `EcdsaP256Verifier` (feature `ota-ecdsa`): an ECDSA P-256 signature (with SHA-256) of the image digest
+//!
+//! For both signature schemes, the signed message is the 32-byte SHA-256 digest of the image
+//! (and not the image itself), e.g. with OpenSSL:
+//!
+//! ```text
+//! openssl dgst -sha256 -binary firmware.bin > firmware.sha256
+//! openssl pkeyutl -sign -inkey ed25519.pem -rawin -in firmware.sha256 -out firmware.sig
+//! openssl dgst -sha256 -sign p256.pem -out firmware.sig firmware.sha256
+//! ```
+
+#[cfg(any(feature = "ota-ed25519", feature = "ota-ecdsa"))]
+use core::fmt::{self, Debug};
+
+#[cfg(any(feature = "ota-ed25519", feature = "ota-ecdsa"))]
+use crate::sys::{EspError, ESP_ERR_INVALID_ARG};
+
+pub use crate::private::hash::{Sha256, SHA256_LEN};
+
+use crate::private::hash::constant_time_eq;
+
+/// Verifies an image, given the SHA-256 digest of its content
+pub trait ImageVerifier {
+    fn verify(&self, digest: &[u8; SHA256_LEN]) -> bool;
+}
+
+impl<V> ImageVerifier for &V
+where
+    V: ImageVerifier,
+{
+    fn verify(&self, digest: &[u8; SHA256_LEN]) -> bool {
+        (*self).verify(digest)
+    }
+}
+
+/// Accepts an image if its SHA-256 digest is equal to the expected one
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ExpectedDigest(pub [u8; SHA256_LEN]);
+
+impl ImageVerifier for ExpectedDigest {
+    fn verify(&self, digest: &[u8; SHA256_LEN]) -> bool {
+        constant_time_eq(&self.0, digest)
+    }
+}
+
+/// Accepts an image if the given Ed25519 signature of its SHA-256 digest is valid
+#[cfg(feature = "ota-ed25519")]
+#[derive(Clone)]
+pub struct Ed25519Verifier {
+    public_key: ed25519_compact::PublicKey,
+    signature: ed25519_compact::Signature,
+}
+
+#[cfg(feature = "ota-ed25519")]
+impl Ed25519Verifier {
+    /// Creates a verifier from a raw 32-byte public key and a raw 64-byte signature.
+    pub fn new(public_key: &[u8], signature: &[u8]) -> Result<Self, EspError> {
+        Ok(Self {
+            public_key: ed25519_compact::PublicKey::from_slice(public_key)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+            signature: ed25519_compact::Signature::from_slice(signature)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+        })
+    }
+}
+
+#[cfg(feature = "ota-ed25519")]
+impl ImageVerifier for Ed25519Verifier {
+    fn verify(&self, digest: &[u8; SHA256_LEN]) -> bool {
+        self.public_key.verify(digest, &self.signature).is_ok()
+    }
+}
+
+#[cfg(feature = "ota-ed25519")]
+impl Debug for Ed25519Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ed25519Verifier").finish_non_exhaustive()
+    }
+}
+
+/// Accepts an image if the given ECDSA P-256 signature (with SHA-256) of its SHA-256 digest is valid
+#[cfg(feature = "ota-ecdsa")]
+#[derive(Clone)]
+pub struct EcdsaP256Verifier {
+    public_key: p256::ecdsa::VerifyingKey,
+    signature: p256::ecdsa::Signature,
+}
+
+#[cfg(feature = "ota-ecdsa")]
+impl EcdsaP256Verifier {
+    /// Creates a verifier from a SEC1-encoded public key and a fixed-size (`r || s`) signature.
+    pub fn new(public_key: &[u8], signature: &[u8]) -> Result<Self, EspError> {
+        Ok(Self {
+            public_key: p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+            signature: p256::ecdsa::Signature::from_slice(signature)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+        })
+    }
+
+    /// Creates a verifier from a SEC1-encoded public key and an ASN.1 DER-encoded signature.
+    pub fn new_der(public_key: &[u8], signature: &[u8]) -> Result<Self, EspError> {
+        Ok(Self {
+            public_key: p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+            signature: p256::ecdsa::Signature::from_der(signature)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?,
+        })
+    }
+}
+
+#[cfg(feature = "ota-ecdsa")]
+impl ImageVerifier for EcdsaP256Verifier {
+    fn verify(&self, digest: &[u8; SHA256_LEN]) -> bool {
+        use p256::ecdsa::signature::Verifier;
+
+        self.public_key.verify(digest, &self.signature).is_ok()
+    }
+}
+
+#[cfg(feature = "ota-ecdsa")]
+impl Debug for EcdsaP256Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EcdsaP256Verifier").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "ota-ed25519", feature = "ota-ecdsa"))]
+    fn hex(hex: &str) -> alloc::vec::Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn expected_digest() {
+        let digest = Sha256::digest(b"firmware");
+
+        assert!(ExpectedDigest(digest).verify(&digest));
+        assert!(!ExpectedDigest(Sha256::digest(b"other")).verify(&digest));
+    }
+
+    // Key from RFC 8032, section 7.1, test 1
+    #[cfg(feature = "ota-ed25519")]
+    const ED25519_PUBLIC_KEY: &str =
+        "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    #[cfg(feature = "ota-ed25519")]
+    const ED25519_SIGNATURE: &str = "a2ebf0bd36fca14041679cfcdd6e324a70bbc0662f3f9248505fd1fdf8dbbdb7f51407aa73b3fc9b0e2a4a8c9e7f399c80c94b9beee45bc06f3ef493d63d8c07";
+
+    #[cfg(feature = "ota-ed25519")]
+    #[test]
+    fn ed25519() {
+        let verifier =
+            Ed25519Verifier::new(&hex(ED25519_PUBLIC_KEY), &hex(ED25519_SIGNATURE)).unwrap();
+
+        assert!(verifier.verify(&Sha256::digest(b"firmware")));
+        assert!(!verifier.verify(&Sha256::digest(b"other")));
+
+        let mut signature = hex(ED25519_SIGNATURE);
+        signature[0] ^= 1;
+
+        let verifier = Ed25519Verifier::new(&hex(ED25519_PUBLIC_KEY), &signature).unwrap();
+
+        assert!(!verifier.verify(&Sha256::digest(b"firmware")));
+
+        assert!(
+            Ed25519Verifier::new(&hex(ED25519_PUBLIC_KEY)[1..], &hex(ED25519_SIGNATURE)).is_err()
+        );
+    }
+
+    #[cfg(feature = "ota-ecdsa")]
+    const P256_PUBLIC_KEY: &str = "0460fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb67903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299";
+    #[cfg(feature = "ota-ecdsa")]
+    const P256_SIGNATURE: &str = "3c3fccfd188eb825a919107475095755e28b9e762d16176a95620e7ae5a0aade5cd13d0e2bd25cf6b2eddd6e1e29b57aa1e230f2ca8a088e7cd32085a1988f1d";
+    #[cfg(feature = "ota-ecdsa")]
+    const P256_SIGNATURE_DER: &str = "304402203c3fccfd188eb825a919107475095755e28b9e762d16176a95620e7ae5a0aade02205cd13d0e2bd25cf6b2eddd6e1e29b57aa1e230f2ca8a088e7cd32085a1988f1d";
+
+    #[cfg(feature = "ota-ecdsa")]
+    #[test]
+    fn ecdsa_p256() {
+        let verifier = EcdsaP256Verifier::new(&hex(P256_PUBLIC_KEY), &hex(P256_SIGNATURE)).unwrap();
+
+        assert!(verifier.verify(&Sha256::digest(b"firmware")));
+        assert!(!verifier.verify(&Sha256::digest(b"other")));
+
+        let verifier =
+            EcdsaP256Verifier::new_der(&hex(P256_PUBLIC_KEY), &hex(P256_SIGNATURE_DER)).unwrap();
+
+        assert!(verifier.verify(&Sha256::digest(b"firmware")));
+
+        let mut signature = hex(P256_SIGNATURE);
+        signature[40] ^= 1;
+
+        let verifier = EcdsaP256Verifier::new(&hex(P256_PUBLIC_KEY), &signature).unwrap();
+
+        assert!(!verifier.verify(&Sha256::digest(b"firmware")));
+
+        assert!(EcdsaP256Verifier::new(&hex(P256_PUBLIC_KEY), &hex(P256_SIGNATURE_DER)).is_err());
+    }
+}
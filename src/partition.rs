@@ -0,0 +1,400 @@
+//! Access to the partitions of the flash partition table
+//!
+//! Partitions are declared in `partitions.csv` and can be listed with `EspPartition::iter`
+//! and opened by label with `EspPartition::find`. An opened partition can be read, written
+//! and erased directly, or used via the `embedded_storage::{ReadStorage, Storage}` and
+//! `io::{Read, Seek}` traits - e.g. for storing data logs or certificates in a custom
+//! data partition.
+//!
+//! Note that writing a partition in use by e.g. NVS or OTA bypasses those components
+//! and will likely corrupt their data.
+//!
+//! ```
+//! use esp_idf_svc::partition::EspPartition;
+//!
+//! for info in EspPartition::iter() {
+//!     info!("{:?}", info);
+//! }
+//!
+//! let mut certs = EspPartition::find("certs")?.unwrap();
+//!
+//! let mut header = [0; 16];
+//! certs.read(0, &mut header)?;
+//! ```
+use core::ptr;
+
+use embedded_storage::{ReadStorage, Storage};
+
+use embedded_svc::io::{self, SeekFrom};
+
+use crate::sys::*;
+
+use crate::io::EspIOError;
+use crate::private::common::*;
+use crate::private::cstr::*;
+
+/// The size of the flash sectors, i.e. the granularity of erase operations
+pub const SECTOR_SIZE: usize = 4096;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PartitionType {
+    App,
+    Data,
+    Other(u8),
+}
+
+impl From<Newtype<esp_partition_type_t>> for PartitionType {
+    #[allow(non_upper_case_globals)]
+    fn from(partition_type: Newtype<esp_partition_type_t>) -> Self {
+        match partition_type.0 {
+            esp_partition_type_t_ESP_PARTITION_TYPE_APP => Self::App,
+            esp_partition_type_t_ESP_PARTITION_TYPE_DATA => Self::Data,
+            other => Self::Other(other as _),
+        }
+    }
+}
+
+impl From<PartitionType> for Newtype<esp_partition_type_t> {
+    fn from(partition_type: PartitionType) -> Self {
+        Self(match partition_type {
+            PartitionType::App => esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+            PartitionType::Data => esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            PartitionType::Other(other) => other as _,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PartitionInfo {
+    pub partition_type: PartitionType,
+    pub subtype: u8,
+    pub label: heapless::String<16>,
+    /// The offset of the partition in flash
+    pub offset: u32,
+    pub size: u32,
+    /// Whether the partition is encrypted with flash encryption
+    pub encrypted: bool,
+}
+
+impl From<Newtype<&esp_partition_t>> for PartitionInfo {
+    fn from(partition: Newtype<&esp_partition_t>) -> Self {
+        let partition = partition.0;
+
+        let mut label = heapless::String::new();
+        label
+            .push_str(unsafe { from_cstr_ptr(partition.label.as_ptr()) })
+            .unwrap();
+
+        Self {
+            partition_type: Newtype(partition.type_).into(),
+            subtype: partition.subtype as _,
+            label,
+            offset: partition.address as _,
+            size: partition.size as _,
+            encrypted: partition.encrypted,
+        }
+    }
+}
+
+/// An iterator over the partitions of the partition table
+pub struct EspPartitionIterator {
+    types: &'static [esp_partition_type_t],
+    iterator: esp_partition_iterator_t,
+}
+
+impl EspPartitionIterator {
+    fn new() -> Self {
+        Self {
+            types: &[
+                esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            ],
+            iterator: ptr::null_mut(),
+        }
+    }
+}
+
+impl Iterator for EspPartitionIterator {
+    type Item = PartitionInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.iterator.is_null() {
+                let (partition_type, types) = self.types.split_first()?;
+                self.types = types;
+
+                self.iterator = unsafe {
+                    esp_partition_find(
+                        *partition_type,
+                        esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                        ptr::null(),
+                    )
+                };
+            } else {
+                // Releases the iterator and returns null once there are no more partitions
+                self.iterator = unsafe { esp_partition_next(self.iterator) };
+            }
+
+            if !self.iterator.is_null() {
+                let partition = unsafe { &*esp_partition_get(self.iterator) };
+
+                return Some(Newtype(partition).into());
+            }
+        }
+    }
+}
+
+impl Drop for EspPartitionIterator {
+    fn drop(&mut self) {
+        if !self.iterator.is_null() {
+            unsafe { esp_partition_iterator_release(self.iterator) };
+        }
+    }
+}
+
+unsafe impl Send for EspPartitionIterator {}
+
+pub struct EspPartition {
+    partition: *const esp_partition_t,
+    position: u64,
+}
+
+impl EspPartition {
+    /// Returns an iterator over the app and data partitions of the partition table.
+    pub fn iter() -> EspPartitionIterator {
+        EspPartitionIterator::new()
+    }
+
+    /// Opens the data or app partition with the given label, if it exists.
+    pub fn find(label: &str) -> Result<Option<Self>, EspError> {
+        let mut c_label = [0_u8; 17];
+        set_str(&mut c_label, label)?;
+
+        for partition_type in [
+            esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            esp_partition_type_t_ESP_PARTITION_TYPE_APP,
+        ] {
+            let partition = unsafe {
+                esp_partition_find_first(
+                    partition_type,
+                    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                    c_label.as_ptr() as *const _,
+                )
+            };
+
+            if !partition.is_null() {
+                return Ok(Some(Self {
+                    partition,
+                    position: 0,
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Opens the first partition with the given type and subtype, if it exists.
+    pub fn find_by_type(partition_type: PartitionType, subtype: u8) -> Option<Self> {
+        let partition = unsafe {
+            esp_partition_find_first(
+                Newtype::<esp_partition_type_t>::from(partition_type).0,
+                subtype as _,
+                ptr::null(),
+            )
+        };
+
+        if partition.is_null() {
+            None
+        } else {
+            Some(Self {
+                partition,
+                position: 0,
+            })
+        }
+    }
+
+    pub fn info(&self) -> PartitionInfo {
+        Newtype(self.raw()).into()
+    }
+
+    pub fn size(&self) -> usize {
+        self.raw().size as _
+    }
+
+    /// Reads `buf.len()` bytes at `offset`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), EspError> {
+        self.check_range(offset, buf.len())?;
+
+        esp!(unsafe {
+            esp_partition_read(
+                self.partition,
+                offset as _,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as _,
+            )
+        })
+    }
+
+    /// Writes `data` at `offset`.
+    ///
+    /// As with all NOR flash, writing can only clear bits, so the range should have been erased before.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EspError> {
+        self.check_range(offset, data.len())?;
+
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition,
+                offset as _,
+                data.as_ptr() as *const _,
+                data.len() as _,
+            )
+        })
+    }
+
+    /// Erases `len` bytes at `offset`. Both must be aligned to `SECTOR_SIZE`.
+    pub fn erase(&mut self, offset: usize, len: usize) -> Result<(), EspError> {
+        self.check_range(offset, len)?;
+
+        if offset % SECTOR_SIZE != 0 || len % SECTOR_SIZE != 0 {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+
+        esp!(unsafe { esp_partition_erase_range(self.partition, offset as _, len as _) })
+    }
+
+    /// Erases the whole partition.
+    pub fn erase_all(&mut self) -> Result<(), EspError> {
+        self.erase(0, self.size())
+    }
+
+    /// Writes `data` at `offset`, erasing and rewriting the affected sectors as necessary.
+    ///
+    /// In contrast to `write`, the range does not need to be erased before.
+    ///
+    /// Note that this is not power-loss safe: a sector is erased before its data is rewritten,
+    /// so if power is lost (or a write fails) in between, the whole sector - including the data
+    /// outside of the written range - is lost. Data which must survive power loss should be
+    /// written to alternating, previously erased locations instead (as e.g. `nvs` does).
+    #[cfg(feature = "alloc")]
+    pub fn write_with_erase(&mut self, offset: usize, data: &[u8]) -> Result<(), EspError> {
+        self.check_range(offset, data.len())?;
+
+        let mut sector = vec![0; SECTOR_SIZE];
+
+        let mut offset = offset;
+        let mut data = data;
+
+        while !data.is_empty() {
+            let sector_offset = offset - offset % SECTOR_SIZE;
+            let start = offset - sector_offset;
+            let len = (SECTOR_SIZE - start).min(data.len());
+
+            let chunk = &data[..len];
+            let current = &mut sector[start..start + len];
+
+            self.read(offset, current)?;
+
+            if current != chunk {
+                if !self.raw().encrypted
+                    && current
+                        .iter()
+                        .zip(chunk)
+                        .all(|(old, new)| old & new == *new)
+                {
+                    // Only bits which need to be cleared, no erase necessary
+                    // (encrypted data cannot be updated in place though)
+                    self.write(offset, chunk)?;
+                } else {
+                    self.read(sector_offset, &mut sector)?;
+                    sector[start..start + len].copy_from_slice(chunk);
+
+                    self.erase(sector_offset, SECTOR_SIZE)?;
+                    self.write(sector_offset, &sector)?;
+                }
+            }
+
+            offset += len;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), EspError> {
+        if offset
+            .checked_add(len)
+            .map(|end| end <= self.size())
+            .unwrap_or(false)
+        {
+            Ok(())
+        } else {
+            Err(EspError::from_infallible::<ESP_ERR_INVALID_SIZE>())
+        }
+    }
+
+    fn raw(&self) -> &esp_partition_t {
+        unsafe { &*self.partition }
+    }
+}
+
+unsafe impl Send for EspPartition {}
+
+impl core::fmt::Debug for EspPartition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EspPartition")
+            .field("info", &self.info())
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl ReadStorage for EspPartition {
+    type Error = EspError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        EspPartition::read(self, offset as _, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Storage for EspPartition {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.write_with_erase(offset as _, bytes)
+    }
+}
+
+impl io::ErrorType for EspPartition {
+    type Error = EspIOError;
+}
+
+impl io::Read for EspPartition {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let position = (self.position as usize).min(self.size());
+        let len = (self.size() - position).min(buf.len());
+
+        EspPartition::read(self, position, &mut buf[..len])?;
+
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl io::Seek for EspPartition {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.size() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = position
+            .ok_or_else(|| EspIOError(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()))?;
+
+        Ok(self.position)
+    }
+}
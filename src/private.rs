@@ -1,10 +1,20 @@
 #![allow(unused)]
 
+#[cfg(feature = "alloc")]
+pub mod auth;
+#[cfg(feature = "alloc")]
+pub mod base64;
 pub mod common;
 pub mod cstr;
+pub mod hash;
+#[cfg(feature = "alloc")]
+pub mod hex;
 pub mod mutex;
 #[cfg(esp_idf_comp_esp_netif_enabled)]
 pub mod net;
+pub mod random;
+#[cfg(esp_idf_comp_esp_timer_enabled)]
+pub mod time;
 #[cfg(feature = "alloc")]
 pub mod unblocker;
 pub mod waitable;
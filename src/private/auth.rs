@@ -0,0 +1,114 @@
+//! Parsing and formatting of HTTP authentication headers, as needed by the HTTP client and server
+
+extern crate alloc;
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Returns the credentials of an `Authorization` header (or the parameters of a challenge) with
+/// the given scheme
+pub fn strip_scheme<'a>(header: &'a str, scheme: &str) -> Option<&'a str> {
+    let (header_scheme, credentials) = header.trim().split_once(' ')?;
+
+    header_scheme
+        .eq_ignore_ascii_case(scheme)
+        .then_some(credentials)
+}
+
+/// Parses the comma-separated `name=value` or `name="value"` parameters of a Digest `Authorization`
+/// header or challenge
+pub fn parse_params(mut params: &str) -> Vec<(&str, String)> {
+    let mut parsed = Vec::new();
+
+    loop {
+        params = params.trim_start_matches([' ', '\t', ',']);
+
+        let Some((name, rest)) = params.split_once('=') else {
+            break parsed;
+        };
+
+        let rest = rest.trim_start();
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => value.extend(chars.next().map(|(_, c)| c)),
+                    Some((index, '"')) => {
+                        params = &quoted[index + 1..];
+                        break;
+                    }
+                    Some((_, c)) => value.push(c),
+                    // Unterminated quoted string
+                    None => return parsed,
+                }
+            }
+
+            value
+        } else {
+            let (value, rest) = rest.split_once(',').unwrap_or((rest, ""));
+            params = rest;
+
+            value.trim().to_owned()
+        };
+
+        parsed.push((name.trim(), value));
+    }
+}
+
+/// Formats `value` as a quoted string
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+
+    quoted.push('"');
+
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+
+        quoted.push(c);
+    }
+
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_params() {
+        assert_eq!(
+            parse_params(
+                "username=\"Mufasa\", realm=\"http-auth@example.org\", nc=00000001, qop=auth"
+            ),
+            vec![
+                ("username", "Mufasa".to_owned()),
+                ("realm", "http-auth@example.org".to_owned()),
+                ("nc", "00000001".to_owned()),
+                ("qop", "auth".to_owned()),
+            ]
+        );
+        assert_eq!(
+            parse_params("realm=\"a, b\",uri=\"/a?b=c,d\""),
+            vec![("realm", "a, b".to_owned()), ("uri", "/a?b=c,d".to_owned())]
+        );
+        assert_eq!(
+            parse_params(r#"username="say \"hi\" \\o/", nc=1"#),
+            vec![
+                ("username", r#"say "hi" \o/"#.to_owned()),
+                ("nc", "1".to_owned())
+            ]
+        );
+        assert_eq!(
+            parse_params("nc=1, username=\"unterminated"),
+            vec![("nc", "1".to_owned())]
+        );
+        assert_eq!(parse_params(""), vec![]);
+    }
+}
@@ -0,0 +1,96 @@
+//! Base64 encoding and decoding, as needed by the HTTP client and server
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Encodes `data` with the standard alphabet and padding (RFC 4648)
+pub fn encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0_u32, |bits, (index, byte)| {
+            bits | ((*byte as u32) << (16 - index * 8))
+        });
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - index * 6)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Decodes `data` encoded with the standard alphabet, with or without padding (RFC 4648)
+pub fn decode(data: &str) -> Option<Vec<u8>> {
+    let unpadded = data.trim_end_matches('=');
+    let padding = data.len() - unpadded.len();
+
+    if padding > 2 || unpadded.len() % 4 == 1 || (padding > 0 && data.len() % 4 != 0) {
+        return None;
+    }
+
+    let data = unpadded.as_bytes();
+
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let mut bits = 0_u32;
+    let mut bit_count = 0;
+
+    for byte in data {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        bits = (bits << 6) | value as u32;
+        bit_count += 6;
+
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(b"user:secret"), "dXNlcjpzZWNyZXQ=");
+    }
+
+    #[test]
+    fn decodes() {
+        assert_eq!(decode(""), Some(vec![]));
+        assert_eq!(decode("Zg=="), Some(b"f".to_vec()));
+        assert_eq!(decode("Zm8="), Some(b"fo".to_vec()));
+        assert_eq!(decode("Zm9v"), Some(b"foo".to_vec()));
+        assert_eq!(decode("Zg"), Some(b"f".to_vec()));
+        assert_eq!(decode("dXNlcjpzZWNyZXQ="), Some(b"user:secret".to_vec()));
+
+        assert_eq!(decode("Zg="), None);
+        assert_eq!(decode("Zg==="), None);
+        assert_eq!(decode("Z"), None);
+        assert_eq!(decode("Zg==Zg=="), None);
+        assert_eq!(decode("Zm9v!"), None);
+        assert_eq!(decode("Zm 9v"), None);
+    }
+}
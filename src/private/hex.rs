@@ -0,0 +1,28 @@
+//! Hex encoding, as needed by HTTP authentication and sessions
+
+extern crate alloc;
+use alloc::string::String;
+
+use core::fmt::Write as _;
+
+/// Encodes `data` as lowercase hex digits
+pub fn encode(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(data.len() * 2);
+
+    for byte in data {
+        write!(&mut hex, "{byte:02x}").unwrap();
+    }
+
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+    }
+}
@@ -0,0 +1,9 @@
+use crate::sys::esp_fill_random;
+
+/// Returns `N` random bytes from the hardware random number generator
+pub fn random<const N: usize>() -> [u8; N] {
+    let mut data = [0; N];
+    unsafe { esp_fill_random(data.as_mut_ptr() as *mut _, data.len() as _) };
+
+    data
+}
@@ -0,0 +1,7 @@
+use crate::sys::esp_timer_get_time;
+
+/// Returns the monotonic time since boot in seconds, which - in contrast to the system time -
+/// never jumps when the clock is set
+pub fn uptime_secs() -> u64 {
+    (unsafe { esp_timer_get_time() } / 1_000_000) as _
+}